@@ -1,4 +1,76 @@
 //! A simple line-oriented scripting language for small computers
+//!
+//! This crate is `no_std`. The `alloc` feature (enabled by default, via the
+//! `std` feature) adds the values which need a heap, like `Value::String`
+//! and `Value::Vector`. Without it, you can still build, inspect and run
+//! programs, but operations which would produce a new string (like adding
+//! two strings together) give `Error::TypeMismatch`. Run the tests with
+//! `cargo test --no-default-features` to check this still works.
+//!
+//! The `std` feature is needed to raise a Float to a power. Either it or the
+//! `math` feature (which uses `libm`) is needed for `sqrt`, `sin` and `cos`.
+
+#![no_std]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "alloc")]
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::cmp::Ordering;
+use core::convert::TryFrom;
+
+// -----------------------------------------------------------------------------
+// Constants
+// -----------------------------------------------------------------------------
+
+/// The longest string that an operation is allowed to produce, in bytes.
+pub const MAX_STRING_LENGTH: usize = 64 * 1024;
+
+/// The most variables that a single function call can hold.
+pub const MAX_VARIABLES: usize = 16;
+
+/// How deeply function calls can nest, unless `Program::run_with_depth_limit`
+/// says otherwise. Each call uses some of the native stack, which is small on
+/// embedded targets.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// How many recently inserted elements a `ProgramBuilder` remembers, for
+/// constant folding. This limits how deeply nested an expression can be and
+/// still be folded all the way down.
+const RECENT_ELEMENTS: usize = 8;
+
+/// How many blocks an `Interpreter` can be inside at once. Running a function
+/// in one go has no such limit.
+pub const MAX_OPEN_BLOCKS: usize = 16;
+
+/// How many comparisons `ProgramBuilder::assemble` takes in one chain, like
+/// `a < b < c`. This is also how many values a `Stash` can hold.
+pub const MAX_CHAIN: usize = 4;
+
+/// How deeply expressions can nest without the `alloc` feature. With it,
+/// nesting is only limited by the heap.
+pub const MAX_EXPRESSION_DEPTH: usize = 32;
+
+/// The bytes at the start of a program header.
+pub const PROGRAM_MAGIC: [u8; 4] = *b"NEO1";
+
+/// The program format version that `ProgramBuilder::new_with_options`
+/// writes, and the only one `Program::try_new` accepts.
+pub const PROGRAM_VERSION: u8 = 1;
+
+/// How many bytes of header `ProgramBuilder::new_with_options` writes: the
+/// magic, the version and a byte giving the `Endianness`.
+pub const HEADER_LEN: usize = PROGRAM_MAGIC.len() + 2;
+
+/// The name of the function that `Program::run_main` runs.
+pub const ENTRY_POINT: &str = "main";
 
 // -----------------------------------------------------------------------------
 // Types
@@ -6,36 +78,210 @@
 
 /// Represents our program, which has some immutable block of memory
 /// containing instructions.
+///
+/// It only borrows the instructions, so it is cheap to copy.
+#[derive(Clone, Copy)]
 pub struct Program<'a> {
     data: &'a [u8],
+    /// How multi-byte integers are stored.
+    endianness: Endianness,
+    /// Any header that came before `data`.
+    header: &'a [u8],
+}
+
+/// A program which owns its instructions, so it doesn't borrow anything.
+///
+/// Borrow it as a `Program` to use it.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedProgram {
+    data: Vec<u8>,
 }
 
 /// Used to build a program.
 pub struct ProgramBuilder<'a> {
     data: &'a mut [u8],
     used: usize,
+    name_style: NameStyle,
+    /// Whether to work out arithmetic on constants as they are inserted.
+    constant_folding: bool,
+    /// Where the most recently inserted elements start, oldest first. Only
+    /// kept if we're folding constants.
+    recent: [usize; RECENT_ELEMENTS],
+    /// How many entries in `recent` are used.
+    num_recent: usize,
+    /// Folding never rewrites anything before this index, so that
+    /// `insert_all` and `restore` can put back what was there.
+    fold_floor: usize,
+    /// Whether a function can have the same name as an earlier one.
+    allow_duplicate_functions: bool,
+    /// How multi-byte integers are stored.
+    endianness: Endianness,
+    /// How many bytes at the start of `data` are header.
+    header_len: usize,
+    /// If set, nothing is written - we just count how many bytes would be.
+    counting: bool,
+}
+
+/// Which characters are allowed in function and variable names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameStyle {
+    /// ASCII letters, digits and `_` only.
+    AsciiOnly,
+    /// Any Unicode letters and digits, plus `_`.
+    #[default]
+    Unicode,
+}
+
+/// The order in which the bytes of a multi-byte integer (an `Integer` or a
+/// `Long`) are stored. Floats and `Block` lengths are always big endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endianness {
+    /// Most significant byte first.
+    #[default]
+    Big,
+    /// Least significant byte first.
+    Little,
 }
 
 /// Errors raised by our program
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     Unknown,
+    /// There's no function with the given name.
+    #[cfg(feature = "alloc")]
+    FunctionNotFound(String),
+    /// There's no function with the given name.
+    #[cfg(not(feature = "alloc"))]
     FunctionNotFound,
     SequenceError(usize),
     InsufficientSpace,
     NameTooLong,
+    /// A name was empty, or a parameter list had an empty entry.
     InvalidName,
-    SyntaxError,
+    /// A name held a character it isn't allowed to. Carries the character
+    /// and its offset, in characters, from the start of the name.
+    InvalidNameChar {
+        ch: char,
+        position: usize,
+    },
+    /// A name or string in the program data isn't valid UTF-8. Carries the
+    /// index of the element holding it.
+    InvalidUtf8 {
+        index: usize,
+    },
+    /// Some text couldn't be parsed. Carries the offset, in characters, of
+    /// the part of the text we didn't understand.
+    SyntaxError(usize),
+    ArithmeticOverflow,
+    DivideByZero,
+    StringTooLong,
+    /// An operation was given values it cannot work with. Carries the kinds
+    /// of the two operands (a unary operation gives its operand kind twice).
+    TypeMismatch(ValueKind, ValueKind),
+    /// An operation would have produced a value larger than
+    /// `MAX_STRING_LENGTH`.
+    AllocationTooLarge,
+    /// Tried to shift an Integer by a negative amount, or by 32 bits or more.
+    InvalidShift,
+    /// A loop went round more times than the limit given to
+    /// `Program::run_with_limit`.
+    IterationLimitExceeded,
+    /// Tried to read a variable which has not been set.
+    UndefinedVariable,
+    /// Tried to create more than `MAX_VARIABLES` variables.
+    TooManyVariables,
+    /// A function was called with the wrong number of arguments.
+    ArityMismatch,
+    /// A line of source text could not be turned into an `Element`. Carries
+    /// the line number, counting from 1, and the offset in characters, from
+    /// the start of the line, of the part we didn't understand.
+    ParseError {
+        line: usize,
+        offset: usize,
+    },
+    /// Tried to index past either end of a Vector or String.
+    IndexOutOfBounds,
+    /// Tried to cut a String part way through a character.
+    NotCharBoundary,
+    /// A String given to `parse_int` or `parse_float` didn't hold a number.
+    ParseValueError,
+    /// Ran more statements than the budget given to
+    /// `Program::run_budgeted`.
+    InstructionBudgetExceeded,
+    /// Tried to add a function with the same name as one which is already
+    /// in the program.
+    DuplicateFunction,
+    /// Tried to assign to a constant.
+    AssignToConstant,
+    /// Tried to make a value which needs the heap, without the `alloc`
+    /// feature.
+    NoAllocator,
+    /// A `FunctionHandle`, `BlockMarker` or `Checkpoint` was used with a
+    /// program other than the one which made it.
+    InvalidHandle,
+    /// Tried to raise an Integer to a negative power.
+    NegativeExponent,
+    /// A block made with `ProgramBuilder::begin_block` was longer than a
+    /// `Block` can say.
+    BlockTooLarge,
+    /// The output given to `run_with_output` returned an error.
+    OutputFailed,
+    /// Function calls (or, in an `Interpreter`, blocks) were nested more
+    /// deeply than allowed.
+    RecursionLimitExceeded,
+    /// An expression was nested more deeply than `MAX_EXPRESSION_DEPTH`.
+    ExpressionTooDeep,
+    /// The program data doesn't start with a header.
+    BadMagic,
+    /// The program header is for a version we don't understand.
+    UnsupportedVersion,
+    /// The program doesn't end with a `Checksum`, or the checksum is wrong.
+    ChecksumMismatch,
+    /// A `Goto` named a label which isn't in the outermost block of the
+    /// function.
+    LabelNotFound,
+    /// A chained comparison, like `a < b < c`, wasn't the whole expression
+    /// of a `return`, `print`, `let`, `+=`, `-=` or `if`. Carries the line
+    /// number, counting from 1.
+    ChainedComparison {
+        line: usize,
+    },
+    /// Tried to insert a `Float` which is infinite or NaN. There's no way to
+    /// write one in source text, so it couldn't be disassembled.
+    NonFiniteFloat,
+    /// An `Interpreter` went into more than `MAX_OPEN_BLOCKS` blocks at once.
+    TooManyOpenBlocks,
 }
 
 /// Values we understand. These are calculated from expressions.
+///
+/// With the `serde` feature, a Value can be serialized and deserialized. Both
+/// kinds of string serialize the same way, and deserializing always produces
+/// `Value::String`.
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value<'a> {
     StringLiteral(&'a str),
+    #[cfg(feature = "alloc")]
     String(String),
+    #[cfg(feature = "alloc")]
     Vector(Vec<Value<'a>>),
     Integer(i32),
+    Long(i64),
     Float(f32),
+    Boolean(bool),
+    Nil,
+}
+
+/// The different kinds of `Value`, without any contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    Integer,
+    Long,
+    Float,
+    String,
+    Vector,
+    Boolean,
     Nil,
 }
 
@@ -46,24 +292,789 @@ pub enum Element<'a> {
     Nop,
     /// Marks the end of a block
     End,
-    /// Followed by a name (the string)
-    Function(&'a str),
+    /// Starts a function, which has a name and takes some parameters
+    Function { name: &'a str, params: Params<'a> },
     /// Followed by an expression
     Return,
     /// Literal Integer
     Integer(i32),
+    /// Literal Float
+    Float(f32),
+    /// Followed by two expressions, which are added together
+    Add,
+    /// Followed by two expressions, which are multiplied together
+    Multiply,
+    /// Followed by two expressions, the first of which is divided by the
+    /// second
+    Divide,
+    /// Followed by two expressions, the second of which is subtracted from
+    /// the first
+    Subtract,
+    /// Followed by an expression, which is negated
+    Negate,
+    /// Literal String. When shown as text, any `\`, `"` or control
+    /// characters are escaped.
+    StringLiteral(&'a str),
+    /// Literal String, as written in source text - still with any escapes
+    /// (like `\n`) in it. The escapes are decoded when it is inserted into a
+    /// program, so it comes back out as a `StringLiteral`.
+    EscapedString(&'a str),
+    /// Followed by two integer expressions, which are bitwise ANDed
+    BitAnd,
+    /// Followed by two integer expressions, which are bitwise ORed
+    BitOr,
+    /// Followed by two integer expressions, which are bitwise XORed
+    BitXor,
+    /// Followed by two integer expressions, the first of which is shifted
+    /// left by the second
+    ShiftLeft,
+    /// Followed by two integer expressions, the first of which is shifted
+    /// right (arithmetically) by the second
+    ShiftRight,
+    /// Followed by a condition expression, then a block of statements
+    /// closed by an `End` (or an `Else`)
+    If,
+    /// Within an `If`, ends the block which runs when the condition is true
+    /// and starts the block which runs when it is false
+    Else,
+    /// Followed by a condition expression, then a block of statements
+    /// closed by an `End`. The block is run for as long as the condition is
+    /// true.
+    While,
+    /// Exits the innermost loop
+    Break,
+    /// Followed by a start expression and an end expression, then a block of
+    /// statements closed by an `End`. The block is run once for every
+    /// integer from the start to the end (inclusive), with the named
+    /// variable set to that integer.
+    For(&'a str),
+    /// Skips the rest of the innermost loop's block, and goes round again
+    Continue,
+    /// Reads the named variable
+    Variable(&'a str),
+    /// Followed by an expression, whose value is stored in the named variable
+    Assign(&'a str),
+    /// Followed by the given number of argument expressions, which are
+    /// passed to the named function. Produces the value the function returns.
+    Call(&'a str, u8),
+    /// Followed by two expressions. Produces true if they are equal
+    Equal,
+    /// Followed by two expressions. Produces true if they are not
+    /// equal
+    NotEqual,
+    /// Followed by two expressions. Produces true if the first is less than
+    /// the second
+    Less,
+    /// Followed by two expressions. Produces true if the first is less than
+    /// or equal to the second
+    LessEqual,
+    /// Followed by two expressions. Produces true if the first is greater
+    /// than the second
+    Greater,
+    /// Followed by two expressions. Produces true if the first is greater
+    /// than or equal to the second
+    GreaterEqual,
+    /// Followed by two boolean expressions. Produces true if both are true.
+    /// The second is not evaluated if the first is false.
+    And,
+    /// Followed by two boolean expressions. Produces true if either is true.
+    /// The second is not evaluated if the first is true.
+    Or,
+    /// Followed by a boolean expression, which is inverted
+    Not,
+    /// Literal Boolean
+    Boolean(bool),
+    /// Followed by the given number of expressions, which are collected into
+    /// a Vector
+    Vector(u8),
+    /// Followed by a Vector expression and an Integer expression. Produces
+    /// the item at that position in the Vector, counting from the end if the
+    /// Integer is negative.
+    Index,
+    /// Followed by a String or Vector expression. Produces its length - the
+    /// number of bytes in a String, or the number of items in a Vector.
+    Len,
+    /// Followed by two integer expressions. Produces the remainder after
+    /// dividing the first by the second
+    Modulo,
+    /// Followed by two expressions. Produces the first raised to the power
+    /// of the second
+    Pow,
+    /// Followed by an integer expression, which is converted to a Float
+    ToFloat,
+    /// Followed by a float expression, which is converted to an Integer by
+    /// rounding towards zero
+    ToInt,
+    /// Followed by an expression, which is written to the output
+    Print,
+    /// Followed by a whole block (such as an `If` and everything up to its
+    /// `End`) which is this many bytes long, so it can be skipped without
+    /// reading it. Make these with `ProgramBuilder::begin_block`.
+    Block(u16),
+    /// Literal Long, which is a 64-bit Integer
+    Long(i64),
+    /// Literal Nil
+    Nil,
+    /// Followed by a condition expression and two more expressions. Produces
+    /// the first if the condition is true, or the second if not. Only the
+    /// chosen one is evaluated.
+    Select,
+    /// Followed by a String expression and two Integer expressions. Produces
+    /// the part of the String between those two byte positions.
+    Substring,
+    /// Followed by an expression. Converts any value to a String, the same
+    /// way `Display` does.
+    ToStr,
+    /// Followed by a String expression. Parses it as an integer, the same
+    /// way integer literals are parsed.
+    ParseInt,
+    /// Followed by a String expression. Parses it as a Float.
+    ParseFloat,
+    /// Followed by an expression, whose value is added to the named variable
+    AddAssign(&'a str),
+    /// Followed by an expression, whose value is subtracted from the named
+    /// variable
+    SubAssign(&'a str),
+    /// Only allowed at the top level of a program. Followed by an
+    /// expression, which gives the value of the named constant. Any function
+    /// can read the constant as if it were a variable, but not change it.
+    Const(&'a str),
+    /// Followed by a Vector expression. Produces a copy of the Vector with
+    /// its items in order.
+    Sort,
+    /// Followed by a Vector expression. Produces a copy of the Vector with
+    /// its items in reverse order.
+    Reverse,
+    /// Followed by a Vector expression. Calls the named function, which must
+    /// take one parameter, with each item. Produces a Vector of the results.
+    Map(&'a str),
+    /// Followed by a Vector expression. Calls the named function, which must
+    /// take one parameter, with each item. Produces a Vector of the items
+    /// for which the function returned something truthy.
+    Filter(&'a str),
+    /// Followed by a Vector expression and another expression. Produces a
+    /// copy of the Vector with the second value added to the end.
+    Push,
+    /// Followed by an expression, then a series of `Case` arms (and
+    /// perhaps an `Else` arm), closed by an `End`. Runs the first arm whose
+    /// value equals the expression, or the `Else` arm if none do.
+    Match,
+    /// Within a `Match`, followed by a value expression and then the block
+    /// of statements to run if the value matches
+    Case,
+    /// Followed by two String expressions. Produces a Boolean which says
+    /// whether they are equal, ignoring ASCII case.
+    EqualsIgnoreCase,
+    /// Followed by a String expression. Converts ASCII letters to upper case.
+    ToUpper,
+    /// Followed by a String expression. Converts ASCII letters to lower case.
+    ToLower,
+    /// Marks the end of the program, and holds the CRC32 of every byte before
+    /// it (including any header). Make it with
+    /// `ProgramBuilder::finalize_with_crc`.
+    Checksum(u32),
+    /// Followed by a numeric expression. Produces its absolute value.
+    Abs,
+    /// Followed by two numeric expressions of the same type. Produces the
+    /// smaller one.
+    Min,
+    /// Followed by two numeric expressions of the same type. Produces the
+    /// larger one.
+    Max,
+    /// Followed by a Float expression. Produces its square root (which is
+    /// NaN for negative numbers). Needs the `std` or `math` feature.
+    Sqrt,
+    /// Followed by a Float expression, in radians. Produces its sine. Needs
+    /// the `std` or `math` feature.
+    Sin,
+    /// Followed by a Float expression, in radians. Produces its cosine.
+    /// Needs the `std` or `math` feature.
+    Cos,
+    /// Marks a place in a function which a `Goto` can jump to. Does nothing
+    /// when run.
+    Label(&'a str),
+    /// Carries on running from just after the named `Label`, which must be in
+    /// the outermost block of the current function. Leaves any blocks it is
+    /// inside.
+    Goto(&'a str),
+    /// Followed by an expression, which is kept in the given slot (counting
+    /// from 0, up to `MAX_CHAIN`) until the next `Return`, `Print`, `Assign`,
+    /// `AddAssign`, `SubAssign` or `If` has worked out its expression.
+    ///
+    /// The slots aren't variables, so they can't clash with one, and they
+    /// don't count towards `MAX_VARIABLES`. `ProgramBuilder::assemble` uses
+    /// them for chained comparisons.
+    Stash(u8),
+    /// Produces the value kept in the given slot by a `Stash`.
+    Stashed(u8),
+}
+
+/// Remembers where a function is in a particular program, so it can be run
+/// many times without searching for it each time.
+///
+/// Get one from `Program::find_function`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionHandle {
+    /// Where the first statement of the function is.
+    body_index: usize,
+    /// How many parameters the function takes.
+    num_params: usize,
+    /// The address of the program data the handle was made from.
+    data_addr: usize,
+    /// The length of the program data the handle was made from.
+    data_len: usize,
+}
+
+/// Remembers where the length of an unfinished block needs to go.
+///
+/// Get one from `ProgramBuilder::begin_block`, and give it back to
+/// `ProgramBuilder::end_block`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlockMarker {
+    /// Where the two length bytes are.
+    length_index: usize,
+}
+
+/// Remembers how much of a `ProgramBuilder` was used, so anything inserted
+/// after that can be taken out again.
+///
+/// Get one from `ProgramBuilder::checkpoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// How many bytes were used.
+    used: usize,
+    /// The address of the space the checkpoint was made from.
+    data_addr: usize,
+}
+
+/// Some numbers about a program, from `Program::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProgramStats {
+    /// How many functions there are.
+    pub functions: usize,
+    /// How many elements there are, as with `Program::statement_count`.
+    pub statements: usize,
+    /// How deeply blocks are nested. A function with nothing but simple
+    /// statements in it counts as 1.
+    pub max_depth: usize,
+}
+
+/// How many times each opcode was run, from `Program::run_profiled`.
+///
+/// An opcode is the first byte of an element, so an Integer is counted
+/// under whichever of its encodings the program happens to use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpcodeCounts {
+    counts: [u64; 256],
+}
+
+/// The names of the parameters a function takes.
+#[derive(Clone, Copy)]
+pub struct Params<'a> {
+    repr: ParamsRepr<'a>,
+}
+
+/// Where the parameter names in a `Params` come from.
+#[derive(Clone, Copy)]
+enum ParamsRepr<'a> {
+    /// Length-prefixed names, as stored in a program.
+    Encoded { count: u8, data: &'a [u8] },
+    /// A comma-separated list of names, as written in source.
+    Source(&'a str),
+}
+
+/// An iterator through the names in a `Params`.
+pub struct ParamsIter<'a> {
+    repr: ParamsRepr<'a>,
+}
+
+/// The owned form of a `Value`, which is what we deserialize.
+///
+/// The variants must match the order used in `Value`'s `Serialize` impl.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+#[serde(rename = "Value")]
+enum OwnedValue {
+    String(String),
+    Vector(Vec<OwnedValue>),
+    Integer(i32),
+    Float(f32),
+    Boolean(bool),
+    Nil,
+    Long(i64),
 }
 
 /// An iterator through the elements of our program.
 pub struct ElementIter<'a> {
-    program: &'a Program<'a>,
+    program: Program<'a>,
+    index: usize,
+}
+
+/// Runs a function one statement at a time.
+///
+/// Get one from `Program::interpreter`. A function called from inside an
+/// expression runs all in one go, as part of the statement which called it.
+///
+/// The same settings are used for every step, so printed text goes to one
+/// place, and calls to functions the program doesn't have go to one host.
+pub struct Interpreter<'c, 'a> {
+    program: Program<'a>,
+    /// Where the first statement of the function is.
+    start: usize,
+    /// The next statement to run.
+    index: usize,
+    /// The variables in the function.
+    scope: VarScope<'a>,
+    /// The blocks we are inside, innermost last.
+    blocks: [OpenBlock<'a>; MAX_OPEN_BLOCKS],
+    /// How many entries in `blocks` are used.
+    num_blocks: usize,
+    /// Whether the function has returned or ended.
+    finished: bool,
+    /// The settings for running each statement.
+    context: Context<'c, 'a>,
+}
+
+/// What happened when an `Interpreter` ran a statement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepResult<'a> {
+    /// There are more statements to run.
+    Continue,
+    /// The function returned this value.
+    Returned(Value<'a>),
+    /// The function reached its `End` (or had already finished).
+    Finished,
+}
+
+/// A block that an `Interpreter` is inside.
+#[derive(Debug, Clone, Copy)]
+enum OpenBlock<'a> {
+    /// Either half of an `If`.
+    If,
+    /// The arm of a `Match` which is running.
+    Match,
+    /// A `While` loop. `start` is the index of the `While`, and `body` is
+    /// where the body of the loop starts.
+    While { start: usize, body: usize },
+    /// A `For` loop, which has got as far as `value`.
+    For {
+        name: &'a str,
+        value: i32,
+        end: i32,
+        body: usize,
+    },
+}
+
+/// Describes how a block of statements finished running.
+#[derive(Debug, Clone, PartialEq)]
+enum BlockEnd<'a> {
+    /// Reached the `End` of the block. Holds the index just past the `End`.
+    End(usize),
+    /// Reached an `Else` in the block. Holds the index just past the `Else`.
+    Else(usize),
+    /// Reached a `Case` in the block. Holds the index just past the `Case`.
+    Case(usize),
+    /// Hit a `Return` statement, which produced this value.
+    Return(Value<'a>),
+    /// Hit a `Break` statement. Holds the index of the `Break`.
+    Break(usize),
+    /// Hit a `Continue` statement. Holds the index of the `Continue`.
+    Continue(usize),
+    /// Hit a `Goto` statement. Holds the index of the `Goto`, and the name of
+    /// the label.
+    Goto(usize, &'a str),
+}
+
+/// Describes which arm of a `Match` should run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CaseChoice {
+    /// Run the arm which starts at this index.
+    Arm(usize),
+    /// No arm matched, and there is no `Else`. Holds the index just past the
+    /// `End` of the `Match`.
+    NoArm(usize),
+}
+
+/// Holds the variables for a function call, without needing the heap.
+///
+/// Pass one to `Program::run_with_scope` to keep variables from one call to
+/// the next.
+#[derive(Debug)]
+pub struct VarScope<'a> {
+    names: [&'a str; MAX_VARIABLES],
+    values: [Value<'a>; MAX_VARIABLES],
+    used: usize,
+    /// The values kept by `Stash` statements, which aren't variables.
+    stash: [Option<Value<'a>>; MAX_CHAIN],
+}
+
+/// A stack used while evaluating an expression. With the `alloc` feature it
+/// grows as required, otherwise it holds up to `MAX_EXPRESSION_DEPTH` items.
+struct EvalStack<T> {
+    #[cfg(feature = "alloc")]
+    items: Vec<T>,
+    #[cfg(not(feature = "alloc"))]
+    items: [Option<T>; MAX_EXPRESSION_DEPTH],
+    #[cfg(not(feature = "alloc"))]
+    used: usize,
+}
+
+/// An operator which is waiting for some of its operands to be evaluated.
+struct PendingOperator<'a> {
+    element: Element<'a>,
+    /// Where the operator is in the program.
     index: usize,
+    /// How many operands are still to come.
+    remaining: usize,
+}
+
+/// Functions provided by the program which is running the script.
+///
+/// When a script calls a function that it doesn't define itself, the call is
+/// passed here. Give one to `Program::run_with_host`.
+pub trait HostFunctions {
+    /// Call the named function with the given arguments.
+    ///
+    /// If there is no function called `name`, return
+    /// `Error::FunctionNotFound`.
+    fn call<'a>(&mut self, name: &str, args: &[Value<'a>]) -> Result<Value<'a>, Error>;
+}
+
+/// Settings and state for a single run of a program.
+///
+/// Strings stored in `scratch` live as long as `'a`, like the program's own.
+struct Context<'c, 'a> {
+    /// How many times any one loop may go around, if limited.
+    max_iterations: Option<u32>,
+    /// Where `Print` sends its text. If there isn't one, it is thrown away.
+    output: Option<&'c mut dyn core::fmt::Write>,
+    /// Where calls go if the script doesn't have the function.
+    host: Option<&'c mut dyn HostFunctions>,
+    /// How many function calls deep we are.
+    depth: usize,
+    /// How many function calls deep we are allowed to go.
+    max_depth: usize,
+    /// Called before each statement is run.
+    hook: Option<StatementHook<'c>>,
+    /// How many more statements may be run, if limited.
+    budget: Option<u64>,
+    /// Where to count each opcode as it runs, if we're profiling.
+    counts: Option<&'c mut OpcodeCounts>,
+    /// The unused part of the buffer given to `Program::run_into`, where
+    /// joined strings go.
+    scratch: Option<&'a mut [u8]>,
+}
+
+/// Something which is called with each statement, and its index, before it
+/// runs.
+type StatementHook<'c> = &'c mut dyn FnMut(usize, &Element);
+
+/// Settings for running a function, which can be combined.
+///
+/// Each method sets one thing and hands the options back, so they can be
+/// chained, and then given to `Program::run_with_options`. Anything not set
+/// works as it does for `Program::run`.
+pub struct RunOptions<'c, 'a> {
+    context: Context<'c, 'a>,
+}
+
+impl<'c, 'a> Default for Context<'c, 'a> {
+    fn default() -> Context<'c, 'a> {
+        Context {
+            max_iterations: None,
+            output: None,
+            host: None,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            hook: None,
+            budget: None,
+            counts: None,
+            scratch: None,
+        }
+    }
+}
+
+impl<'c, 'a> RunOptions<'c, 'a> {
+    /// Options which run a function just as `Program::run` does.
+    pub fn new() -> RunOptions<'c, 'a> {
+        RunOptions {
+            context: Context::default(),
+        }
+    }
+
+    /// Send anything the function prints to `output`. Otherwise it is
+    /// thrown away.
+    pub fn output(mut self, output: &'c mut dyn core::fmt::Write) -> RunOptions<'c, 'a> {
+        self.context.output = Some(output);
+        self
+    }
+
+    /// Pass calls to any function not in the program on to `host`.
+    pub fn host(mut self, host: &'c mut dyn HostFunctions) -> RunOptions<'c, 'a> {
+        self.context.host = Some(host);
+        self
+    }
+
+    /// Stop with `Error::IterationLimitExceeded` if any loop goes round more
+    /// than `max_iterations` times.
+    ///
+    /// Each `goto` back to an earlier label counts as going round a loop, and
+    /// all the ones in a function share one count.
+    pub fn max_iterations(mut self, max_iterations: u32) -> RunOptions<'c, 'a> {
+        self.context.max_iterations = Some(max_iterations);
+        self
+    }
+
+    /// Stop with `Error::InstructionBudgetExceeded` once `max_instructions`
+    /// statements have been run.
+    ///
+    /// Unlike `max_iterations`, this counts every statement, in every loop
+    /// and every function called, so it also catches runaway recursion.
+    pub fn budget(mut self, max_instructions: u64) -> RunOptions<'c, 'a> {
+        self.context.budget = Some(max_instructions);
+        self
+    }
+
+    /// Stop with `Error::RecursionLimitExceeded` if function calls nest more
+    /// than `max_depth` deep, rather than `DEFAULT_MAX_DEPTH`.
+    pub fn max_depth(mut self, max_depth: usize) -> RunOptions<'c, 'a> {
+        self.context.max_depth = max_depth;
+        self
+    }
+
+    /// Call `hook` with the index of each statement, and the statement
+    /// itself, just before it runs.
+    ///
+    /// This includes statements in blocks, and in any functions that are
+    /// called. The expressions which belong to a statement aren't passed to
+    /// the hook.
+    pub fn hook(mut self, hook: &'c mut dyn FnMut(usize, &Element)) -> RunOptions<'c, 'a> {
+        self.context.hook = Some(hook);
+        self
+    }
+
+    /// Count how many times each opcode runs, adding to `counts`.
+    ///
+    /// This includes the statements and the expression elements, in every
+    /// function called, so it shows where a script spends its time.
+    pub fn profile(mut self, counts: &'c mut OpcodeCounts) -> RunOptions<'c, 'a> {
+        self.context.counts = Some(counts);
+        self
+    }
+
+    /// Put any strings the function makes in `scratch`, rather than on the
+    /// heap.
+    ///
+    /// This lets string concatenation work without the `alloc` feature. A
+    /// string result comes back as a `Value::StringLiteral`, borrowing from
+    /// `scratch` if it was made while running. `+`, `str`, `to_upper`,
+    /// `to_lower` and `substring` put their results in `scratch`.
+    ///
+    /// Each new string goes just after the last one, and the space isn't
+    /// handed back until the function returns, even if the string is thrown
+    /// away - so a loop which builds strings uses up more of `scratch` every
+    /// time round. If `scratch` fills up, you get `Error::InsufficientSpace`.
+    pub fn scratch(mut self, scratch: &'a mut [u8]) -> RunOptions<'c, 'a> {
+        self.context.scratch = Some(scratch);
+        self
+    }
+}
+
+impl<'c, 'a> Default for RunOptions<'c, 'a> {
+    fn default() -> RunOptions<'c, 'a> {
+        RunOptions::new()
+    }
+}
+
+impl<'c, 'a> Context<'c, 'a> {
+    /// Check whether a loop which has gone round `iterations` times is
+    /// allowed to go round again.
+    fn check_iterations(&self, iterations: usize) -> Result<(), Error> {
+        if self.max_iterations.map(|max| max as usize) == Some(iterations) {
+            Err(Error::IterationLimitExceeded)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Take one statement from the budget, if we have one.
+    fn use_budget(&mut self) -> Result<(), Error> {
+        match self.budget.as_mut() {
+            Some(0) => Err(Error::InstructionBudgetExceeded),
+            Some(budget) => {
+                *budget -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Count one run of an opcode, if we're profiling.
+    fn count(&mut self, opcode: u8) {
+        if let Some(counts) = self.counts.as_mut() {
+            counts.counts[usize::from(opcode)] += 1;
+        }
+    }
+
+    /// Copy the strings, one after the other, into the scratch buffer, and
+    /// give back the joined-up string.
+    ///
+    /// Gives `Error::InsufficientSpace` if they don't fit in what's left.
+    fn store(&mut self, parts: &[&str]) -> Result<&'a str, Error> {
+        self.store_mut(parts).map(|s| &*s)
+    }
+
+    /// Like `store`, but the string we give back can be changed in place.
+    fn store_mut(&mut self, parts: &[&str]) -> Result<&'a mut str, Error> {
+        let scratch = self.scratch.take().ok_or(Error::NoAllocator)?;
+        let len = parts.iter().map(|part| part.len()).sum();
+        if len > scratch.len() {
+            self.scratch = Some(scratch);
+            return Err(Error::InsufficientSpace);
+        }
+        let (used, rest) = scratch.split_at_mut(len);
+        self.scratch = Some(rest);
+        let mut offset = 0;
+        for part in parts {
+            used[offset..offset + part.len()].copy_from_slice(part.as_bytes());
+            offset += part.len();
+        }
+        // Joining whole strings can't make invalid UTF-8
+        core::str::from_utf8_mut(used).map_err(|_| Error::Unknown)
+    }
+
+    /// Write a value into the scratch buffer, the same way `Display` does,
+    /// and give back the string.
+    ///
+    /// Gives `Error::InsufficientSpace` if it doesn't fit in what's left.
+    fn store_display(&mut self, value: &Value) -> Result<&'a str, Error> {
+        use core::fmt::Write;
+
+        struct Cursor<'b> {
+            buffer: &'b mut [u8],
+            used: usize,
+        }
+
+        impl<'b> core::fmt::Write for Cursor<'b> {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                let end = self.used + s.len();
+                let space = self
+                    .buffer
+                    .get_mut(self.used..end)
+                    .ok_or(core::fmt::Error)?;
+                space.copy_from_slice(s.as_bytes());
+                self.used = end;
+                Ok(())
+            }
+        }
+
+        let scratch = self.scratch.take().ok_or(Error::NoAllocator)?;
+        let mut cursor = Cursor {
+            buffer: scratch,
+            used: 0,
+        };
+        if write!(cursor, "{}", value).is_err() {
+            self.scratch = Some(cursor.buffer);
+            return Err(Error::InsufficientSpace);
+        }
+        let (used, rest) = cursor.buffer.split_at_mut(cursor.used);
+        self.scratch = Some(rest);
+        let used: &'a [u8] = used;
+        // We only wrote whole strings, so this is valid UTF-8
+        core::str::from_utf8(used).map_err(|_| Error::Unknown)
+    }
+
+    /// Write a value to the output, if we have one.
+    fn print(&mut self, value: &Value) -> Result<(), Error> {
+        match self.output.as_mut() {
+            Some(output) => write!(output, "{}", value).map_err(|_| Error::OutputFailed),
+            None => Ok(()),
+        }
+    }
 }
 
 // -----------------------------------------------------------------------------
 // Implementations
 // -----------------------------------------------------------------------------
 
+impl Error {
+    /// Make a `FunctionNotFound` error, which holds the name if we can.
+    fn function_not_found(name: &str) -> Error {
+        #[cfg(feature = "alloc")]
+        {
+            Error::FunctionNotFound(name.to_string())
+        }
+        #[cfg(not(feature = "alloc"))]
+        {
+            let _ = name;
+            Error::FunctionNotFound
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Unknown => write!(f, "unknown error"),
+            #[cfg(feature = "alloc")]
+            Error::FunctionNotFound(name) => write!(f, "no function called {name:?}"),
+            #[cfg(not(feature = "alloc"))]
+            Error::FunctionNotFound => write!(f, "no function with that name"),
+            Error::SequenceError(index) => write!(f, "unexpected element at index {index}"),
+            Error::InsufficientSpace => write!(f, "not enough space for the program"),
+            Error::NameTooLong => write!(f, "name is too long"),
+            Error::InvalidName => write!(f, "invalid name"),
+            Error::InvalidNameChar { ch, position } => {
+                write!(f, "invalid character {ch:?} at position {position} in name")
+            }
+            Error::InvalidUtf8 { index } => {
+                write!(f, "invalid UTF-8 in element at index {index}")
+            }
+            Error::SyntaxError(offset) => write!(f, "syntax error at character {offset}"),
+            Error::ArithmeticOverflow => write!(f, "arithmetic overflow"),
+            Error::DivideByZero => write!(f, "divide by zero"),
+            Error::StringTooLong => write!(f, "string is too long"),
+            Error::TypeMismatch(a, b) => write!(f, "type mismatch between {a:?} and {b:?}"),
+            Error::AllocationTooLarge => write!(f, "value would be too large"),
+            Error::InvalidShift => write!(f, "invalid shift amount"),
+            Error::IterationLimitExceeded => write!(f, "loop iteration limit exceeded"),
+            Error::UndefinedVariable => write!(f, "undefined variable"),
+            Error::TooManyVariables => write!(f, "too many variables"),
+            Error::ArityMismatch => write!(f, "wrong number of arguments"),
+            Error::ParseError { line, offset } => {
+                write!(f, "parse error on line {line}, at offset {offset}")
+            }
+            Error::IndexOutOfBounds => write!(f, "index out of bounds"),
+            Error::NotCharBoundary => write!(f, "index is not on a character boundary"),
+            Error::ParseValueError => write!(f, "string does not hold a number"),
+            Error::InstructionBudgetExceeded => write!(f, "instruction budget exceeded"),
+            Error::DuplicateFunction => write!(f, "duplicate function"),
+            Error::AssignToConstant => write!(f, "cannot assign to a constant"),
+            Error::NoAllocator => write!(f, "no allocator available"),
+            Error::InvalidHandle => write!(f, "handle is for a different program"),
+            Error::NegativeExponent => write!(f, "negative exponent"),
+            Error::BlockTooLarge => write!(f, "block is too large"),
+            Error::OutputFailed => write!(f, "writing output failed"),
+            Error::RecursionLimitExceeded => write!(f, "recursion limit exceeded"),
+            Error::ExpressionTooDeep => write!(f, "expression nested too deeply"),
+            Error::BadMagic => write!(f, "program has no header"),
+            Error::UnsupportedVersion => write!(f, "unsupported program version"),
+            Error::LabelNotFound => write!(f, "no label with that name"),
+            Error::NonFiniteFloat => write!(f, "float is infinite or NaN"),
+            Error::TooManyOpenBlocks => write!(f, "too many blocks open at once"),
+            Error::ChainedComparison { line } => {
+                write!(
+                    f,
+                    "chained comparison on line {line} must be a whole statement"
+                )
+            }
+            Error::ChecksumMismatch => write!(f, "checksum mismatch"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
 impl<'a> Program<'a> {
     pub(crate) const NOP_ID: u8 = 0x00;
     pub(crate) const FUNCTION_ID: u8 = 0x01;
@@ -73,646 +1084,11098 @@ impl<'a> Program<'a> {
     pub(crate) const INTEGER2_ID: u8 = 0x05;
     pub(crate) const INTEGER3_ID: u8 = 0x06;
     pub(crate) const INTEGER4_ID: u8 = 0x07;
+    pub(crate) const FLOAT_ID: u8 = 0x08;
+    pub(crate) const ADD_ID: u8 = 0x09;
+    pub(crate) const MULTIPLY_ID: u8 = 0x0A;
+    pub(crate) const DIVIDE_ID: u8 = 0x0B;
+    pub(crate) const SUBTRACT_ID: u8 = 0x0C;
+    pub(crate) const NEGATE_ID: u8 = 0x0D;
+    pub(crate) const STRING_ID: u8 = 0x0E;
+    pub(crate) const BIT_AND_ID: u8 = 0x0F;
+    pub(crate) const BIT_OR_ID: u8 = 0x10;
+    pub(crate) const BIT_XOR_ID: u8 = 0x11;
+    pub(crate) const SHIFT_LEFT_ID: u8 = 0x12;
+    pub(crate) const SHIFT_RIGHT_ID: u8 = 0x13;
+    pub(crate) const IF_ID: u8 = 0x14;
+    pub(crate) const ELSE_ID: u8 = 0x15;
+    pub(crate) const WHILE_ID: u8 = 0x16;
+    pub(crate) const BREAK_ID: u8 = 0x17;
+    pub(crate) const FOR_ID: u8 = 0x18;
+    pub(crate) const CONTINUE_ID: u8 = 0x19;
+    pub(crate) const VARIABLE_ID: u8 = 0x1A;
+    pub(crate) const ASSIGN_ID: u8 = 0x1B;
+    pub(crate) const CALL_ID: u8 = 0x1C;
+    pub(crate) const EQUAL_ID: u8 = 0x1D;
+    pub(crate) const NOT_EQUAL_ID: u8 = 0x1E;
+    pub(crate) const LESS_ID: u8 = 0x1F;
+    pub(crate) const LESS_EQUAL_ID: u8 = 0x20;
+    pub(crate) const GREATER_ID: u8 = 0x21;
+    pub(crate) const GREATER_EQUAL_ID: u8 = 0x22;
+    pub(crate) const AND_ID: u8 = 0x23;
+    pub(crate) const OR_ID: u8 = 0x24;
+    pub(crate) const NOT_ID: u8 = 0x25;
+    pub(crate) const FALSE_ID: u8 = 0x26;
+    pub(crate) const TRUE_ID: u8 = 0x27;
+    pub(crate) const VECTOR_ID: u8 = 0x28;
+    pub(crate) const INDEX_ID: u8 = 0x29;
+    pub(crate) const LEN_ID: u8 = 0x2A;
+    pub(crate) const MODULO_ID: u8 = 0x2B;
+    pub(crate) const POW_ID: u8 = 0x2C;
+    pub(crate) const TO_FLOAT_ID: u8 = 0x2D;
+    pub(crate) const TO_INT_ID: u8 = 0x2E;
+    pub(crate) const PRINT_ID: u8 = 0x2F;
+    pub(crate) const BLOCK_ID: u8 = 0x30;
+    pub(crate) const INTEGER_VARINT_ID: u8 = 0x31;
+    pub(crate) const LONG_ID: u8 = 0x32;
+    pub(crate) const NIL_ID: u8 = 0x33;
+    pub(crate) const SELECT_ID: u8 = 0x34;
+    pub(crate) const SUBSTRING_ID: u8 = 0x35;
+    pub(crate) const TO_STR_ID: u8 = 0x36;
+    pub(crate) const PARSE_INT_ID: u8 = 0x37;
+    pub(crate) const PARSE_FLOAT_ID: u8 = 0x38;
+    pub(crate) const ADD_ASSIGN_ID: u8 = 0x39;
+    pub(crate) const SUB_ASSIGN_ID: u8 = 0x3A;
+    pub(crate) const CONST_ID: u8 = 0x3B;
+    pub(crate) const SORT_ID: u8 = 0x3C;
+    pub(crate) const REVERSE_ID: u8 = 0x3D;
+    pub(crate) const MAP_ID: u8 = 0x3E;
+    pub(crate) const FILTER_ID: u8 = 0x3F;
+    pub(crate) const PUSH_ID: u8 = 0x40;
+    pub(crate) const MATCH_ID: u8 = 0x41;
+    pub(crate) const CASE_ID: u8 = 0x42;
+    pub(crate) const EQUALS_IGNORE_CASE_ID: u8 = 0x43;
+    pub(crate) const TO_UPPER_ID: u8 = 0x44;
+    pub(crate) const TO_LOWER_ID: u8 = 0x45;
+    pub(crate) const BIG_ENDIAN_ID: u8 = 0x46;
+    pub(crate) const LITTLE_ENDIAN_ID: u8 = 0x47;
+    pub(crate) const CHECKSUM_ID: u8 = 0x48;
+    pub(crate) const ABS_ID: u8 = 0x49;
+    pub(crate) const MIN_ID: u8 = 0x4A;
+    pub(crate) const MAX_ID: u8 = 0x4B;
+    pub(crate) const SQRT_ID: u8 = 0x4C;
+    pub(crate) const SIN_ID: u8 = 0x4D;
+    pub(crate) const COS_ID: u8 = 0x4E;
+    pub(crate) const LABEL_ID: u8 = 0x4F;
+    pub(crate) const GOTO_ID: u8 = 0x50;
+    pub(crate) const STASH_ID: u8 = 0x51;
+    pub(crate) const STASHED_ID: u8 = 0x52;
+
+    /// Wrap some program data, without checking it.
+    ///
+    /// This is free, so use it for data you trust (such as the output of a
+    /// `ProgramBuilder`). Badly formed data is still caught, but only when
+    /// the bad part is run.
+    ///
+    /// Raw data, with no header, is fine. If the data starts with the header
+    /// written by `ProgramBuilder::new_with_header` or
+    /// `ProgramBuilder::new_with_options`, it is stripped off (without
+    /// checking the version), so indices into the program start just after
+    /// it, and integers are decoded in the order it gives. Otherwise they
+    /// are big endian.
+    pub fn new(program_data: &'a [u8]) -> Program<'a> {
+        let mut data = program_data;
+        if data.starts_with(&PROGRAM_MAGIC) {
+            // Skip the version too
+            data = data.get(PROGRAM_MAGIC.len() + 1..).unwrap_or_default();
+        }
+        let endianness = match data.first() {
+            Some(&Program::LITTLE_ENDIAN_ID) => Endianness::Little,
+            _ => Endianness::Big,
+        };
+        if let Some((&(Program::BIG_ENDIAN_ID | Program::LITTLE_ENDIAN_ID), rest)) =
+            data.split_first()
+        {
+            data = rest;
+        }
+        Program {
+            data,
+            endianness,
+            header: &program_data[0..program_data.len() - data.len()],
+        }
+    }
 
-    pub fn new(program_data: &'a [u8]) -> Program {
-        Program { data: program_data }
+    /// Wrap some program data, checking it with `validate` first.
+    ///
+    /// The data must start with the header written by
+    /// `ProgramBuilder::new_with_header` or
+    /// `ProgramBuilder::new_with_options`, or you get `Error::BadMagic`. If
+    /// the header is for a version other than `PROGRAM_VERSION`, you get
+    /// `Error::UnsupportedVersion`. `ProgramBuilder::new` doesn't write a
+    /// header, so what it builds has to be loaded with `Program::new`.
+    ///
+    /// This walks the whole program, so it is O(n) in the size of the
+    /// program, but afterwards you know the program is well formed. Use it
+    /// for data loaded from somewhere you don't trust.
+    pub fn try_new(program_data: &'a [u8]) -> Result<Program<'a>, Error> {
+        let Some(rest) = program_data.strip_prefix(&PROGRAM_MAGIC) else {
+            return Err(Error::BadMagic);
+        };
+        match rest.first() {
+            Some(&PROGRAM_VERSION) => {}
+            Some(_) => return Err(Error::UnsupportedVersion),
+            None => return Err(Error::BadMagic),
+        }
+        let program = Program::new(program_data);
+        program.validate()?;
+        Ok(program)
     }
 
-    pub fn iter_statements(&'a self, index: usize) -> ElementIter<'a> {
+    pub fn iter_statements(&self, index: usize) -> ElementIter<'a> {
         ElementIter {
-            program: self,
+            program: *self,
             index,
         }
     }
 
-    pub fn run(&self, function_name: &str) -> Result<Value, Error> {
-        let mut fn_index = None;
-        // Looking for a function
-        for (index, statement) in self.iter_statements(0) {
-            match statement {
-                Element::Function(name) => {
-                    if name == function_name {
-                        fn_index = Some(index + 2 + name.len());
-                        break;
-                    }
+    /// Go through every element in the program, with where it starts and how
+    /// many bytes it takes up.
+    ///
+    /// Like `iter_statements`, this stops at the first element that can't be
+    /// decoded.
+    pub fn iter_with_spans(&self) -> impl Iterator<Item = (usize, usize, Element<'a>)> + 'a {
+        let mut iter = self.iter_statements(0);
+        core::iter::from_fn(move || {
+            let (start, element) = iter.next()?;
+            Some((start, iter.index - start, element))
+        })
+    }
+
+    /// Go through the names of the functions in the program, in order.
+    ///
+    /// The body of each function is skipped. If the program is malformed, we
+    /// stop at the first thing which isn't a complete function.
+    pub fn functions(&self) -> impl Iterator<Item = &'a str> + 'a {
+        self.iter_toplevel()
+            .filter_map(|(_, element)| match element {
+                Element::Function { name, .. } => Some(name),
+                _ => None,
+            })
+    }
+
+    /// Go through the top level of the program, which is the `Function`
+    /// that starts each function and the `Const` that starts each constant,
+    /// along with their indices.
+    ///
+    /// The body of each function is skipped, including any blocks inside it,
+    /// as is the value of each constant. If the program is malformed, we stop
+    /// at the first thing which isn't a complete function or constant.
+    pub fn iter_toplevel(&self) -> impl Iterator<Item = (usize, Element<'a>)> + 'a {
+        let program = *self;
+        let mut next_index = Some(0);
+        core::iter::from_fn(move || {
+            let mut iter = program.iter_statements(next_index?);
+            match iter.next() {
+                Some((index, element @ Element::Function { .. })) => {
+                    next_index = program.skip_to_end(iter.index).ok();
+                    Some((index, element))
+                }
+                Some((index, element @ Element::Const(_))) => {
+                    next_index = program.skip_expression(iter.index).ok();
+                    Some((index, element))
                 }
                 _ => {
-                    // Skip this statement
+                    // Only functions and constants are allowed at the top level
+                    next_index = None;
+                    None
                 }
             }
-        }
-        if let Some(index) = fn_index {
-            self.run_from_index(index)
-        } else {
-            Err(Error::FunctionNotFound)
-        }
+        })
     }
 
-    /// Evaluate an expression at the given index.
+    /// Check the program ends with a `Checksum` (as added by
+    /// `ProgramBuilder::finalize_with_crc`) that matches the rest of the
+    /// program, including any header.
     ///
-    /// Currently only integer literals are supported. TODO:
-    ///
-    /// * Addition
-    ///   * Integer + Integer
-    ///   * Float + Float
-    ///   * String + String
-    /// * Subtraction
-    ///   * Integer - Integer
-    ///   * Float - Float
-    /// * Multiplication
-    ///   * Integer * Integer
-    ///   * Float * Float
-    ///   * String * Integer
-    /// * Division
-    ///   * Integer / Integer
-    ///   * Float / Float
-    /// * Function call
-    /// * Bitwise OR (integer)
-    /// * Bitwise AND (integer)
-    /// * Bitwise XOR (integer)
-    /// * Unary negation
-    ///   * Integer
-    ///   * Float
-    fn evaluate_expression(&self, index: usize) -> Result<(usize, Value), Error> {
-        match self.iter_statements(index).next() {
-            Some((sub_index, Element::Integer(i))) => Ok((sub_index, Value::Integer(i))),
-            _ => Err(Error::SequenceError(index)),
+    /// Gives `Error::ChecksumMismatch` if it doesn't, which means the
+    /// program has been corrupted.
+    pub fn verify_crc(&self) -> Result<(), Error> {
+        let Some(crc_index) = self.data.len().checked_sub(5) else {
+            return Err(Error::ChecksumMismatch);
+        };
+        match self.iter_statements(crc_index).next() {
+            Some((_, Element::Checksum(crc)))
+                if crc == crc32(&[self.header, &self.data[0..crc_index]]) =>
+            {
+                Ok(())
+            }
+            _ => Err(Error::ChecksumMismatch),
         }
     }
 
-    /// Runs a sequence of statements (each described by a leading `Element`).
+    /// Count the elements in the program.
     ///
-    /// TODO:
+    /// This walks the whole program every time, so it is O(n) in the size of
+    /// the program. It stops at the first element that can't be decoded.
+    pub fn statement_count(&self) -> usize {
+        self.iter_statements(0).count()
+    }
+
+    /// Go through every Integer literal in the program, in order.
     ///
-    /// * If statement
-    /// * If/Else statement
-    /// * If/Elseif/Else statement
-    /// * Loop statement (with break)
-    /// * For loop
-    pub fn run_from_index(&self, index: usize) -> Result<Value, Error> {
-        for (sub_index, statement) in self.iter_statements(index) {
-            match statement {
-                Element::Nop => {
-                    // Skip this one
+    /// Only `Integer` elements count, not things like the lengths of
+    /// strings and blocks. Like `statement_count`, this stops at the first
+    /// element that can't be decoded.
+    pub fn integer_literals(&self) -> impl Iterator<Item = i32> + 'a {
+        self.iter_statements(0)
+            .filter_map(|(_, element)| match element {
+                Element::Integer(i) => Some(i),
+                _ => None,
+            })
+    }
+
+    /// How many bytes the program takes up, not counting any header.
+    pub fn byte_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Count the functions, elements and nesting depth of the program, in
+    /// one pass.
+    ///
+    /// Like `statement_count`, this stops at the first element that can't
+    /// be decoded.
+    pub fn stats(&self) -> ProgramStats {
+        let mut stats = ProgramStats::default();
+        let mut depth = 0usize;
+        for (_, element) in self.iter_statements(0) {
+            stats.statements += 1;
+            match element {
+                Element::Function { .. } => {
+                    stats.functions += 1;
+                    depth += 1;
                 }
-                Element::Return => {
-                    // Pop and evaluate an expression
-                    let (_new_index, value) = self.evaluate_expression(sub_index + 1)?;
-                    return Ok(value);
+                Element::If | Element::While | Element::For(_) | Element::Match => {
+                    depth += 1;
                 }
                 Element::End => {
-                    // End of our function
-                    break;
+                    depth = depth.saturating_sub(1);
                 }
-                _ => {
-                    // Uh oh - shouldn't find this element inside a function as a statement
-                    return Err(Error::SequenceError(sub_index));
+                _ => {}
+            }
+            stats.max_depth = stats.max_depth.max(depth);
+        }
+        stats
+    }
+
+    /// Check the program is well formed, without running any of it.
+    ///
+    /// Every element must decode, the top level must hold only functions and
+    /// constants (and perhaps a final `Checksum`), every block must be closed
+    /// by an `End`, every statement must have the expressions it needs, and
+    /// every `Block` must have the right length.
+    /// If not, we give `Error::SequenceError` with the index of the first
+    /// problem found.
+    ///
+    /// This walks the whole program, so it is O(n) in the size of the
+    /// program.
+    pub fn validate(&self) -> Result<(), Error> {
+        // Check everything decodes first, so we can point at the exact byte
+        let mut iter = self.iter_statements(0);
+        for _ in iter.by_ref() {}
+        if iter.index != self.data.len() {
+            return Err(self.decode_error(iter.index));
+        }
+        let mut index = 0;
+        while index < self.data.len() {
+            let mut iter = self.iter_statements(index);
+            match iter.next() {
+                Some((_, Element::Function { .. })) => {
+                    index = self.validate_block(iter.index, false)?;
+                }
+                Some((_, Element::Const(_))) => {
+                    index = self.skip_expression(iter.index)?;
                 }
+                // A checksum can only come last
+                Some((_, Element::Checksum(_))) if iter.index == self.data.len() => {
+                    index = iter.index;
+                }
+                // Only functions and constants are allowed at the top level
+                _ => return Err(Error::SequenceError(index)),
             }
         }
-        Ok(Value::Nil)
+        Ok(())
     }
 
-    /// Assuming there's a string at this index, we pull an (8-bit) length, then interpret that many bytes as UTF-8.
+    /// Collect every element in the program.
     ///
-    /// Returns None if we run out of bytes or it doesn't look like valid UTF-8.
-    fn read_string(&self, index: usize) -> Option<&str> {
-        self.data.get(index).and_then(|len| {
-            core::str::from_utf8(&self.data[index + 1..index + 1 + usize::from(*len)]).ok()
-        })
+    /// The elements borrow names and strings from the program data, so the
+    /// `Vec` can't outlive it. Like `statement_count`, this stops at the
+    /// first element that can't be decoded.
+    #[cfg(feature = "alloc")]
+    pub fn to_elements(&self) -> Vec<Element<'a>> {
+        self.iter_statements(0)
+            .map(|(_, element)| element)
+            .collect()
     }
-}
 
-impl<'a> ProgramBuilder<'a> {
-    /// Construct a new program inside a given slice
-    pub fn new(space: &'a mut [u8]) -> ProgramBuilder<'a> {
-        ProgramBuilder {
-            data: space,
-            used: 0,
-        }
+    /// Do the two programs hold the same elements?
+    ///
+    /// Unlike `==`, this doesn't mind how each element is encoded, so an
+    /// Integer stored with `encode_integer` matches the same Integer stored
+    /// with `encode_integer_leb128`. `Nop`s (including any zeroed padding)
+    /// are ignored, and so is anything after the first element that can't
+    /// be decoded.
+    pub fn eq_elements(&self, other: &Program) -> bool {
+        let not_nop = |(_, element): &(usize, Element)| *element != Element::Nop;
+        let mine = self.iter_statements(0).filter(not_nop);
+        let theirs = other.iter_statements(0).filter(not_nop);
+        mine.map(|(_, element)| element)
+            .eq(theirs.map(|(_, element)| element))
     }
 
-    /// Insert an element
-    pub fn insert(&mut self, element: &Element) -> Result<(), Error> {
-        match element {
-            Element::Nop => {
-                self.insert_byte(Program::NOP_ID)?;
-            }
-            Element::End => {
-                self.insert_byte(Program::END_ID)?;
-            }
-            Element::Function(name) => {
-                if name.len() > 255 {
-                    return Err(Error::NameTooLong);
-                }
-                // Avoid partial writes
-                if self.free() < (2 + name.len()) {
-                    return Err(Error::InsufficientSpace);
-                }
-                self.insert_byte(Program::FUNCTION_ID)?;
-                self.insert_byte(name.len() as u8)?;
-                for b in name.bytes() {
-                    self.insert_byte(b)?;
-                }
-            }
-            Element::Return => {
-                self.insert_byte(Program::RETURN_ID)?;
-            }
-            Element::Integer(i) => {
-                let mut buffer = [0u8; 5];
-                let encoded_integer = Self::encode_integer(&mut buffer, *i);
-                if self.free() < encoded_integer.len() {
-                    return Err(Error::InsufficientSpace);
-                }
-                for b in encoded_integer {
-                    self.insert_byte(*b)?;
-                }
+    /// Render the whole program as text, one element per line.
+    ///
+    /// This is the same as the `Display` output.
+    #[cfg(feature = "alloc")]
+    pub fn disassemble(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn run(&self, function_name: &str) -> Result<Value<'a>, Error> {
+        self.run_with_options(function_name, RunOptions::new())
+    }
+
+    /// Run the program's entry point - the function called `ENTRY_POINT`.
+    ///
+    /// If there isn't one, you get `Error::FunctionNotFound`.
+    pub fn run_main(&self) -> Result<Value<'a>, Error> {
+        self.run(ENTRY_POINT)
+    }
+
+    /// Run a function, with the given options. The function must not take
+    /// any parameters.
+    pub fn run_with_options(
+        &self,
+        function_name: &str,
+        options: RunOptions<'_, 'a>,
+    ) -> Result<Value<'a>, Error> {
+        let mut context = options.context;
+        self.run_no_args(function_name, &mut context, &mut VarScope::new())
+    }
+
+    /// Run a function which takes no parameters, with the given settings and
+    /// variables.
+    fn run_no_args(
+        &self,
+        function_name: &str,
+        context: &mut Context<'_, 'a>,
+        scope: &mut VarScope<'a>,
+    ) -> Result<Value<'a>, Error> {
+        let (index, params) = self.function_body(function_name)?;
+        // We have no arguments to give it
+        if !params.is_empty() {
+            return Err(Error::ArityMismatch);
+        }
+        match self.run_with_context(index, context, scope)? {
+            // A String made on the heap goes in the scratch buffer too, so
+            // it can be handed back the same way
+            #[cfg(feature = "alloc")]
+            Value::String(s) if context.scratch.is_some() => {
+                context.store(&[&s]).map(Value::StringLiteral)
             }
+            value => Ok(value),
         }
-        Ok(())
     }
 
-    /// Encode an integer`
-    pub fn encode_integer(buffer: &mut [u8; 5], integer: i32) -> &[u8] {
-        let bytes = integer.to_be_bytes();
-        if integer >= 0 {
-            // Positive
-            if integer < 1 << 7 {
-                // i8
-                buffer[0] = Program::INTEGER1_ID;
-                buffer[1] = bytes[3];
-                &buffer[0..2]
-            } else if integer < 1 << 15 {
-                // i16
-                buffer[0] = Program::INTEGER2_ID;
-                buffer[1] = bytes[2];
-                buffer[2] = bytes[3];
-                &buffer[0..3]
-            } else if integer < 1 << 23 {
-                // i24
-                buffer[0] = Program::INTEGER3_ID;
-                buffer[1] = bytes[1];
-                buffer[2] = bytes[2];
-                buffer[3] = bytes[3];
-                &buffer[0..4]
-            } else {
-                // i32
-                buffer[0] = Program::INTEGER4_ID;
-                buffer[1] = bytes[0];
-                buffer[2] = bytes[1];
-                buffer[3] = bytes[2];
-                buffer[4] = bytes[3];
-                &buffer[0..5]
-            }
-        } else {
-            // Negative
-            if integer >= -(1 << 7) {
-                // i8
-                buffer[0] = Program::INTEGER1_ID;
-                buffer[1] = bytes[3];
-                &buffer[0..2]
-            } else if integer >= -(1 << 15) {
-                // i16
-                buffer[0] = Program::INTEGER2_ID;
-                buffer[1] = bytes[2];
-                buffer[2] = bytes[3];
-                &buffer[0..3]
-            } else if integer >= -(1 << 23) {
-                // i24
-                buffer[0] = Program::INTEGER3_ID;
-                buffer[1] = bytes[1];
-                buffer[2] = bytes[2];
-                buffer[3] = bytes[3];
-                &buffer[0..4]
-            } else {
-                // i32
-                buffer[0] = Program::INTEGER4_ID;
-                buffer[1] = bytes[0];
-                buffer[2] = bytes[1];
-                buffer[3] = bytes[2];
-                buffer[4] = bytes[3];
-                &buffer[0..5]
-            }
+    /// Run a function, giving its parameters the values in `args`, in order.
+    ///
+    /// If the function takes a different number of parameters, you get
+    /// `Error::ArityMismatch`.
+    pub fn run_with_args(
+        &self,
+        function_name: &str,
+        args: &[Value<'a>],
+    ) -> Result<Value<'a>, Error> {
+        let (index, params) = self.function_body(function_name)?;
+        if args.len() != params.len() {
+            return Err(Error::ArityMismatch);
+        }
+        let mut scope = VarScope::new();
+        for (param, value) in params.iter().zip(args) {
+            scope.set(param, value.clone())?;
         }
+        self.run_with_context(index, &mut Context::default(), &mut scope)
     }
 
-    /// Add a byte to the program.
+    /// Run a function using the variables in `scope`, rather than starting
+    /// with none.
     ///
-    /// Returns an error if it doesn't fit.
-    fn insert_byte(&mut self, value: u8) -> Result<(), Error> {
-        let Some(slot) = self.data.get_mut(self.used) else {
-            return Err(Error::InsufficientSpace);
-        };
-        *slot = value;
-        self.used += 1;
-        Ok(())
+    /// Any variables the function sets are left in `scope`, so a later call
+    /// can read them. The function must not take any parameters.
+    pub fn run_with_scope(
+        &self,
+        function_name: &str,
+        scope: &mut VarScope<'a>,
+    ) -> Result<Value<'a>, Error> {
+        self.run_no_args(function_name, &mut Context::default(), scope)
     }
 
-    /// How many bytes are used?
-    pub fn used(&self) -> usize {
-        self.used
+    /// Run a function, but stop with `Error::IterationLimitExceeded` if any
+    /// loop goes round more than `max_iterations` times.
+    ///
+    /// See `RunOptions::max_iterations`.
+    pub fn run_with_limit(
+        &self,
+        function_name: &str,
+        max_iterations: u32,
+    ) -> Result<Value<'a>, Error> {
+        self.run_with_options(
+            function_name,
+            RunOptions::new().max_iterations(max_iterations),
+        )
     }
 
-    /// How many bytes are free?
-    pub fn free(&self) -> usize {
-        self.data.len() - self.used
+    /// Run a function, but stop with `Error::InstructionBudgetExceeded` once
+    /// `max_instructions` statements have been run.
+    ///
+    /// See `RunOptions::budget`.
+    pub fn run_budgeted(
+        &self,
+        function_name: &str,
+        max_instructions: u64,
+    ) -> Result<Value<'a>, Error> {
+        self.run_with_options(function_name, RunOptions::new().budget(max_instructions))
     }
-}
 
-impl<'a> core::convert::TryFrom<&'a str> for Element<'a> {
-    type Error = Error;
+    /// Run a function, but stop with `Error::RecursionLimitExceeded` if
+    /// function calls nest more than `max_depth` deep.
+    ///
+    /// The other ways of running a function use `DEFAULT_MAX_DEPTH`.
+    pub fn run_with_depth_limit(
+        &self,
+        function_name: &str,
+        max_depth: usize,
+    ) -> Result<Value<'a>, Error> {
+        self.run_with_options(function_name, RunOptions::new().max_depth(max_depth))
+    }
 
-    fn try_from(s: &'a str) -> Result<Element<'a>, Error> {
-        if s.eq_ignore_ascii_case("return") {
-            return Ok(Element::Return);
-        } else if s.eq_ignore_ascii_case("end") {
-            return Ok(Element::End);
-        } else if s.eq_ignore_ascii_case("nop") {
-            return Ok(Element::Nop);
-        } else if let Ok(i) = s.parse::<i32>() {
-            return Ok(Element::Integer(i));
-        } else if let Some(name) = s.strip_prefix("fn ") {
-            if name.is_empty() {
-                return Err(Error::InvalidName);
-            }
-            let mut first = true;
-            for ch in name.chars() {
-                if first {
-                    first = false;
-                    if !(ch.is_alphabetic() || ch == '_') {
-                        return Err(Error::InvalidName);
-                    }
-                } else {
-                    if !(ch.is_alphanumeric() || ch == '_') {
-                        return Err(Error::InvalidName);
-                    }
-                }
-            }
-            return Ok(Element::Function(name));
+    /// Run a function, calling `hook` with the index of each statement, and
+    /// the statement itself, just before it runs.
+    ///
+    /// See `RunOptions::hook`.
+    pub fn run_with_hook(
+        &self,
+        function_name: &str,
+        hook: &mut dyn FnMut(usize, &Element),
+    ) -> Result<Value<'a>, Error> {
+        self.run_with_options(function_name, RunOptions::new().hook(hook))
+    }
+
+    /// Run a function, putting any strings it joins together in `scratch`
+    /// rather than on the heap.
+    ///
+    /// See `RunOptions::scratch`.
+    pub fn run_into(&self, function_name: &str, scratch: &'a mut [u8]) -> Result<Value<'a>, Error> {
+        self.run_with_options(function_name, RunOptions::new().scratch(scratch))
+    }
+
+    /// Run a function, counting how many times each opcode runs.
+    ///
+    /// See `RunOptions::profile`.
+    pub fn run_profiled(&self, function_name: &str) -> Result<(Value<'a>, OpcodeCounts), Error> {
+        let mut counts = OpcodeCounts::default();
+        let value = self.run_with_options(function_name, RunOptions::new().profile(&mut counts))?;
+        Ok((value, counts))
+    }
+
+    /// Get ready to run a function one statement at a time.
+    ///
+    /// Printed text is thrown away, unless you give the interpreter
+    /// somewhere to send it with `Interpreter::with_output`.
+    pub fn interpreter<'c>(&self, function_name: &str) -> Result<Interpreter<'c, 'a>, Error> {
+        self.interpreter_with_options(function_name, RunOptions::new())
+    }
+
+    /// Get ready to run a function one statement at a time, with the given
+    /// options.
+    ///
+    /// The options apply to every step. A budget counts each step as a
+    /// statement, but `max_iterations` only limits loops in the functions
+    /// which are called - the interpreter's own loops only go round as
+    /// often as you step them.
+    pub fn interpreter_with_options<'c>(
+        &self,
+        function_name: &str,
+        options: RunOptions<'c, 'a>,
+    ) -> Result<Interpreter<'c, 'a>, Error> {
+        let (index, params) = self.function_body(function_name)?;
+        if !params.is_empty() {
+            return Err(Error::ArityMismatch);
         }
-        Err(Error::SyntaxError)
+        Ok(Interpreter {
+            program: *self,
+            start: index,
+            index,
+            scope: VarScope::new(),
+            blocks: [OpenBlock::If; MAX_OPEN_BLOCKS],
+            num_blocks: 0,
+            finished: false,
+            context: options.context,
+        })
     }
-}
 
-impl<'a> core::fmt::Display for Element<'a> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Element::Nop => write!(f, "nop"),
-            Element::End => write!(f, "end"),
-            Element::Function(name) => write!(f, "fn {name}"),
-            Element::Return => write!(f, "return"),
-            Element::Integer(i) => write!(f, "{i}"),
+    /// Run a function, sending anything it prints to `out`.
+    ///
+    /// With `run`, printed text is thrown away.
+    pub fn run_with_output<W>(&self, function_name: &str, out: &mut W) -> Result<Value<'a>, Error>
+    where
+        W: core::fmt::Write,
+    {
+        self.run_with_options(function_name, RunOptions::new().output(out))
+    }
+
+    /// Run a function, passing calls to any function not in the program on
+    /// to `host`.
+    pub fn run_with_host(
+        &self,
+        function_name: &str,
+        host: &mut dyn HostFunctions,
+    ) -> Result<Value<'a>, Error> {
+        self.run_with_options(function_name, RunOptions::new().host(host))
+    }
+
+    /// Find the named function, so that it can be run later with
+    /// `run_handle`.
+    pub fn find_function(&self, function_name: &str) -> Option<FunctionHandle> {
+        let (body_index, params) = self.function_body(function_name).ok()?;
+        Some(FunctionHandle {
+            body_index,
+            num_params: params.len(),
+            data_addr: self.data.as_ptr() as usize,
+            data_len: self.data.len(),
+        })
+    }
+
+    /// Run a function found earlier with `find_function`.
+    ///
+    /// The handle must have come from this program, otherwise you get
+    /// `Error::InvalidHandle`.
+    pub fn run_handle(&self, handle: FunctionHandle) -> Result<Value<'a>, Error> {
+        if handle.data_addr != self.data.as_ptr() as usize || handle.data_len != self.data.len() {
+            return Err(Error::InvalidHandle);
         }
+        // We have no arguments to give it
+        if handle.num_params != 0 {
+            return Err(Error::ArityMismatch);
+        }
+        self.run_from_index(handle.body_index)
     }
-}
 
-impl<'a> Iterator for ElementIter<'a> {
-    type Item = (usize, Element<'a>);
+    /// Find where the first statement of the named function is, so that it
+    /// can be run later with `run_at`.
+    pub fn function_index(&self, function_name: &str) -> Option<usize> {
+        self.find_body(function_name)
+            .map(|(body_index, _)| body_index)
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        // Find what sort of statement is next
-        match self.program.data.get(self.index).cloned() {
-            Some(Program::FUNCTION_ID) => {
-                if let Some(name) = self.program.read_string(self.index + 1) {
-                    let old_index = self.index;
-                    self.index += 2 + name.len();
-                    Some((old_index, Element::Function(name)))
-                } else {
-                    None
-                }
-            }
-            Some(Program::NOP_ID) => {
-                let old_index = self.index;
-                self.index += 1;
-                Some((old_index, Element::Nop))
-            }
-            Some(Program::END_ID) => {
-                let old_index = self.index;
-                self.index += 1;
-                Some((old_index, Element::End))
-            }
-            Some(Program::RETURN_ID) => {
-                let old_index = self.index;
-                self.index += 1;
-                Some((old_index, Element::Return))
-            }
-            Some(Program::INTEGER1_ID) => {
-                if let Some(i) = self.program.data.get(self.index + 1) {
-                    let old_index = self.index;
-                    self.index += 2;
-                    Some((old_index, Element::Integer(*i as i8 as i32)))
-                } else {
-                    None
+    /// Run a function, given where its first statement is (as found by
+    /// `function_index`).
+    ///
+    /// Gives `Error::SequenceError` if `body_index` isn't where the body of a
+    /// function starts, or `Error::ArityMismatch` if it's the start of a
+    /// function which takes parameters. Checking this only looks at the
+    /// top level of the program, up to the function, so it costs no more
+    /// than finding the function by name with `run`.
+    pub fn run_at(&self, body_index: usize) -> Result<Value<'a>, Error> {
+        let params = self
+            .iter_toplevel()
+            .take_while(|(index, _)| *index < body_index)
+            .find_map(|(index, element)| match element {
+                Element::Function { params, .. } => {
+                    let mut iter = self.iter_statements(index);
+                    iter.next();
+                    (iter.index == body_index).then_some(params)
                 }
-            }
-            Some(Program::INTEGER2_ID) => {
-                if let Some(i) = self.program.data.get(self.index + 1..self.index + 3) {
-                    let old_index = self.index;
-                    self.index += 3;
-                    // Stored as big endian
-                    let value: i32 = ((u16::from(i[0]) << 8) | u16::from(i[1])) as i16 as i32;
-                    Some((old_index, Element::Integer(value)))
-                } else {
-                    None
+                _ => None,
+            })
+            .ok_or(Error::SequenceError(body_index))?;
+        if !params.is_empty() {
+            return Err(Error::ArityMismatch);
+        }
+        self.run_from_index(body_index)
+    }
+
+    /// Find the index of the first statement in the named function, along
+    /// with the parameters it takes.
+    fn function_body(&self, function_name: &str) -> Result<(usize, Params<'a>), Error> {
+        self.find_body(function_name)
+            .ok_or_else(|| Error::function_not_found(function_name))
+    }
+
+    /// Like `function_body`, but gives `None` if there is no such function.
+    fn find_body(&self, function_name: &str) -> Option<(usize, Params<'a>)> {
+        let mut iter = self.iter_statements(0);
+        // Looking for a function
+        while let Some((_, statement)) = iter.next() {
+            match statement {
+                Element::Function { name, params } if name == function_name => {
+                    return Some((iter.index, params));
                 }
-            }
-            Some(Program::INTEGER3_ID) => {
-                if let Some(i) = self.program.data.get(self.index + 1..self.index + 4) {
-                    let old_index = self.index;
-                    self.index += 4;
-                    // Stored as big endian
-                    let value: u32 =
-                        (u32::from(i[0]) << 16) | (u32::from(i[1]) << 8) | u32::from(i[2]);
-                    // Do sign extension
-                    let value: i32 = if (value & 0x0080_0000) != 0 {
-                        // it's negative
-                        (value | 0xFF000000) as i32
-                    } else {
-                        value as i32
-                    };
-                    Some((old_index, Element::Integer(value)))
-                } else {
-                    None
+                _ => {
+                    // Skip this statement
                 }
             }
-            Some(Program::INTEGER4_ID) => {
-                if let Some(i) = self.program.data.get(self.index + 1..self.index + 5) {
-                    let old_index = self.index;
-                    self.index += 5;
-                    // Stored as big endian
-                    let value: i32 = ((u32::from(i[0]) << 24)
-                        | (u32::from(i[1]) << 16)
-                        | (u32::from(i[2]) << 8)
-                        | u32::from(i[3])) as i32;
-                    Some((old_index, Element::Integer(value)))
-                } else {
-                    None
+        }
+        None
+    }
+
+    /// Find where the value of the named constant is.
+    fn find_constant(&self, name: &str) -> Option<usize> {
+        self.iter_toplevel()
+            .find_map(|(index, element)| match element {
+                Element::Const(constant) if constant == name => {
+                    let mut iter = self.iter_statements(index);
+                    iter.next();
+                    Some(iter.index)
                 }
-            }
+                _ => None,
+            })
+    }
 
-            _ => None,
+    /// Get the value of the named variable or, if there isn't one, the named
+    /// constant.
+    ///
+    /// A constant's value is worked out each time it is read. Constants can
+    /// use other constants, so this counts towards the call depth, to catch
+    /// any which use themselves.
+    fn read_variable(
+        &self,
+        name: &str,
+        context: &mut Context<'_, 'a>,
+        scope: &VarScope<'a>,
+    ) -> Result<Value<'a>, Error> {
+        if let Some(value) = scope.get(name) {
+            return Ok(value.clone());
         }
+        let index = self.find_constant(name).ok_or(Error::UndefinedVariable)?;
+        if context.depth == context.max_depth {
+            return Err(Error::RecursionLimitExceeded);
+        }
+        context.depth += 1;
+        let value = self.evaluate_expression(index, context, &VarScope::new());
+        context.depth -= 1;
+        Ok(value?.1)
     }
-}
 
-// -----------------------------------------------------------------------------
-// Tests
-// -----------------------------------------------------------------------------
+    /// Check we're allowed to assign to the named variable, which we aren't
+    /// if it's a constant.
+    ///
+    /// A variable which already exists has passed this check before, so we
+    /// only have to look for a constant when the variable is new.
+    fn check_assign(&self, name: &str, scope: &VarScope<'a>) -> Result<(), Error> {
+        if scope.get(name).is_none() && self.find_constant(name).is_some() {
+            Err(Error::AssignToConstant)
+        } else {
+            Ok(())
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Check that a function can be called with this many arguments, before
+    /// we go to the trouble of evaluating them.
+    fn check_call(&self, name: &str, num_args: u8, context: &Context) -> Result<(), Error> {
+        match self.find_body(name) {
+            Some((_, params)) if usize::from(num_args) != params.len() => Err(Error::ArityMismatch),
+            None if context.host.is_none() => Err(Error::function_not_found(name)),
+            _ if usize::from(num_args) > MAX_VARIABLES => Err(Error::TooManyVariables),
+            _ => Ok(()),
+        }
+    }
+
+    /// Call a function with some evaluated arguments. Functions in the
+    /// program are tried first, then those given by the host.
+    fn call_function(
+        &self,
+        name: &str,
+        args: &mut [Value<'a>],
+        context: &mut Context<'_, 'a>,
+    ) -> Result<Value<'a>, Error> {
+        let Some((body_index, params)) = self.find_body(name) else {
+            return match context.host.as_mut() {
+                Some(host) => host.call(name, args),
+                None => Err(Error::function_not_found(name)),
+            };
+        };
+        // Each argument becomes a variable in the called function
+        let mut callee_scope = VarScope::new();
+        for (param, value) in params.iter().zip(args.iter_mut()) {
+            callee_scope.set(param, core::mem::replace(value, VarScope::EMPTY))?;
+        }
+        if context.depth == context.max_depth {
+            return Err(Error::RecursionLimitExceeded);
+        }
+        context.depth += 1;
+        let value = self.run_with_context(body_index, context, &mut callee_scope);
+        context.depth -= 1;
+        value
+    }
+
+    /// Call the named function with each item in a Vector. Produces a Vector
+    /// of the results or, if `filter` is set, of the items for which the
+    /// function returned something truthy.
+    #[cfg(feature = "alloc")]
+    fn map_items(
+        &self,
+        name: &str,
+        vector: Value<'a>,
+        filter: bool,
+        context: &mut Context<'_, 'a>,
+    ) -> Result<Value<'a>, Error> {
+        let Value::Vector(items) = vector else {
+            return Err(vector.mismatch(&vector));
+        };
+        let mut results = Vec::with_capacity(items.len());
+        for item in items {
+            if filter {
+                let result = self.call_function(name, &mut [item.clone()], context)?;
+                if result.truthiness() {
+                    results.push(item);
+                }
+            } else {
+                results.push(self.call_function(name, &mut [item], context)?);
+            }
+        }
+        Ok(Value::Vector(results))
+    }
+
+    /// Evaluate the expression which belongs to a statement, then throw away
+    /// anything that `Stash` statements kept for it.
+    fn evaluate_statement(
+        &self,
+        index: usize,
+        context: &mut Context<'_, 'a>,
+        scope: &mut VarScope<'a>,
+    ) -> Result<(usize, Value<'a>), Error> {
+        let result = self.evaluate_expression(index, context, scope);
+        scope.clear_stash();
+        result
+    }
+
+    /// Evaluate an expression at the given index.
+    ///
+    /// Returns the index just past the end of the expression, along with its
+    /// value.
+    ///
+    /// Currently supported:
+    ///
+    /// * Integer literals
+    /// * Float literals
+    /// * String literals
+    /// * Variables
+    /// * Function calls
+    /// * Addition
+    ///   * Integer + Integer
+    ///   * Float + Float
+    ///   * String + String
+    /// * Subtraction
+    ///   * Integer - Integer
+    ///   * Float - Float
+    /// * Multiplication
+    ///   * Integer * Integer
+    ///   * Float * Float
+    ///   * String * Integer
+    /// * Division
+    ///   * Integer / Integer
+    ///   * Float / Float
+    /// * Remainder
+    ///   * Integer % Integer
+    /// * Exponentiation
+    ///   * Integer ** Integer
+    ///   * Float ** Float (with the `std` feature)
+    /// * Conversion
+    ///   * Integer to Float
+    ///   * Float to Integer
+    ///   * anything to String
+    ///   * String to Integer (or Long)
+    ///   * String to Float
+    ///   * String to upper or lower case (ASCII letters only)
+    /// * Unary negation
+    ///   * Integer
+    ///   * Float
+    /// * Absolute value
+    ///   * Integer
+    ///   * Float
+    /// * Square root, sine and cosine
+    ///   * Float (with the `std` or `math` feature)
+    /// * Minimum and maximum
+    ///   * Integer with Integer
+    ///   * Float with Float
+    /// * Bitwise AND, OR and XOR
+    ///   * Integer & Integer
+    ///   * Integer | Integer
+    ///   * Integer ^ Integer
+    /// * Bit shifts
+    ///   * Integer << Integer
+    ///   * Integer >> Integer
+    /// * Comparisons (`==`, `!=`, `<`, `<=`, `>`, `>=`)
+    ///   * Integer with Integer
+    ///   * Float with Float
+    ///   * String with String
+    ///   * Boolean with Boolean
+    /// * Equality ignoring ASCII case
+    ///   * String with String
+    /// * Logical AND, OR and NOT
+    ///   * Boolean && Boolean
+    ///   * Boolean || Boolean
+    ///   * !Boolean
+    /// * Vector literals
+    /// * Indexing
+    ///   * Vector[Integer]
+    /// * Length
+    ///   * String (in bytes)
+    ///   * Vector
+    /// * Substrings
+    ///   * String[Integer..Integer]
+    /// * Sorting and reversing
+    ///   * Vector
+    /// * Pushing
+    ///   * Vector with anything
+    /// * Mapping and filtering
+    ///   * Vector, with a function taking one parameter
+    ///
+    /// Integer arithmetic which overflows produces
+    /// `Error::ArithmeticOverflow`, rather than wrapping. Integer division
+    /// truncates towards zero, and dividing by zero produces
+    /// `Error::DivideByZero`. Float arithmetic follows IEEE-754, so dividing
+    /// a Float by zero produces an infinity (or NaN), not an error. Integers
+    /// and Floats are never mixed - any other combination of values produces
+    /// `Error::TypeMismatch`.
+    ///
+    /// Shifting left discards any bits shifted off the top, and shifting
+    /// right preserves the sign. Shifting by a negative amount, or by 32 or
+    /// more, produces `Error::InvalidShift`.
+    ///
+    /// Comparisons produce a Boolean. Strings compare lexicographically, by
+    /// their bytes.
+    ///
+    /// Logical AND and OR only evaluate their second operand if they need to.
+    ///
+    /// Vectors need the `alloc` feature - without it, making one produces
+    /// `Error::NoAllocator`.
+    ///
+    /// Nested expressions are evaluated with a stack of pending operators,
+    /// rather than by recursion, so untrusted input can't overflow the native
+    /// stack. With the `alloc` feature the stack grows on the heap. Without
+    /// it, the stack has room for `MAX_EXPRESSION_DEPTH` entries, and an
+    /// expression nested more deeply produces `Error::ExpressionTooDeep`.
+    ///
+    fn evaluate_expression(
+        &self,
+        index: usize,
+        context: &mut Context<'_, 'a>,
+        scope: &VarScope<'a>,
+    ) -> Result<(usize, Value<'a>), Error> {
+        let mut iter = self.iter_statements(index);
+        // Operators which are still waiting for some of their operands
+        let mut operators: EvalStack<PendingOperator<'a>> = EvalStack::new();
+        // Operands which have been evaluated, but not yet used
+        let mut operands: EvalStack<Value<'a>> = EvalStack::new();
+        loop {
+            let element_index = iter.index;
+            let next = iter.next();
+            if next.is_some() {
+                context.count(self.data[element_index]);
+            }
+            let mut value = match next {
+                Some((_, Element::Variable(name))) => self.read_variable(name, context, scope)?,
+                Some((_, Element::Stashed(slot))) => scope.stashed(slot)?,
+                Some((_, Element::Integer(i))) => Value::Integer(i),
+                Some((_, Element::Long(i))) => Value::Long(i),
+                Some((_, Element::Float(f))) => Value::Float(f),
+                Some((_, Element::StringLiteral(s))) => Value::StringLiteral(s),
+                Some((_, Element::Boolean(b))) => Value::Boolean(b),
+                Some((_, Element::Nil)) => Value::Nil,
+                #[cfg(not(feature = "alloc"))]
+                Some((_, Element::Vector(_))) => return Err(Error::NoAllocator),
+                Some((_, element)) => {
+                    let Some(remaining) = element.operand_count() else {
+                        return Err(Error::SequenceError(element_index));
+                    };
+                    match element {
+                        Element::Call(name, num_args) => {
+                            self.check_call(name, num_args, context)?;
+                        }
+                        Element::Map(name) | Element::Filter(name) => {
+                            self.check_call(name, 1, context)?;
+                        }
+                        _ => {}
+                    }
+                    let operator = PendingOperator {
+                        element,
+                        index: element_index,
+                        remaining,
+                    };
+                    if remaining > 0 {
+                        operators.push(operator)?;
+                        continue;
+                    }
+                    // A call with no arguments, or an empty Vector
+                    self.apply_operator(operator, &mut operands, context)?
+                }
+                None => return Err(Error::SequenceError(element_index)),
+            };
+            // Hand the value to the operators waiting for it, applying each
+            // one which then has all of its operands.
+            loop {
+                let Some(operator) = operators.last_mut() else {
+                    return Ok((iter.index, value));
+                };
+                operator.remaining -= 1;
+                match (&operator.element, operator.remaining) {
+                    (Element::And, 1) => {
+                        if value.as_boolean()? {
+                            break;
+                        }
+                        operators.pop();
+                        iter.index = self.skip_expression(iter.index)?;
+                        value = Value::Boolean(false);
+                    }
+                    (Element::Or, 1) => {
+                        if !value.as_boolean()? {
+                            break;
+                        }
+                        operators.pop();
+                        iter.index = self.skip_expression(iter.index)?;
+                        value = Value::Boolean(true);
+                    }
+                    (Element::And | Element::Or, _) => {
+                        operators.pop();
+                        value = Value::Boolean(value.as_boolean()?);
+                    }
+                    (Element::Select, 2) => {
+                        if value.is_true()? {
+                            operator.remaining = 1;
+                        } else {
+                            // The second branch stands in for the whole
+                            // Select, so we no longer need the operator
+                            operators.pop();
+                            iter.index = self.skip_expression(iter.index)?;
+                        }
+                        break;
+                    }
+                    (Element::Select, _) => {
+                        // We've evaluated the first branch, so step over the
+                        // second one
+                        operators.pop();
+                        iter.index = self.skip_expression(iter.index)?;
+                    }
+                    (_, 0) => {
+                        operands.push(value)?;
+                        let Some(operator) = operators.pop() else {
+                            return Err(Error::SequenceError(index));
+                        };
+                        value = self.apply_operator(operator, &mut operands, context)?;
+                    }
+                    _ => {
+                        operands.push(value)?;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Apply an operator to its operands, which are the values on top of the
+    /// operand stack.
+    fn apply_operator(
+        &self,
+        operator: PendingOperator<'a>,
+        operands: &mut EvalStack<Value<'a>>,
+        context: &mut Context<'_, 'a>,
+    ) -> Result<Value<'a>, Error> {
+        let index = operator.index;
+        match operator.element {
+            Element::Call(name, num_args) => {
+                let num_args = usize::from(num_args);
+                let mut args = [VarScope::EMPTY; MAX_VARIABLES];
+                for arg in args[0..num_args].iter_mut().rev() {
+                    *arg = operands.pop().ok_or(Error::SequenceError(index))?;
+                }
+                self.call_function(name, &mut args[0..num_args], context)
+            }
+            #[cfg(feature = "alloc")]
+            Element::Vector(num_items) => {
+                let mut items = Vec::with_capacity(usize::from(num_items));
+                for _ in 0..num_items {
+                    items.push(operands.pop().ok_or(Error::SequenceError(index))?);
+                }
+                items.reverse();
+                Ok(Value::Vector(items))
+            }
+            Element::Negate
+            | Element::Not
+            | Element::Len
+            | Element::ToFloat
+            | Element::ToInt
+            | Element::ToStr
+            | Element::ParseInt
+            | Element::ParseFloat
+            | Element::Sort
+            | Element::Reverse
+            | Element::ToUpper
+            | Element::ToLower
+            | Element::Abs
+            | Element::Sqrt
+            | Element::Sin
+            | Element::Cos => {
+                let a = operands.pop().ok_or(Error::SequenceError(index))?;
+                match operator.element {
+                    Element::ToStr if context.scratch.is_some() => {
+                        context.store_display(&a).map(Value::StringLiteral)
+                    }
+                    Element::ToUpper | Element::ToLower if context.scratch.is_some() => {
+                        let s = a.as_str().ok_or_else(|| a.mismatch(&a))?;
+                        let s = context.store_mut(&[s])?;
+                        if operator.element == Element::ToUpper {
+                            s.make_ascii_uppercase();
+                        } else {
+                            s.make_ascii_lowercase();
+                        }
+                        Ok(Value::StringLiteral(s))
+                    }
+                    Element::Negate => a.negate(),
+                    Element::Not => Ok(Value::Boolean(!a.as_boolean()?)),
+                    Element::Len => a.length(),
+                    Element::ToFloat => a.to_float(),
+                    Element::ToInt => a.to_int(),
+                    Element::ToStr => a.to_str(),
+                    Element::ParseInt => a.parse_int(),
+                    Element::ParseFloat => a.parse_float(),
+                    Element::Sort => a.sort(),
+                    Element::Reverse => a.reverse(),
+                    Element::ToUpper => a.to_upper(),
+                    Element::ToLower => a.to_lower(),
+                    Element::Abs => a.abs(),
+                    Element::Sqrt => a.sqrt(),
+                    Element::Sin => a.sin(),
+                    Element::Cos => a.cos(),
+                    _ => Err(Error::SequenceError(index)),
+                }
+            }
+            #[cfg(feature = "alloc")]
+            Element::Map(name) | Element::Filter(name) => {
+                let vector = operands.pop().ok_or(Error::SequenceError(index))?;
+                let filter = matches!(operator.element, Element::Filter(_));
+                self.map_items(name, vector, filter, context)
+            }
+            #[cfg(not(feature = "alloc"))]
+            Element::Map(_) | Element::Filter(_) => {
+                let a = operands.pop().ok_or(Error::SequenceError(index))?;
+                Err(a.mismatch(&a))
+            }
+            Element::Substring => {
+                let end = operands.pop().ok_or(Error::SequenceError(index))?;
+                let start = operands.pop().ok_or(Error::SequenceError(index))?;
+                let s = operands.pop().ok_or(Error::SequenceError(index))?;
+                if context.scratch.is_some() {
+                    let part = s.substring_str(start, end)?;
+                    context.store(&[part]).map(Value::StringLiteral)
+                } else {
+                    s.substring(start, end)
+                }
+            }
+            element => {
+                let b = operands.pop().ok_or(Error::SequenceError(index))?;
+                let a = operands.pop().ok_or(Error::SequenceError(index))?;
+                match element {
+                    Element::Add if context.scratch.is_some() => match (a.as_str(), b.as_str()) {
+                        (Some(x), Some(y)) => context.store(&[x, y]).map(Value::StringLiteral),
+                        _ => a.add(b),
+                    },
+                    Element::Add => a.add(b),
+                    Element::Subtract => a.subtract(b),
+                    Element::Multiply => a.multiply(b),
+                    Element::Divide => a.divide(b),
+                    Element::Modulo => a.remainder(b),
+                    Element::Pow => a.power(b),
+                    Element::BitAnd => a.bitwise(b, |a, b| a & b),
+                    Element::BitOr => a.bitwise(b, |a, b| a | b),
+                    Element::BitXor => a.bitwise(b, |a, b| a ^ b),
+                    Element::ShiftLeft => a.shift(b, i32::checked_shl),
+                    Element::ShiftRight => a.shift(b, i32::checked_shr),
+                    Element::Equal => a.equality(b, true),
+                    Element::NotEqual => a.equality(b, false),
+                    Element::Less => a.compare(b, |o| o == Some(Ordering::Less)),
+                    Element::LessEqual => {
+                        a.compare(b, |o| matches!(o, Some(Ordering::Less | Ordering::Equal)))
+                    }
+                    Element::Greater => a.compare(b, |o| o == Some(Ordering::Greater)),
+                    Element::GreaterEqual => a.compare(b, |o| {
+                        matches!(o, Some(Ordering::Greater | Ordering::Equal))
+                    }),
+                    Element::Index => a.index(b),
+                    Element::Push => a.push(b),
+                    Element::EqualsIgnoreCase => a.equals_ignore_case(&b),
+                    Element::Min => a.min_max(b, false),
+                    Element::Max => a.min_max(b, true),
+                    _ => Err(Error::SequenceError(index)),
+                }
+            }
+        }
+    }
+
+    /// Skip over the expression at the given index, without evaluating it.
+    ///
+    /// Returns the index just past the end of the expression.
+    fn skip_expression(&self, index: usize) -> Result<usize, Error> {
+        let mut iter = self.iter_statements(index);
+        // How many more expressions we need to step over
+        let mut pending = 1;
+        while pending > 0 {
+            let Some((element_index, element)) = iter.next() else {
+                // Ran out of program part way through the expression
+                return Err(Error::SequenceError(index));
+            };
+            pending -= 1;
+            pending += element
+                .operand_count()
+                .ok_or(Error::SequenceError(element_index))?;
+        }
+        Ok(iter.index)
+    }
+
+    /// Runs a sequence of statements (each described by a leading `Element`).
+    ///
+    /// Currently supported:
+    ///
+    /// * Return statement
+    /// * Assignment statement
+    /// * Add-assign and subtract-assign statements
+    /// * If statement
+    /// * If/Else statement
+    /// * While loop (with break and continue)
+    /// * For loop (with break and continue)
+    /// * Match statement (with a default arm)
+    /// * Print statement
+    /// * Label and Goto statements
+    /// * Stash statement
+    pub fn run_from_index(&self, index: usize) -> Result<Value<'a>, Error> {
+        self.run_with_context(index, &mut Context::default(), &mut VarScope::new())
+    }
+
+    /// Runs a sequence of statements, with the given settings and variables.
+    fn run_with_context(
+        &self,
+        index: usize,
+        context: &mut Context<'_, 'a>,
+        scope: &mut VarScope<'a>,
+    ) -> Result<Value<'a>, Error> {
+        let mut block_index = index;
+        // Jumping backwards is a loop, so it counts against the iteration limit
+        let mut jumps_back = 0;
+        loop {
+            match self.run_block(block_index, context, scope)? {
+                BlockEnd::End(_) => return Ok(Value::Nil),
+                BlockEnd::Return(value) => return Ok(value),
+                // An `Else` or `Case` is one byte long, and shouldn't be here
+                BlockEnd::Else(next_index) | BlockEnd::Case(next_index) => {
+                    return Err(Error::SequenceError(next_index - 1))
+                }
+                // Not in a loop
+                BlockEnd::Break(loop_index) | BlockEnd::Continue(loop_index) => {
+                    return Err(Error::SequenceError(loop_index))
+                }
+                // Carry on from the label, outside of any blocks
+                BlockEnd::Goto(goto_index, name) => {
+                    block_index = self.find_label(index, name)?;
+                    if block_index <= goto_index {
+                        context.check_iterations(jumps_back)?;
+                        jumps_back += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs statements from the given index until the block they are in
+    /// finishes.
+    fn run_block(
+        &self,
+        mut index: usize,
+        context: &mut Context<'_, 'a>,
+        scope: &mut VarScope<'a>,
+    ) -> Result<BlockEnd<'a>, Error> {
+        loop {
+            let mut iter = self.iter_statements(index);
+            let Some((_, statement)) = iter.next() else {
+                // Ran out of program before the block was closed
+                return Err(Error::SequenceError(index));
+            };
+            context.use_budget()?;
+            context.count(self.data[index]);
+            if let Some(hook) = context.hook.as_mut() {
+                hook(index, &statement);
+            }
+            match statement {
+                Element::Nop => {
+                    // Skip this one
+                    index = iter.index;
+                }
+                Element::Return => {
+                    // Pop and evaluate an expression
+                    let (_new_index, value) =
+                        self.evaluate_statement(iter.index, context, scope)?;
+                    return Ok(BlockEnd::Return(value));
+                }
+                Element::Assign(name) => {
+                    self.check_assign(name, scope)?;
+                    let (next_index, value) =
+                        self.evaluate_statement(iter.index, context, scope)?;
+                    scope.set(name, value)?;
+                    index = next_index;
+                }
+                Element::AddAssign(name) => {
+                    self.check_assign(name, scope)?;
+                    let (next_index, value) =
+                        self.evaluate_statement(iter.index, context, scope)?;
+                    scope.update(name, |current| current.add(value))?;
+                    index = next_index;
+                }
+                Element::SubAssign(name) => {
+                    self.check_assign(name, scope)?;
+                    let (next_index, value) =
+                        self.evaluate_statement(iter.index, context, scope)?;
+                    scope.update(name, |current| current.subtract(value))?;
+                    index = next_index;
+                }
+                Element::Print => {
+                    let (next_index, value) =
+                        self.evaluate_statement(iter.index, context, scope)?;
+                    context.print(&value)?;
+                    index = next_index;
+                }
+                Element::Stash(slot) => {
+                    let (next_index, value) =
+                        self.evaluate_expression(iter.index, context, scope)?;
+                    scope.stash(slot, value)?;
+                    index = next_index;
+                }
+                Element::Block(_) => {
+                    // Only useful when skipping - run the block as normal
+                    index = iter.index;
+                }
+                Element::End => {
+                    // End of our block
+                    return Ok(BlockEnd::End(iter.index));
+                }
+                Element::Else => {
+                    // End of our block, and the start of the next one
+                    return Ok(BlockEnd::Else(iter.index));
+                }
+                Element::Case => {
+                    // End of our arm, and the start of the next one
+                    return Ok(BlockEnd::Case(iter.index));
+                }
+                Element::If => {
+                    let (body_index, condition) =
+                        self.evaluate_statement(iter.index, context, scope)?;
+                    let outcome = if condition.is_true()? {
+                        match self.run_block(body_index, context, scope)? {
+                            // Don't run the else block
+                            BlockEnd::Else(else_index) => self.skip_block(else_index)?,
+                            other => other,
+                        }
+                    } else {
+                        match self.skip_block(body_index)? {
+                            // Run the else block
+                            BlockEnd::Else(else_index) => {
+                                self.run_block(else_index, context, scope)?
+                            }
+                            other => other,
+                        }
+                    };
+                    match outcome {
+                        BlockEnd::End(next_index) => index = next_index,
+                        // Only one `Else` is allowed, and no `Case`
+                        BlockEnd::Else(next_index) | BlockEnd::Case(next_index) => {
+                            return Err(Error::SequenceError(next_index - 1))
+                        }
+                        other => return Ok(other),
+                    }
+                }
+                Element::Match => {
+                    let (arms_index, scrutinee) =
+                        self.evaluate_expression(iter.index, context, scope)?;
+                    let chosen = self.choose_case(arms_index, &scrutinee, |case_index| {
+                        self.evaluate_expression(case_index, context, scope)
+                    })?;
+                    index = match chosen {
+                        CaseChoice::Arm(body_index) => {
+                            match self.run_block(body_index, context, scope)? {
+                                BlockEnd::End(next_index) => next_index,
+                                // Don't run the other arms
+                                BlockEnd::Else(next_index) | BlockEnd::Case(next_index) => {
+                                    self.skip_arms(next_index)?
+                                }
+                                other => return Ok(other),
+                            }
+                        }
+                        CaseChoice::NoArm(next_index) => next_index,
+                    };
+                }
+                Element::While => {
+                    let condition_index = iter.index;
+                    let mut iterations = 0;
+                    index = loop {
+                        let (body_index, condition) =
+                            self.evaluate_expression(condition_index, context, scope)?;
+                        if !condition.is_true()? {
+                            break self.skip_to_end(body_index)?;
+                        }
+                        context.check_iterations(iterations)?;
+                        iterations += 1;
+                        match self.run_block(body_index, context, scope)? {
+                            BlockEnd::End(_) | BlockEnd::Continue(_) => {
+                                // Go round again
+                            }
+                            BlockEnd::Break(_) => {
+                                break self.skip_to_end(body_index)?;
+                            }
+                            // An `Else` or `Case` is one byte long, and shouldn't be here
+                            BlockEnd::Else(next_index) | BlockEnd::Case(next_index) => {
+                                return Err(Error::SequenceError(next_index - 1))
+                            }
+                            other => return Ok(other),
+                        }
+                    };
+                }
+                Element::For(name) => {
+                    self.check_assign(name, scope)?;
+                    let (end_index, start) =
+                        self.evaluate_expression(iter.index, context, scope)?;
+                    let (body_index, end) = self.evaluate_expression(end_index, context, scope)?;
+                    let (start, end) = match (&start, &end) {
+                        (Value::Integer(start), Value::Integer(end)) => (*start, *end),
+                        _ => return Err(start.mismatch(&end)),
+                    };
+                    for (iterations, value) in (start..=end).enumerate() {
+                        context.check_iterations(iterations)?;
+                        scope.set(name, Value::Integer(value))?;
+                        match self.run_block(body_index, context, scope)? {
+                            BlockEnd::End(_) | BlockEnd::Continue(_) => {
+                                // Go round again
+                            }
+                            BlockEnd::Break(_) => {
+                                break;
+                            }
+                            // An `Else` or `Case` is one byte long, and shouldn't be here
+                            BlockEnd::Else(next_index) | BlockEnd::Case(next_index) => {
+                                return Err(Error::SequenceError(next_index - 1))
+                            }
+                            other => return Ok(other),
+                        }
+                    }
+                    index = self.skip_to_end(body_index)?;
+                }
+                Element::Break => {
+                    return Ok(BlockEnd::Break(index));
+                }
+                Element::Continue => {
+                    return Ok(BlockEnd::Continue(index));
+                }
+                Element::Label(_) => {
+                    // Only useful as somewhere to jump to
+                    index = iter.index;
+                }
+                Element::Goto(name) => {
+                    return Ok(BlockEnd::Goto(index, name));
+                }
+                _ => {
+                    // Uh oh - shouldn't find this element inside a function as a statement
+                    return Err(Error::SequenceError(index));
+                }
+            }
+        }
+    }
+
+    /// Skips over statements from the given index, without running them,
+    /// until the block they are in finishes.
+    ///
+    /// Any nested blocks are skipped entirely. Returns `BlockEnd::End`,
+    /// `BlockEnd::Else` or `BlockEnd::Case`, depending on what closed the
+    /// block.
+    fn skip_block(&self, index: usize) -> Result<BlockEnd<'a>, Error> {
+        let mut depth = 0;
+        let mut iter = self.iter_statements(index);
+        while let Some((_, element)) = iter.next() {
+            match element {
+                Element::If | Element::While | Element::For(_) | Element::Match => {
+                    depth += 1;
+                }
+                Element::Block(length) => {
+                    // Jump straight past the whole thing
+                    iter.index += usize::from(length);
+                }
+                Element::End if depth == 0 => {
+                    return Ok(BlockEnd::End(iter.index));
+                }
+                Element::Else if depth == 0 => {
+                    return Ok(BlockEnd::Else(iter.index));
+                }
+                Element::Case if depth == 0 => {
+                    return Ok(BlockEnd::Case(iter.index));
+                }
+                Element::End => {
+                    depth -= 1;
+                }
+                _ => {
+                    // Statements and expressions alike are skipped
+                }
+            }
+        }
+        // Ran out of program before the block was closed
+        Err(Error::SequenceError(iter.index))
+    }
+
+    /// Looks for the named `Label` in the block which starts at the given
+    /// index, ignoring any nested blocks.
+    ///
+    /// Returns the index just past the `Label`.
+    fn find_label(&self, index: usize, name: &str) -> Result<usize, Error> {
+        let mut depth = 0;
+        let mut iter = self.iter_statements(index);
+        while let Some((_, element)) = iter.next() {
+            match element {
+                Element::If | Element::While | Element::For(_) | Element::Match => {
+                    depth += 1;
+                }
+                Element::Block(length) => {
+                    // Jump straight past the whole thing
+                    iter.index += usize::from(length);
+                }
+                Element::Label(label) if depth == 0 && label == name => {
+                    return Ok(iter.index);
+                }
+                Element::End if depth == 0 => {
+                    return Err(Error::LabelNotFound);
+                }
+                Element::End => {
+                    depth -= 1;
+                }
+                _ => {
+                    // Statements and expressions alike are skipped
+                }
+            }
+        }
+        // Ran out of program before the block was closed
+        Err(Error::SequenceError(iter.index))
+    }
+
+    /// Skips over statements from the given index, without running them,
+    /// until the block they are in is closed by an `End`.
+    ///
+    /// Returns the index just past the `End`.
+    fn skip_to_end(&self, index: usize) -> Result<usize, Error> {
+        match self.skip_block(index)? {
+            BlockEnd::End(next_index) => Ok(next_index),
+            // An `Else` or `Case` is one byte long, and shouldn't be here
+            BlockEnd::Else(next_index) | BlockEnd::Case(next_index) => {
+                Err(Error::SequenceError(next_index - 1))
+            }
+            _ => unreachable!("skip_block only finds End, Else or Case"),
+        }
+    }
+
+    /// Skips over the remaining arms of a `Match`, from the given index,
+    /// until the `End` which closes it.
+    ///
+    /// Returns the index just past the `End`.
+    fn skip_arms(&self, mut index: usize) -> Result<usize, Error> {
+        loop {
+            match self.skip_block(index)? {
+                BlockEnd::End(next_index) => return Ok(next_index),
+                BlockEnd::Else(next_index) | BlockEnd::Case(next_index) => index = next_index,
+                _ => unreachable!("skip_block only finds End, Else or Case"),
+            }
+        }
+    }
+
+    /// Works out which arm of a `Match` to run, given the arms which start
+    /// at this index and the value being matched.
+    ///
+    /// Each `Case` value is evaluated with `evaluate`, in order, until one
+    /// equals `scrutinee`.
+    fn choose_case<F>(
+        &self,
+        mut index: usize,
+        scrutinee: &Value<'a>,
+        mut evaluate: F,
+    ) -> Result<CaseChoice, Error>
+    where
+        F: FnMut(usize) -> Result<(usize, Value<'a>), Error>,
+    {
+        loop {
+            let mut iter = self.iter_statements(index);
+            match iter.next() {
+                Some((_, Element::Case)) => {
+                    let (body_index, value) = evaluate(iter.index)?;
+                    if scrutinee.clone().equality(value, true)?.is_true()? {
+                        return Ok(CaseChoice::Arm(body_index));
+                    }
+                    index = match self.skip_block(body_index)? {
+                        // Go back and look at the next arm
+                        BlockEnd::Else(next_index) | BlockEnd::Case(next_index) => next_index - 1,
+                        BlockEnd::End(next_index) => return Ok(CaseChoice::NoArm(next_index)),
+                        _ => return Err(Error::SequenceError(body_index)),
+                    };
+                }
+                Some((_, Element::Else)) => return Ok(CaseChoice::Arm(iter.index)),
+                Some((_, Element::End)) => return Ok(CaseChoice::NoArm(iter.index)),
+                // Statements must be inside an arm
+                _ => return Err(Error::SequenceError(index)),
+            }
+        }
+    }
+
+    /// Check the statements from the given index, up to and including the
+    /// `End` which closes their block.
+    ///
+    /// Returns the index just past the `End`.
+    fn validate_block(&self, mut index: usize, mut else_allowed: bool) -> Result<usize, Error> {
+        loop {
+            let mut iter = self.iter_statements(index);
+            index = match iter.next() {
+                Some((_, Element::End)) => return Ok(iter.index),
+                Some((_, Element::Else)) if else_allowed => {
+                    // Only one `Else` per `If`
+                    else_allowed = false;
+                    iter.index
+                }
+                _ => self.validate_statement(index)?,
+            };
+        }
+    }
+
+    /// Check the arms of a `Match`, from the given index, up to and
+    /// including the `End` which closes them.
+    ///
+    /// Every statement must be inside an arm, and the `Else` arm (if any)
+    /// must come last. Returns the index just past the `End`.
+    fn validate_arms(&self, mut index: usize) -> Result<usize, Error> {
+        let mut in_arm = false;
+        let mut else_seen = false;
+        loop {
+            let mut iter = self.iter_statements(index);
+            index = match iter.next() {
+                Some((_, Element::End)) => return Ok(iter.index),
+                Some((_, Element::Case)) if !else_seen => {
+                    in_arm = true;
+                    self.skip_expression(iter.index)?
+                }
+                Some((_, Element::Else)) if !else_seen => {
+                    in_arm = true;
+                    else_seen = true;
+                    iter.index
+                }
+                _ if in_arm => self.validate_statement(index)?,
+                _ => return Err(Error::SequenceError(index)),
+            };
+        }
+    }
+
+    /// Check the single statement at the given index, including any
+    /// expressions and blocks that belong to it.
+    ///
+    /// Returns the index just past the statement.
+    fn validate_statement(&self, index: usize) -> Result<usize, Error> {
+        let mut iter = self.iter_statements(index);
+        match iter.next() {
+            Some((
+                _,
+                Element::Nop
+                | Element::Break
+                | Element::Continue
+                | Element::Label(_)
+                | Element::Goto(_),
+            )) => Ok(iter.index),
+            Some((
+                _,
+                Element::Return
+                | Element::Assign(_)
+                | Element::AddAssign(_)
+                | Element::SubAssign(_)
+                | Element::Print
+                | Element::Stash(_),
+            )) => self.skip_expression(iter.index),
+            Some((_, Element::If)) => {
+                let body_index = self.skip_expression(iter.index)?;
+                self.validate_block(body_index, true)
+            }
+            Some((_, Element::While)) => {
+                let body_index = self.skip_expression(iter.index)?;
+                self.validate_block(body_index, false)
+            }
+            Some((_, Element::For(_))) => {
+                let end_index = self.skip_expression(iter.index)?;
+                let body_index = self.skip_expression(end_index)?;
+                self.validate_block(body_index, false)
+            }
+            Some((_, Element::Match)) => {
+                let arms_index = self.skip_expression(iter.index)?;
+                self.validate_arms(arms_index)
+            }
+            Some((_, Element::Block(length))) => {
+                // Must hold exactly one block, and nothing else
+                let mut inner = self.iter_statements(iter.index);
+                let Some((_, Element::If | Element::While | Element::For(_) | Element::Match)) =
+                    inner.next()
+                else {
+                    return Err(Error::SequenceError(iter.index));
+                };
+                let next_index = self.validate_statement(iter.index)?;
+                if next_index != iter.index + usize::from(length) {
+                    return Err(Error::SequenceError(index));
+                }
+                Ok(next_index)
+            }
+            _ => Err(Error::SequenceError(index)),
+        }
+    }
+
+    /// Work out why the element at this index couldn't be decoded.
+    ///
+    /// If all the bytes of its names and strings are there, but one of them
+    /// isn't valid UTF-8, that's an `InvalidUtf8`. Anything else is a
+    /// `SequenceError`.
+    fn decode_error(&self, index: usize) -> Error {
+        // Step over a string, returning the index just past it
+        let skip_string = |string_index: usize| -> Result<usize, Error> {
+            let len = usize::from(
+                *self
+                    .data
+                    .get(string_index)
+                    .ok_or(Error::SequenceError(index))?,
+            );
+            let bytes = self
+                .data
+                .get(string_index + 1..string_index + 1 + len)
+                .ok_or(Error::SequenceError(index))?;
+            core::str::from_utf8(bytes).map_err(|_| Error::InvalidUtf8 { index })?;
+            Ok(string_index + 1 + len)
+        };
+        let result = match self.data.get(index).copied() {
+            Some(Program::FUNCTION_ID) => skip_string(index + 1).and_then(|count_index| {
+                let count = *self
+                    .data
+                    .get(count_index)
+                    .ok_or(Error::SequenceError(index))?;
+                let mut next = count_index + 1;
+                for _ in 0..count {
+                    next = skip_string(next)?;
+                }
+                Ok(next)
+            }),
+            Some(
+                Program::STRING_ID
+                | Program::VARIABLE_ID
+                | Program::ASSIGN_ID
+                | Program::ADD_ASSIGN_ID
+                | Program::SUB_ASSIGN_ID
+                | Program::CONST_ID
+                | Program::FOR_ID
+                | Program::CALL_ID
+                | Program::MAP_ID
+                | Program::FILTER_ID
+                | Program::LABEL_ID
+                | Program::GOTO_ID,
+            ) => skip_string(index + 1),
+            _ => Ok(index),
+        };
+        match result {
+            Err(e) => e,
+            Ok(_) => Error::SequenceError(index),
+        }
+    }
+
+    /// Assuming there's a string at this index, we pull an (8-bit) length, then interpret that many bytes as UTF-8.
+    ///
+    /// Returns None if we run out of bytes or it doesn't look like valid UTF-8.
+    fn read_string(&self, index: usize) -> Option<&'a str> {
+        let len = usize::from(*self.data.get(index)?);
+        let bytes = self.data.get(index + 1..index + 1 + len)?;
+        core::str::from_utf8(bytes).ok()
+    }
+
+    /// Read the `N` bytes of an integer at this index, and put them in big
+    /// endian order (whichever order the program stores them in).
+    ///
+    /// Returns None if we run out of bytes.
+    fn read_number<const N: usize>(&self, index: usize) -> Option<[u8; N]> {
+        let mut bytes = <[u8; N]>::try_from(self.data.get(index..index + N)?).ok()?;
+        if self.endianness == Endianness::Little {
+            bytes.reverse();
+        }
+        Some(bytes)
+    }
+}
+
+impl<'c, 'a> Interpreter<'c, 'a> {
+    /// Send anything the function prints to `output`.
+    pub fn with_output(mut self, output: &'c mut dyn core::fmt::Write) -> Interpreter<'c, 'a> {
+        self.context.output = Some(output);
+        self
+    }
+
+    /// Pass calls to any function not in the program on to `host`.
+    pub fn with_host(mut self, host: &'c mut dyn HostFunctions) -> Interpreter<'c, 'a> {
+        self.context.host = Some(host);
+        self
+    }
+
+    /// Run the next statement.
+    ///
+    /// A block statement (like an `If`) just checks its condition, and the
+    /// statements in the block are run by the steps which follow.
+    pub fn step(&mut self) -> Result<StepResult<'a>, Error> {
+        if self.finished {
+            return Ok(StepResult::Finished);
+        }
+        let mut iter = self.program.iter_statements(self.index);
+        let Some((_, statement)) = iter.next() else {
+            return Err(Error::SequenceError(self.index));
+        };
+        let next_index = iter.index;
+        self.context.use_budget()?;
+        self.context.count(self.program.data[self.index]);
+        if let Some(hook) = self.context.hook.as_mut() {
+            hook(self.index, &statement);
+        }
+        match statement {
+            Element::Nop | Element::Block(_) | Element::Label(_) => {
+                self.index = next_index;
+            }
+            Element::Goto(name) => {
+                // Leave every block we're in
+                self.index = self.program.find_label(self.start, name)?;
+                self.num_blocks = 0;
+            }
+            Element::Return => {
+                let (_, value) = self.evaluate_statement(next_index)?;
+                self.finished = true;
+                return Ok(StepResult::Returned(value));
+            }
+            Element::Assign(name) => {
+                self.program.check_assign(name, &self.scope)?;
+                let (next_index, value) = self.evaluate_statement(next_index)?;
+                self.scope.set(name, value)?;
+                self.index = next_index;
+            }
+            Element::AddAssign(name) => {
+                self.program.check_assign(name, &self.scope)?;
+                let (next_index, value) = self.evaluate_statement(next_index)?;
+                self.scope.update(name, |current| current.add(value))?;
+                self.index = next_index;
+            }
+            Element::SubAssign(name) => {
+                self.program.check_assign(name, &self.scope)?;
+                let (next_index, value) = self.evaluate_statement(next_index)?;
+                self.scope.update(name, |current| current.subtract(value))?;
+                self.index = next_index;
+            }
+            Element::Print => {
+                let (next_index, value) = self.evaluate_statement(next_index)?;
+                self.context.print(&value)?;
+                self.index = next_index;
+            }
+            Element::Stash(slot) => {
+                let (next_index, value) = self.evaluate(next_index)?;
+                self.scope.stash(slot, value)?;
+                self.index = next_index;
+            }
+            Element::If => {
+                let (body_index, condition) = self.evaluate_statement(next_index)?;
+                if condition.is_true()? {
+                    self.push(OpenBlock::If)?;
+                    self.index = body_index;
+                } else {
+                    match self.program.skip_block(body_index)? {
+                        BlockEnd::Else(else_index) => {
+                            self.push(OpenBlock::If)?;
+                            self.index = else_index;
+                        }
+                        BlockEnd::End(end_index) => self.index = end_index,
+                        BlockEnd::Case(_) => return Err(Error::SequenceError(self.index)),
+                        _ => unreachable!("skip_block only finds End, Else or Case"),
+                    }
+                }
+            }
+            Element::Else => match self.pop()? {
+                // We ran the first half, so skip the second
+                OpenBlock::If => self.index = self.program.skip_to_end(next_index)?,
+                // We ran an arm, so skip the rest
+                OpenBlock::Match => self.index = self.program.skip_arms(next_index)?,
+                _ => return Err(Error::SequenceError(self.index)),
+            },
+            Element::Match => {
+                let (arms_index, scrutinee) = self.evaluate(next_index)?;
+                let program = self.program;
+                match program.choose_case(arms_index, &scrutinee, |case_index| {
+                    self.evaluate(case_index)
+                })? {
+                    CaseChoice::Arm(body_index) => {
+                        self.push(OpenBlock::Match)?;
+                        self.index = body_index;
+                    }
+                    CaseChoice::NoArm(end_index) => self.index = end_index,
+                }
+            }
+            Element::Case => match self.pop()? {
+                // We ran an arm, so skip the rest
+                OpenBlock::Match => self.index = self.program.skip_arms(next_index)?,
+                _ => return Err(Error::SequenceError(self.index)),
+            },
+            Element::While => {
+                let (body_index, condition) = self.evaluate(next_index)?;
+                if condition.is_true()? {
+                    self.push(OpenBlock::While {
+                        start: self.index,
+                        body: body_index,
+                    })?;
+                    self.index = body_index;
+                } else {
+                    self.index = self.program.skip_to_end(body_index)?;
+                }
+            }
+            Element::For(name) => {
+                self.program.check_assign(name, &self.scope)?;
+                let (end_index, start) = self.evaluate(next_index)?;
+                let (body_index, end) = self.evaluate(end_index)?;
+                let (start, end) = match (&start, &end) {
+                    (Value::Integer(start), Value::Integer(end)) => (*start, *end),
+                    _ => return Err(start.mismatch(&end)),
+                };
+                if start > end {
+                    self.index = self.program.skip_to_end(body_index)?;
+                } else {
+                    self.scope.set(name, Value::Integer(start))?;
+                    self.push(OpenBlock::For {
+                        name,
+                        value: start,
+                        end,
+                        body: body_index,
+                    })?;
+                    self.index = body_index;
+                }
+            }
+            Element::End => {
+                if self.num_blocks == 0 {
+                    // The end of the function
+                    self.finished = true;
+                    return Ok(StepResult::Finished);
+                }
+                self.close_block(next_index)?;
+            }
+            Element::Break => {
+                let body_index = self.innermost_loop()?;
+                self.pop()?;
+                self.index = self.program.skip_to_end(body_index)?;
+            }
+            Element::Continue => {
+                let body_index = self.innermost_loop()?;
+                let end_index = self.program.skip_to_end(body_index)?;
+                self.close_block(end_index)?;
+            }
+            _ => return Err(Error::SequenceError(self.index)),
+        }
+        Ok(StepResult::Continue)
+    }
+
+    /// Evaluate the expression at the given index.
+    fn evaluate(&mut self, index: usize) -> Result<(usize, Value<'a>), Error> {
+        self.program
+            .evaluate_expression(index, &mut self.context, &self.scope)
+    }
+
+    /// Evaluate the expression which belongs to a statement, and throw away
+    /// anything stashed for it.
+    fn evaluate_statement(&mut self, index: usize) -> Result<(usize, Value<'a>), Error> {
+        self.program
+            .evaluate_statement(index, &mut self.context, &mut self.scope)
+    }
+
+    /// We've reached the `End` of the innermost block, so either go round
+    /// again or carry on from `end_index`.
+    fn close_block(&mut self, end_index: usize) -> Result<(), Error> {
+        match self.pop()? {
+            OpenBlock::If | OpenBlock::Match => self.index = end_index,
+            // Go back and check the condition again
+            OpenBlock::While { start, .. } => self.index = start,
+            OpenBlock::For {
+                name,
+                value,
+                end,
+                body,
+            } if value < end => {
+                self.scope.set(name, Value::Integer(value + 1))?;
+                self.push(OpenBlock::For {
+                    name,
+                    value: value + 1,
+                    end,
+                    body,
+                })?;
+                self.index = body;
+            }
+            OpenBlock::For { .. } => self.index = end_index,
+        }
+        Ok(())
+    }
+
+    /// Leave any `If` or `Match` blocks until we're in a loop, and get the index of
+    /// the body of the loop.
+    fn innermost_loop(&mut self) -> Result<usize, Error> {
+        while self.num_blocks > 0 {
+            match self.blocks[self.num_blocks - 1] {
+                OpenBlock::If | OpenBlock::Match => self.num_blocks -= 1,
+                OpenBlock::While { body, .. } | OpenBlock::For { body, .. } => return Ok(body),
+            }
+        }
+        // Not in a loop
+        Err(Error::SequenceError(self.index))
+    }
+
+    /// Note that we're inside another block.
+    fn push(&mut self, block: OpenBlock<'a>) -> Result<(), Error> {
+        let slot = self
+            .blocks
+            .get_mut(self.num_blocks)
+            .ok_or(Error::TooManyOpenBlocks)?;
+        *slot = block;
+        self.num_blocks += 1;
+        Ok(())
+    }
+
+    /// Leave the innermost block.
+    fn pop(&mut self) -> Result<OpenBlock<'a>, Error> {
+        if self.num_blocks == 0 {
+            return Err(Error::SequenceError(self.index));
+        }
+        self.num_blocks -= 1;
+        Ok(self.blocks[self.num_blocks])
+    }
+}
+
+impl<'a> ProgramBuilder<'a> {
+    /// Construct a new program inside a given slice
+    ///
+    /// This writes raw program data, with no header, so load it with
+    /// `Program::new`. Use `new_with_header` if it needs to pass
+    /// `Program::try_new`.
+    pub fn new(space: &'a mut [u8]) -> ProgramBuilder<'a> {
+        ProgramBuilder {
+            data: space,
+            used: 0,
+            name_style: NameStyle::default(),
+            constant_folding: false,
+            recent: [0; RECENT_ELEMENTS],
+            num_recent: 0,
+            fold_floor: 0,
+            allow_duplicate_functions: false,
+            endianness: Endianness::Big,
+            header_len: 0,
+            counting: false,
+        }
+    }
+
+    /// Construct a builder which writes nothing, but counts how many bytes
+    /// everything inserted would use, with no limit.
+    ///
+    /// It can't look back at what it has inserted, so it doesn't know which
+    /// functions it already has, and can't fold constants.
+    fn new_counting() -> ProgramBuilder<'static> {
+        let mut builder = ProgramBuilder::new(&mut []);
+        builder.counting = true;
+        builder
+    }
+
+    /// Construct a new program inside a given slice, with a header, so
+    /// `Program::try_new` will accept it.
+    ///
+    /// Integers are big endian, as with `new`. Gives
+    /// `Error::InsufficientSpace` if `space` is too small for the header.
+    pub fn new_with_header(space: &'a mut [u8]) -> Result<ProgramBuilder<'a>, Error> {
+        Self::new_with_options(space, Endianness::Big)
+    }
+
+    /// Construct a new program inside a given slice, with a header, storing
+    /// integers in the given order.
+    ///
+    /// The header takes the first `HEADER_LEN` bytes of `space`. It holds
+    /// `PROGRAM_MAGIC`, `PROGRAM_VERSION` and the order, so
+    /// `Program::try_new` can check the data is really a program and
+    /// there's no need to pass the order in again. Gives
+    /// `Error::InsufficientSpace` if `space` is too small for the header.
+    pub fn new_with_options(
+        space: &'a mut [u8],
+        endianness: Endianness,
+    ) -> Result<ProgramBuilder<'a>, Error> {
+        if space.len() < HEADER_LEN {
+            return Err(Error::InsufficientSpace);
+        }
+        space[0..HEADER_LEN].copy_from_slice(&Self::header_for(endianness));
+        let mut builder = ProgramBuilder::new(space);
+        builder.endianness = endianness;
+        builder.header_len = HEADER_LEN;
+        builder.used = HEADER_LEN;
+        Ok(builder)
+    }
+
+    /// The header `new_with_options` writes, for the given order.
+    fn header_for(endianness: Endianness) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        header[0..4].copy_from_slice(&PROGRAM_MAGIC);
+        header[4] = PROGRAM_VERSION;
+        header[5] = match endianness {
+            Endianness::Big => Program::BIG_ENDIAN_ID,
+            Endianness::Little => Program::LITTLE_ENDIAN_ID,
+        };
+        header
+    }
+
+    /// Look at what we've built so far. Indices in it start after any
+    /// header.
+    fn program(&self) -> Program<'_> {
+        Program {
+            data: &self.data[self.header_len..self.used],
+            endianness: self.endianness,
+            header: &self.data[0..self.header_len],
+        }
+    }
+
+    /// Work out arithmetic on constants as it is inserted, to save space.
+    ///
+    /// For example, inserting `Add`, `Integer(2)` and `Integer(3)` gives
+    /// just `Integer(5)`. Anything which would fail (like an overflow or a
+    /// divide by zero) is left as it is, so it fails when it runs instead.
+    pub fn with_constant_folding(mut self) -> ProgramBuilder<'a> {
+        self.constant_folding = true;
+        self
+    }
+
+    /// Choose which characters are allowed in the names of the functions we
+    /// insert. The default is `NameStyle::Unicode`.
+    pub fn set_name_style(&mut self, name_style: NameStyle) {
+        self.name_style = name_style;
+    }
+
+    /// Choose whether we can insert a function with the same name as one
+    /// we've already got. If so, calls will find the first one. The default
+    /// is to give `Error::DuplicateFunction`.
+    pub fn set_allow_duplicate_functions(&mut self, allow: bool) {
+        self.allow_duplicate_functions = allow;
+    }
+
+    /// Insert an element
+    ///
+    /// Either the whole element is added, or (if there's an error) nothing
+    /// is.
+    pub fn insert(&mut self, element: &Element) -> Result<(), Error> {
+        let start = self.used;
+        self.insert_element(element, !self.allow_duplicate_functions)?;
+        if self.constant_folding {
+            self.remember(start);
+            if let Element::Integer(_) | Element::Long(_) | Element::Float(_) = element {
+                self.fold_constants()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Note that an element starts at this index.
+    fn remember(&mut self, start: usize) {
+        // Anything at or after this point has been removed
+        while self.num_recent > 0 && self.recent[self.num_recent - 1] >= start {
+            self.num_recent -= 1;
+        }
+        if self.num_recent == RECENT_ELEMENTS {
+            self.recent.copy_within(1.., 0);
+            self.num_recent -= 1;
+        }
+        self.recent[self.num_recent] = start;
+        self.num_recent += 1;
+    }
+
+    /// If the last three elements are an arithmetic operation and its two
+    /// constant operands, replace them with the answer.
+    fn fold_constants(&mut self) -> Result<(), Error> {
+        if self.num_recent < 3 {
+            return Ok(());
+        }
+        let op_start = self.recent[self.num_recent - 3];
+        if op_start < self.fold_floor {
+            // Those bytes are from before a checkpoint, or an `insert_all`
+            return Ok(());
+        }
+        let answer = {
+            let program = self.program();
+            let mut iter = program.iter_statements(op_start - self.header_len);
+            // Check the elements really are next to each other, as something
+            // like `begin_block` may have inserted bytes we didn't see
+            let (Some((_, op)), Some((_, a)), Some((_, b))) =
+                (iter.next(), iter.next(), iter.next())
+            else {
+                return Ok(());
+            };
+            if iter.index + self.header_len != self.used {
+                return Ok(());
+            }
+            let (Some(a), Some(b)) = (Self::constant_value(&a), Self::constant_value(&b)) else {
+                return Ok(());
+            };
+            let answer = match op {
+                Element::Add => a.add(b),
+                Element::Subtract => a.subtract(b),
+                Element::Multiply => a.multiply(b),
+                Element::Divide => a.divide(b),
+                Element::Modulo => a.remainder(b),
+                _ => return Ok(()),
+            };
+            match answer {
+                Ok(Value::Integer(i)) => Element::Integer(i),
+                Ok(Value::Long(i)) => Element::Long(i),
+                Ok(Value::Float(f)) if f.is_finite() => Element::Float(f),
+                // Leave it for run time
+                _ => return Ok(()),
+            }
+        };
+        self.used = op_start;
+        // This might fold again, with whatever came before
+        self.insert(&answer)
+    }
+
+    /// Get the value of a numeric literal.
+    fn constant_value(element: &Element) -> Option<Value<'static>> {
+        match element {
+            Element::Integer(i) => Some(Value::Integer(*i)),
+            Element::Long(i) => Some(Value::Long(*i)),
+            Element::Float(f) => Some(Value::Float(*f)),
+            _ => None,
+        }
+    }
+
+    /// Whether we already have a function with this name.
+    fn has_function(&self, name: &str) -> bool {
+        !self.counting && self.program().find_body(name).is_some()
+    }
+
+    /// Insert an element, without any constant folding. A function with the
+    /// same name as one we already have is only turned down if
+    /// `check_duplicates` is set.
+    fn insert_element(&mut self, element: &Element, check_duplicates: bool) -> Result<(), Error> {
+        match element {
+            Element::Nop => self.insert_slice(&[&[Program::NOP_ID]]),
+            Element::End => self.insert_slice(&[&[Program::END_ID]]),
+            Element::Function { name, params } => {
+                validate_name(name, self.name_style)?;
+                if check_duplicates && self.has_function(name) {
+                    return Err(Error::DuplicateFunction);
+                }
+                let name_len = Self::name_length(name)?;
+                let count = params.len();
+                if count > MAX_VARIABLES {
+                    return Err(Error::TooManyVariables);
+                }
+                let mut lengths = [0u8; MAX_VARIABLES];
+                for (length, param) in lengths.iter_mut().zip(params.iter()) {
+                    validate_name(param, self.name_style)?;
+                    *length = Self::name_length(param)?;
+                }
+                let header = [Program::FUNCTION_ID, name_len];
+                let count_byte = [count as u8];
+                // Each parameter is a length, then the name
+                let mut parts: [&[u8]; 3 + 2 * MAX_VARIABLES] = [&[]; 3 + 2 * MAX_VARIABLES];
+                parts[0] = &header;
+                parts[1] = name.as_bytes();
+                parts[2] = &count_byte;
+                for (idx, (length, param)) in lengths.iter().zip(params.iter()).enumerate() {
+                    parts[3 + (2 * idx)] = core::slice::from_ref(length);
+                    parts[4 + (2 * idx)] = param.as_bytes();
+                }
+                self.insert_slice(&parts[0..3 + (2 * count)])
+            }
+            Element::Print => self.insert_slice(&[&[Program::PRINT_ID]]),
+            Element::Return => self.insert_slice(&[&[Program::RETURN_ID]]),
+            Element::Integer(i) => {
+                let mut buffer = [0u8; 5];
+                let encoded_integer = Self::encode_integer_as(&mut buffer, *i, self.endianness);
+                self.insert_slice(&[encoded_integer])
+            }
+            Element::Add => self.insert_slice(&[&[Program::ADD_ID]]),
+            Element::Multiply => self.insert_slice(&[&[Program::MULTIPLY_ID]]),
+            Element::Divide => self.insert_slice(&[&[Program::DIVIDE_ID]]),
+            Element::Subtract => self.insert_slice(&[&[Program::SUBTRACT_ID]]),
+            Element::Negate => self.insert_slice(&[&[Program::NEGATE_ID]]),
+            Element::BitAnd => self.insert_slice(&[&[Program::BIT_AND_ID]]),
+            Element::BitOr => self.insert_slice(&[&[Program::BIT_OR_ID]]),
+            Element::BitXor => self.insert_slice(&[&[Program::BIT_XOR_ID]]),
+            Element::ShiftLeft => self.insert_slice(&[&[Program::SHIFT_LEFT_ID]]),
+            Element::ShiftRight => self.insert_slice(&[&[Program::SHIFT_RIGHT_ID]]),
+            Element::If => self.insert_slice(&[&[Program::IF_ID]]),
+            Element::Else => self.insert_slice(&[&[Program::ELSE_ID]]),
+            Element::While => self.insert_slice(&[&[Program::WHILE_ID]]),
+            Element::Break => self.insert_slice(&[&[Program::BREAK_ID]]),
+            Element::For(name) => {
+                let len = Self::name_length(name)?;
+                self.insert_slice(&[&[Program::FOR_ID, len], name.as_bytes()])
+            }
+            Element::Continue => self.insert_slice(&[&[Program::CONTINUE_ID]]),
+            Element::Variable(name) => {
+                let len = Self::name_length(name)?;
+                self.insert_slice(&[&[Program::VARIABLE_ID, len], name.as_bytes()])
+            }
+            Element::Assign(name) => {
+                let len = Self::name_length(name)?;
+                self.insert_slice(&[&[Program::ASSIGN_ID, len], name.as_bytes()])
+            }
+            Element::AddAssign(name) => {
+                let len = Self::name_length(name)?;
+                self.insert_slice(&[&[Program::ADD_ASSIGN_ID, len], name.as_bytes()])
+            }
+            Element::SubAssign(name) => {
+                let len = Self::name_length(name)?;
+                self.insert_slice(&[&[Program::SUB_ASSIGN_ID, len], name.as_bytes()])
+            }
+            Element::Call(name, num_args) => {
+                let len = Self::name_length(name)?;
+                self.insert_slice(&[&[Program::CALL_ID, len], name.as_bytes(), &[*num_args]])
+            }
+            Element::Equal => self.insert_slice(&[&[Program::EQUAL_ID]]),
+            Element::NotEqual => self.insert_slice(&[&[Program::NOT_EQUAL_ID]]),
+            Element::Less => self.insert_slice(&[&[Program::LESS_ID]]),
+            Element::LessEqual => self.insert_slice(&[&[Program::LESS_EQUAL_ID]]),
+            Element::Greater => self.insert_slice(&[&[Program::GREATER_ID]]),
+            Element::GreaterEqual => self.insert_slice(&[&[Program::GREATER_EQUAL_ID]]),
+            Element::And => self.insert_slice(&[&[Program::AND_ID]]),
+            Element::Or => self.insert_slice(&[&[Program::OR_ID]]),
+            Element::Not => self.insert_slice(&[&[Program::NOT_ID]]),
+            Element::Boolean(false) => self.insert_slice(&[&[Program::FALSE_ID]]),
+            Element::Boolean(true) => self.insert_slice(&[&[Program::TRUE_ID]]),
+            Element::Vector(num_items) => self.insert_slice(&[&[Program::VECTOR_ID, *num_items]]),
+            Element::Index => self.insert_slice(&[&[Program::INDEX_ID]]),
+            Element::Len => self.insert_slice(&[&[Program::LEN_ID]]),
+            Element::Modulo => self.insert_slice(&[&[Program::MODULO_ID]]),
+            Element::Pow => self.insert_slice(&[&[Program::POW_ID]]),
+            Element::ToFloat => self.insert_slice(&[&[Program::TO_FLOAT_ID]]),
+            Element::ToInt => self.insert_slice(&[&[Program::TO_INT_ID]]),
+            Element::Block(length) => {
+                let length = length.to_be_bytes();
+                self.insert_slice(&[&[Program::BLOCK_ID], &length])
+            }
+            Element::Long(i) => {
+                let bytes = match self.endianness {
+                    Endianness::Big => i.to_be_bytes(),
+                    Endianness::Little => i.to_le_bytes(),
+                };
+                self.insert_slice(&[&[Program::LONG_ID], &bytes])
+            }
+            Element::Nil => self.insert_slice(&[&[Program::NIL_ID]]),
+            Element::Select => self.insert_slice(&[&[Program::SELECT_ID]]),
+            Element::Substring => self.insert_slice(&[&[Program::SUBSTRING_ID]]),
+            Element::ToStr => self.insert_slice(&[&[Program::TO_STR_ID]]),
+            Element::ParseInt => self.insert_slice(&[&[Program::PARSE_INT_ID]]),
+            Element::ParseFloat => self.insert_slice(&[&[Program::PARSE_FLOAT_ID]]),
+            Element::Const(name) => {
+                let len = Self::name_length(name)?;
+                self.insert_slice(&[&[Program::CONST_ID, len], name.as_bytes()])
+            }
+            Element::Sort => self.insert_slice(&[&[Program::SORT_ID]]),
+            Element::Reverse => self.insert_slice(&[&[Program::REVERSE_ID]]),
+            Element::Map(name) => {
+                let len = Self::name_length(name)?;
+                self.insert_slice(&[&[Program::MAP_ID, len], name.as_bytes()])
+            }
+            Element::Filter(name) => {
+                let len = Self::name_length(name)?;
+                self.insert_slice(&[&[Program::FILTER_ID, len], name.as_bytes()])
+            }
+            Element::Push => self.insert_slice(&[&[Program::PUSH_ID]]),
+            Element::Match => self.insert_slice(&[&[Program::MATCH_ID]]),
+            Element::Case => self.insert_slice(&[&[Program::CASE_ID]]),
+            Element::EqualsIgnoreCase => self.insert_slice(&[&[Program::EQUALS_IGNORE_CASE_ID]]),
+            Element::ToUpper => self.insert_slice(&[&[Program::TO_UPPER_ID]]),
+            Element::ToLower => self.insert_slice(&[&[Program::TO_LOWER_ID]]),
+            Element::Checksum(crc) => {
+                // Stored as big endian, whatever the integers use
+                self.insert_slice(&[&[Program::CHECKSUM_ID], &crc.to_be_bytes()])
+            }
+            Element::Abs => self.insert_slice(&[&[Program::ABS_ID]]),
+            Element::Min => self.insert_slice(&[&[Program::MIN_ID]]),
+            Element::Max => self.insert_slice(&[&[Program::MAX_ID]]),
+            Element::Sqrt => self.insert_slice(&[&[Program::SQRT_ID]]),
+            Element::Sin => self.insert_slice(&[&[Program::SIN_ID]]),
+            Element::Cos => self.insert_slice(&[&[Program::COS_ID]]),
+            Element::Label(name) => {
+                let len = Self::name_length(name)?;
+                self.insert_slice(&[&[Program::LABEL_ID, len], name.as_bytes()])
+            }
+            Element::Goto(name) => {
+                let len = Self::name_length(name)?;
+                self.insert_slice(&[&[Program::GOTO_ID, len], name.as_bytes()])
+            }
+            Element::Stash(slot) => self.insert_slice(&[&[Program::STASH_ID, *slot]]),
+            Element::Stashed(slot) => self.insert_slice(&[&[Program::STASHED_ID, *slot]]),
+            Element::StringLiteral(s) => {
+                let len = u8::try_from(s.len()).map_err(|_| Error::StringTooLong)?;
+                self.insert_slice(&[&[Program::STRING_ID, len], s.as_bytes()])
+            }
+            Element::EscapedString(s) => {
+                let mut buffer = [0u8; u8::MAX as usize];
+                let mut len = 0;
+                unescape(s, |bytes| {
+                    let end = len + bytes.len();
+                    buffer
+                        .get_mut(len..end)
+                        .ok_or(Error::StringTooLong)?
+                        .copy_from_slice(bytes);
+                    len = end;
+                    Ok(())
+                })?;
+                self.insert_slice(&[&[Program::STRING_ID, len as u8], &buffer[..len]])
+            }
+            Element::Float(f) => {
+                if !f.is_finite() {
+                    return Err(Error::NonFiniteFloat);
+                }
+                // Stored as big endian IEEE-754
+                self.insert_slice(&[&[Program::FLOAT_ID], &f.to_be_bytes()])
+            }
+        }
+    }
+
+    /// Encode an integer, big endian.
+    pub fn encode_integer(buffer: &mut [u8; 5], integer: i32) -> &[u8] {
+        Self::encode_integer_as(buffer, integer, Endianness::Big)
+    }
+
+    /// Encode an integer, with its bytes in the given order.
+    pub fn encode_integer_as(buffer: &mut [u8; 5], integer: i32, endianness: Endianness) -> &[u8] {
+        let len = Self::encode_integer_be(buffer, integer).len();
+        if endianness == Endianness::Little {
+            buffer[1..len].reverse();
+        }
+        &buffer[0..len]
+    }
+
+    /// Encode an integer, big endian, picking the shortest opcode it fits.
+    fn encode_integer_be(buffer: &mut [u8; 5], integer: i32) -> &[u8] {
+        let bytes = integer.to_be_bytes();
+        if integer >= 0 {
+            // Positive
+            if integer < 1 << 7 {
+                // i8
+                buffer[0] = Program::INTEGER1_ID;
+                buffer[1] = bytes[3];
+                &buffer[0..2]
+            } else if integer < 1 << 15 {
+                // i16
+                buffer[0] = Program::INTEGER2_ID;
+                buffer[1] = bytes[2];
+                buffer[2] = bytes[3];
+                &buffer[0..3]
+            } else if integer < 1 << 23 {
+                // i24
+                buffer[0] = Program::INTEGER3_ID;
+                buffer[1] = bytes[1];
+                buffer[2] = bytes[2];
+                buffer[3] = bytes[3];
+                &buffer[0..4]
+            } else {
+                // i32
+                buffer[0] = Program::INTEGER4_ID;
+                buffer[1] = bytes[0];
+                buffer[2] = bytes[1];
+                buffer[3] = bytes[2];
+                buffer[4] = bytes[3];
+                &buffer[0..5]
+            }
+        } else {
+            // Negative
+            if integer >= -(1 << 7) {
+                // i8
+                buffer[0] = Program::INTEGER1_ID;
+                buffer[1] = bytes[3];
+                &buffer[0..2]
+            } else if integer >= -(1 << 15) {
+                // i16
+                buffer[0] = Program::INTEGER2_ID;
+                buffer[1] = bytes[2];
+                buffer[2] = bytes[3];
+                &buffer[0..3]
+            } else if integer >= -(1 << 23) {
+                // i24
+                buffer[0] = Program::INTEGER3_ID;
+                buffer[1] = bytes[1];
+                buffer[2] = bytes[2];
+                buffer[3] = bytes[3];
+                &buffer[0..4]
+            } else {
+                // i32
+                buffer[0] = Program::INTEGER4_ID;
+                buffer[1] = bytes[0];
+                buffer[2] = bytes[1];
+                buffer[3] = bytes[2];
+                buffer[4] = bytes[3];
+                &buffer[0..5]
+            }
+        }
+    }
+
+    /// Encode an integer as signed LEB128, which uses seven bits from each
+    /// byte, with the top bit set on every byte but the last.
+    ///
+    /// This is an alternative to `encode_integer`, and always uses the same
+    /// opcode. Small numbers (from -64 to 63) take one byte after the
+    /// opcode, and the biggest take five.
+    pub fn encode_integer_leb128(buffer: &mut [u8; 6], integer: i32) -> &[u8] {
+        buffer[0] = Program::INTEGER_VARINT_ID;
+        let mut value = integer;
+        let mut len = 1;
+        loop {
+            let byte = (value & 0x7F) as u8;
+            // This is an arithmetic shift, so it keeps the sign
+            value >>= 7;
+            // Stop when all that's left is sign bits, and the sign bit of
+            // this byte is right
+            let done = (value == 0 && (byte & 0x40) == 0) || (value == -1 && (byte & 0x40) != 0);
+            if done {
+                buffer[len] = byte;
+                return &buffer[0..len + 1];
+            }
+            buffer[len] = byte | 0x80;
+            len += 1;
+        }
+    }
+
+    /// Insert several elements, in order.
+    ///
+    /// This either inserts all of the elements, or none of them - if any
+    /// element fails to insert, everything this call added is removed again
+    /// before the error is returned.
+    pub fn insert_all(&mut self, elements: &[Element]) -> Result<(), Error> {
+        let start = self.used;
+        // Don't fold into what was there before, so we can go back to it
+        let old_floor = self.fold_floor;
+        self.fold_floor = old_floor.max(start);
+        let result = elements.iter().try_for_each(|element| self.insert(element));
+        self.fold_floor = old_floor;
+        if result.is_err() {
+            self.used = start;
+        }
+        result
+    }
+
+    /// Insert the start of a function, and say where its body begins.
+    ///
+    /// The index returned is where the function's first statement goes, as
+    /// given by `Program::function_index` for the finished program, so a
+    /// host can build its own table of names and indices as it goes.
+    pub fn insert_function(&mut self, name: &str, params: Params) -> Result<usize, Error> {
+        self.insert(&Element::Function { name, params })?;
+        Ok(self.used - self.header_len)
+    }
+
+    /// Parse some source text, one element per line, and insert each element.
+    ///
+    /// Blank lines are skipped, as is any indentation, so the output of
+    /// `Program::disassemble` can be read back in. Several elements can go
+    /// on one line if they are separated by `;`. A `#` or `//` starts a
+    /// comment which runs to the end of the line. Neither counts if it is
+    /// inside a string literal. If a line can't be parsed we stop and return
+    /// `Error::ParseError`, but anything from earlier lines stays in the
+    /// program.
+    ///
+    /// A comparison can also be written on one line, as `a < b`, where each
+    /// operand is a single element (such as a variable, a literal or a `call`
+    /// with no arguments). Comparisons can be chained: `a < b <= c` means
+    /// `a < b && b <= c`, except that `b` is only worked out once. Every
+    /// operand but the last is worked out first, in order, and kept with a
+    /// `Stash` just before the statement, so a chain has to be the whole
+    /// expression of a `return`, `print`, `let`, `+=`, `-=` or `if`, or you
+    /// get `Error::ChainedComparison`.
+    pub fn assemble(&mut self, source: &str) -> Result<(), Error> {
+        self.assemble_lines(source.lines())
+    }
+
+    /// Like `assemble`, but taking the source one line at a time.
+    ///
+    /// Each line is parsed and inserted before the next is asked for, so the
+    /// whole of the source never needs to be in memory at once. Line numbers
+    /// in any `Error::ParseError` count from 1 at the first line given.
+    pub fn assemble_lines<'s, I>(&mut self, lines: I) -> Result<(), Error>
+    where
+        I: Iterator<Item = &'s str>,
+    {
+        // A statement which a chain could belong to. It isn't inserted until
+        // we've seen what comes next, in case that needs stashes first.
+        let mut statement = None;
+        for (line_idx, line) in lines.enumerate() {
+            let parse_error = |part: &str, error: Error| Error::ParseError {
+                line: line_idx + 1,
+                offset: char_offset(line, part)
+                    + match error {
+                        Error::SyntaxError(offset) => offset,
+                        _ => 0,
+                    },
+            };
+            for part in split_statements(strip_comment(line)) {
+                let element = match Element::try_from(part) {
+                    Ok(element) => element,
+                    Err(error) => {
+                        // If it isn't a chain either, report why it wasn't an element
+                        let chain = match Chain::parse(part).and_then(|chain| chain.ok_or(error)) {
+                            Ok(chain) => chain,
+                            Err(error) => {
+                                // Keep the earlier lines, as for any other error
+                                if let Some(statement) = statement.take() {
+                                    self.insert(&statement)?;
+                                }
+                                return Err(parse_error(part, error));
+                            }
+                        };
+                        self.insert_chain(&chain, statement.take(), line_idx + 1)?;
+                        continue;
+                    }
+                };
+                if let Some(statement) = statement.take() {
+                    self.insert(&statement)?;
+                }
+                match element {
+                    Element::Return
+                    | Element::Print
+                    | Element::Assign(_)
+                    | Element::AddAssign(_)
+                    | Element::SubAssign(_)
+                    | Element::If => statement = Some(element),
+                    _ => self.insert(&element)?,
+                }
+            }
+        }
+        if let Some(statement) = statement {
+            self.insert(&statement)?;
+        }
+        Ok(())
+    }
+
+    /// Insert a comparison chain from the given line, which is the whole
+    /// expression of `statement`, if there is one.
+    ///
+    /// A chain of more than one comparison stashes its operands first, and
+    /// so gives `Error::ChainedComparison` if there's no statement to put
+    /// them before.
+    fn insert_chain(
+        &mut self,
+        chain: &Chain,
+        statement: Option<Element>,
+        line: usize,
+    ) -> Result<(), Error> {
+        if chain.len > 1 && statement.is_none() {
+            return Err(Error::ChainedComparison { line });
+        }
+        chain.stashes(|element| self.insert(&element))?;
+        if let Some(statement) = statement {
+            self.insert(&statement)?;
+        }
+        chain.expression(|element| self.insert(&element))
+    }
+
+    /// Start a block, with a `Block` that says how long it is.
+    ///
+    /// The `Block` is inserted with a placeholder length, followed by
+    /// `element` (which should be an `If`, `While`, `For` or `Match`). Insert
+    /// the body of the block, then call `end_block` to add the `End` and fill
+    /// in the length.
+    pub fn begin_block(&mut self, element: &Element) -> Result<BlockMarker, Error> {
+        let start = self.used;
+        self.insert_slice(&[&[Program::BLOCK_ID, 0, 0]])?;
+        if let Err(e) = self.insert(element) {
+            self.used = start;
+            return Err(e);
+        }
+        Ok(BlockMarker {
+            length_index: start + 1,
+        })
+    }
+
+    /// Finish a block started with `begin_block`.
+    ///
+    /// Inserts an `End`, and goes back to fill in the length of the block.
+    /// Gives `Error::BlockTooLarge` (and inserts nothing) if the block
+    /// doesn't fit in a `Block`.
+    pub fn end_block(&mut self, marker: BlockMarker) -> Result<(), Error> {
+        let body_start = marker.length_index + 2;
+        if self.used < body_start || self.data[marker.length_index - 1] != Program::BLOCK_ID {
+            return Err(Error::InvalidHandle);
+        }
+        // Count the `End` we're about to add
+        let length = u16::try_from(self.used + 1 - body_start).map_err(|_| Error::BlockTooLarge)?;
+        self.insert_slice(&[&[Program::END_ID]])?;
+        self.data[marker.length_index..body_start].copy_from_slice(&length.to_be_bytes());
+        Ok(())
+    }
+
+    /// Get the 8-bit length of a function or variable name.
+    fn name_length(name: &str) -> Result<u8, Error> {
+        u8::try_from(name.len()).map_err(|_| Error::NameTooLong)
+    }
+
+    /// Add some bytes to the program, taken from each of the given slices in
+    /// turn.
+    ///
+    /// The space is checked once, before anything is written, so either all
+    /// of the bytes are added or none of them are.
+    fn insert_slice(&mut self, parts: &[&[u8]]) -> Result<(), Error> {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        if self.counting {
+            self.used += len;
+            return Ok(());
+        }
+        if self.free() < len {
+            return Err(Error::InsufficientSpace);
+        }
+        for part in parts {
+            self.data[self.used..self.used + part.len()].copy_from_slice(part);
+            self.used += part.len();
+        }
+        Ok(())
+    }
+
+    /// Copy the whole of another program onto the end of this one. This lets
+    /// you build a library of functions once, and then put it in front of
+    /// other code.
+    ///
+    /// The other program is checked with `Program::validate` first. If it
+    /// ends with a `Checksum`, that is left off, as it doesn't cover the
+    /// joined-up program.
+    ///
+    /// If there isn't room for all of it, nothing is copied and you get
+    /// `Error::InsufficientSpace`. Unless duplicates are allowed, nothing is
+    /// copied if it has a function with the same name as one of ours either.
+    pub fn append_program(&mut self, other: &Program) -> Result<(), Error> {
+        other.validate()?;
+        if !self.allow_duplicate_functions && other.functions().any(|name| self.has_function(name))
+        {
+            return Err(Error::DuplicateFunction);
+        }
+        let data = match other.iter_with_spans().last() {
+            Some((start, _, Element::Checksum(_))) => &other.data[0..start],
+            _ => other.data,
+        };
+        if other.endianness == self.endianness {
+            return self.insert_slice(&[data]);
+        }
+        // The integers need their bytes swapping, so go element by element
+        let start = self.used;
+        let result = other
+            .iter_statements(0)
+            .take_while(|(index, _)| *index < data.len())
+            .try_for_each(|(_, element)| self.insert_element(&element, false));
+        if result.is_err() {
+            self.data[start..self.used].fill(0);
+            self.used = start;
+        }
+        result
+    }
+
+    /// How many bytes are used? This includes any header.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Look at the bytes used so far, including any header. These can be
+    /// copied somewhere else and given to `Program::new`.
+    pub fn snapshot_bytes(&self) -> &[u8] {
+        &self.data[0..self.used]
+    }
+
+    /// How many bytes are free?
+    pub fn free(&self) -> usize {
+        self.data.len() - self.used
+    }
+
+    /// Remember how much of the space is used, so we can go back to it with
+    /// `restore`.
+    ///
+    /// Constant folding won't rewrite anything from before the checkpoint,
+    /// so `restore` always gets back exactly what was there.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        self.fold_floor = self.fold_floor.max(self.used);
+        Checkpoint {
+            used: self.used,
+            data_addr: self.data.as_ptr() as usize,
+        }
+    }
+
+    /// Remove everything inserted since the checkpoint was made. The space
+    /// that frees up is zeroed.
+    ///
+    /// Gives `Error::InvalidHandle` if the checkpoint came from a different
+    /// builder, or if we've already gone back past it.
+    pub fn restore(&mut self, checkpoint: Checkpoint) -> Result<(), Error> {
+        if checkpoint.data_addr != self.data.as_ptr() as usize || checkpoint.used > self.used {
+            return Err(Error::InvalidHandle);
+        }
+        self.data[checkpoint.used..self.used].fill(0);
+        self.used = checkpoint.used;
+        self.fold_floor = self.fold_floor.min(self.used);
+        Ok(())
+    }
+
+    /// Copy the program we've built into an `OwnedProgram`, so the space
+    /// can be used for something else.
+    #[cfg(feature = "alloc")]
+    pub fn into_owned(self) -> OwnedProgram {
+        OwnedProgram::new(self.data[0..self.used].to_vec())
+    }
+
+    /// Finish the program by adding a `Checksum` holding the CRC32 of
+    /// everything before it, including any header. `Program::verify_crc`
+    /// can then spot if the program has been corrupted.
+    ///
+    /// Returns how many bytes the finished program uses (the same as `used`
+    /// would), or `Error::InsufficientSpace` if the checksum doesn't fit.
+    pub fn finalize_with_crc(mut self) -> Result<usize, Error> {
+        let crc = crc32(&[&self.data[0..self.used]]);
+        self.insert(&Element::Checksum(crc))?;
+        Ok(self.used)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl OwnedProgram {
+    /// Take ownership of some program data.
+    pub fn new(program_data: Vec<u8>) -> OwnedProgram {
+        OwnedProgram { data: program_data }
+    }
+
+    /// Borrow this as a `Program`, to get at the rest of the API.
+    pub fn as_program(&self) -> Program<'_> {
+        Program::new(&self.data)
+    }
+
+    /// See `Program::run`.
+    pub fn run(&self, function_name: &str) -> Result<Value<'_>, Error> {
+        self.as_program().run(function_name)
+    }
+
+    /// See `Program::iter_statements`.
+    pub fn iter_statements(&self, index: usize) -> ElementIter<'_> {
+        self.as_program().iter_statements(index)
+    }
+
+    /// See `Program::functions`.
+    pub fn functions(&self) -> impl Iterator<Item = &str> + '_ {
+        self.as_program().functions()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<'a> From<&Program<'a>> for Vec<Element<'a>> {
+    fn from(program: &Program<'a>) -> Vec<Element<'a>> {
+        program.to_elements()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<'a> From<&Program<'a>> for OwnedProgram {
+    fn from(program: &Program<'a>) -> OwnedProgram {
+        let mut data = Vec::with_capacity(program.header.len() + program.data.len());
+        data.extend_from_slice(program.header);
+        data.extend_from_slice(program.data);
+        OwnedProgram::new(data)
+    }
+}
+
+/// Parse a line of source which may hold several elements, separated by
+/// `;`.
+///
+/// A `;` inside a string literal doesn't count. Empty parts are skipped, so
+/// a blank line gives no elements.
+#[cfg(feature = "alloc")]
+pub fn parse_line(line: &str) -> Result<Vec<Element<'_>>, Error> {
+    split_statements(line).map(Element::try_from).collect()
+}
+
+/// Work out how many bytes `ProgramBuilder::assemble` would use for this
+/// source, without writing anything.
+///
+/// The source goes through `assemble` itself, so you get the same errors
+/// for anything it can't read - except that we don't keep the functions we
+/// have seen, so a duplicate function isn't an error here. The size is for a
+/// builder made with `ProgramBuilder::new`, without constant folding.
+pub fn estimate_size(source: &str) -> Result<usize, Error> {
+    let mut builder = ProgramBuilder::new_counting();
+    builder.assemble(source)?;
+    Ok(builder.used())
+}
+
+/// Split a line of source on any `;` which isn't inside a string literal.
+///
+/// Each part is trimmed, and empty parts are skipped.
+fn split_statements(line: &str) -> impl Iterator<Item = &str> {
+    let mut rest = Some(line);
+    core::iter::from_fn(move || loop {
+        let remaining = rest?;
+        let mut in_string = false;
+        let mut escaped = false;
+        let split_at = remaining.char_indices().find_map(|(idx, ch)| {
+            if escaped {
+                escaped = false;
+                return None;
+            }
+            match ch {
+                '\\' if in_string => {
+                    escaped = true;
+                    None
+                }
+                '"' => {
+                    in_string = !in_string;
+                    None
+                }
+                ';' if !in_string => Some(idx),
+                _ => None,
+            }
+        });
+        let part = match split_at {
+            Some(idx) => {
+                rest = Some(&remaining[idx + 1..]);
+                &remaining[..idx]
+            }
+            None => {
+                rest = None;
+                remaining
+            }
+        };
+        let part = part.trim();
+        if !part.is_empty() {
+            return Some(part);
+        }
+    })
+}
+
+/// A comparison chain, like `a < b <= c`, from one line of source.
+struct Chain<'s> {
+    /// The operands, each of which is a single element.
+    operands: [&'s str; MAX_CHAIN + 1],
+    /// The comparisons between each pair of operands.
+    comparisons: [&'s str; MAX_CHAIN],
+    /// How many comparisons there are.
+    len: usize,
+}
+
+impl<'s> Chain<'s> {
+    /// Split up a comparison chain.
+    ///
+    /// Each comparison must have whitespace either side of it, and one
+    /// inside a string literal doesn't count. Gives `None` if there are no
+    /// comparisons. Gives `Error::SyntaxError`, with the offset in
+    /// characters, if there are too many of them or an operand isn't a
+    /// single element which is a whole expression.
+    fn parse(part: &'s str) -> Result<Option<Chain<'s>>, Error> {
+        let mut chain = Chain {
+            operands: [""; MAX_CHAIN + 1],
+            comparisons: [""; MAX_CHAIN],
+            len: 0,
+        };
+        let mut operand_start = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+        for token in part.split_whitespace() {
+            let offset = token.as_ptr() as usize - part.as_ptr() as usize;
+            if !in_string && matches!(token, "<" | "<=" | ">" | ">=" | "==" | "!=") {
+                if chain.len == MAX_CHAIN {
+                    return Err(Error::SyntaxError(char_offset(part, token)));
+                }
+                chain.operands[chain.len] = part[operand_start..offset].trim();
+                chain.comparisons[chain.len] = token;
+                chain.len += 1;
+                operand_start = offset + token.len();
+                continue;
+            }
+            for ch in token.chars() {
+                match ch {
+                    _ if escaped => escaped = false,
+                    '\\' if in_string => escaped = true,
+                    '"' => in_string = !in_string,
+                    _ => {}
+                }
+            }
+        }
+        if chain.len == 0 {
+            return Ok(None);
+        }
+        chain.operands[chain.len] = part[operand_start..].trim();
+        for operand in &chain.operands[0..=chain.len] {
+            let start = char_offset(part, operand);
+            let element = Element::try_from(*operand).map_err(|error| match error {
+                Error::SyntaxError(offset) => Error::SyntaxError(start + offset),
+                _ => Error::SyntaxError(start),
+            })?;
+            if element.operand_count() != Some(0) {
+                return Err(Error::SyntaxError(start));
+            }
+        }
+        Ok(Some(chain))
+    }
+
+    /// Give the elements which stash every operand but the last, in order,
+    /// if there's more than one comparison.
+    fn stashes<F>(&self, mut emit: F) -> Result<(), Error>
+    where
+        F: FnMut(Element<'s>) -> Result<(), Error>,
+    {
+        if self.len == 1 {
+            return Ok(());
+        }
+        for (slot, operand) in self.operands[0..self.len].iter().enumerate() {
+            emit(Element::Stash(slot as u8))?;
+            emit(Element::try_from(*operand)?)?;
+        }
+        Ok(())
+    }
+
+    /// Give the elements of the expression, which `&&`s together each
+    /// comparison, reading any stashed operands back.
+    fn expression<F>(&self, mut emit: F) -> Result<(), Error>
+    where
+        F: FnMut(Element<'s>) -> Result<(), Error>,
+    {
+        let operand = |idx: usize| {
+            if self.len == 1 || idx == self.len {
+                Element::try_from(self.operands[idx])
+            } else {
+                Ok(Element::Stashed(idx as u8))
+            }
+        };
+        for _ in 1..self.len {
+            emit(Element::And)?;
+        }
+        for idx in 0..self.len {
+            emit(Element::try_from(self.comparisons[idx])?)?;
+            emit(operand(idx)?)?;
+            emit(operand(idx + 1)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove any comment from the end of a line of source.
+///
+/// A comment starts with `#` or `//`, but not if it is inside a string
+/// literal.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (idx, ch) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match ch {
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..idx],
+            '/' if !in_string && line[idx..].starts_with("//") => return &line[..idx],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Parse a string literal, given everything after its opening quote.
+///
+/// If there are no escapes, we get a `StringLiteral`, otherwise an
+/// `EscapedString`. Gives `Error::SyntaxError` if an escape isn't one we
+/// understand, or if there's no closing quote at the end.
+fn parse_string_literal(rest: &str) -> Result<Element<'_>, Error> {
+    // Counting the opening quote
+    let unterminated = Error::SyntaxError(rest.chars().count() + 1);
+    let contents = rest.strip_suffix('"').ok_or(unterminated.clone())?;
+    // The closing quote mustn't have been escaped
+    let backslashes = contents.len() - contents.trim_end_matches('\\').len();
+    if backslashes % 2 == 1 {
+        return Err(unterminated);
+    }
+    if !contents.contains('\\') {
+        return Ok(Element::StringLiteral(contents));
+    }
+    unescape(contents, |_| Ok(())).map_err(|e| match e {
+        Error::SyntaxError(offset) => Error::SyntaxError(offset + 1),
+        e => e,
+    })?;
+    Ok(Element::EscapedString(contents))
+}
+
+/// Decode the escapes in a string literal, passing each piece of the
+/// decoded string to `emit`, in order.
+///
+/// We understand `\n`, `\t`, `\\`, `\"` and `\xNN`, which must be an ASCII
+/// character so the result is still valid UTF-8. Any other escape gives
+/// `Error::SyntaxError`, with the offset in characters of its `\`.
+fn unescape<F>(contents: &str, mut emit: F) -> Result<(), Error>
+where
+    F: FnMut(&[u8]) -> Result<(), Error>,
+{
+    let mut chars = contents.chars().enumerate();
+    while let Some((offset, ch)) = chars.next() {
+        if ch != '\\' {
+            let mut buffer = [0u8; 4];
+            emit(ch.encode_utf8(&mut buffer).as_bytes())?;
+            continue;
+        }
+        let byte = match chars.next() {
+            Some((_, 'n')) => b'\n',
+            Some((_, 't')) => b'\t',
+            Some((_, '\\')) => b'\\',
+            Some((_, '"')) => b'"',
+            Some((_, 'x')) => {
+                let mut value = 0;
+                for _ in 0..2 {
+                    let digit = chars.next().and_then(|(_, ch)| ch.to_digit(16));
+                    value = value * 16 + digit.ok_or(Error::SyntaxError(offset))?;
+                }
+                u8::try_from(value)
+                    .ok()
+                    .filter(u8::is_ascii)
+                    .ok_or(Error::SyntaxError(offset))?
+            }
+            _ => return Err(Error::SyntaxError(offset)),
+        };
+        emit(&[byte])?;
+    }
+    Ok(())
+}
+
+/// Write out the contents of a string literal, escaping anything which
+/// `unescape` would need to get it back.
+fn write_escaped(f: &mut core::fmt::Formatter, s: &str) -> core::fmt::Result {
+    for ch in s.chars() {
+        match ch {
+            '\\' => write!(f, "\\\\")?,
+            '"' => write!(f, "\\\"")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            ch if ch.is_ascii_control() => write!(f, "\\x{:02x}", ch as u8)?,
+            ch => write!(f, "{ch}")?,
+        }
+    }
+    Ok(())
+}
+
+/// Parse an integer literal, into an `Element::Integer` if it fits or an
+/// `Element::Long` if it doesn't.
+///
+/// As well as decimal, we take hex, binary and octal with a `0x`, `0b` or
+/// `0o` prefix. Any of them can start with `-`, and can have `_` between
+/// the digits. An `L` on the end makes it a Long, whatever its size. Gives
+/// `None` if this doesn't look like an integer, or `Error::SyntaxError` if
+/// it does but it's malformed or won't fit in an `i64`.
+fn parse_integer(s: &str) -> Result<Option<Element<'_>>, Error> {
+    let (literal, long) = match s.strip_suffix('L') {
+        Some(literal) => (literal, true),
+        None => (s, false),
+    };
+    let unsigned = literal.strip_prefix('-').unwrap_or(literal);
+    let (radix, digits) = if let Some(digits) = unsigned.strip_prefix("0x") {
+        (16, digits)
+    } else if let Some(digits) = unsigned.strip_prefix("0b") {
+        (2, digits)
+    } else if let Some(digits) = unsigned.strip_prefix("0o") {
+        (8, digits)
+    } else if unsigned.starts_with(|ch: char| ch.is_ascii_digit())
+        && unsigned.chars().all(|ch| ch.is_ascii_digit() || ch == '_')
+    {
+        (10, unsigned)
+    } else {
+        // Might be a float, or a name
+        return Ok(None);
+    };
+    if digits.is_empty() {
+        return Err(Error::SyntaxError(char_offset(s, digits)));
+    }
+    let mut magnitude: u64 = 0;
+    for (idx, ch) in digits.char_indices() {
+        if ch == '_' {
+            continue;
+        }
+        let digit = ch
+            .to_digit(radix)
+            .ok_or_else(|| Error::SyntaxError(char_offset(s, &digits[idx..])))?;
+        magnitude = magnitude
+            .checked_mul(u64::from(radix))
+            .and_then(|m| m.checked_add(u64::from(digit)))
+            .ok_or_else(|| Error::SyntaxError(char_offset(s, digits)))?;
+    }
+    let value = if s.starts_with('-') {
+        -i128::from(magnitude)
+    } else {
+        i128::from(magnitude)
+    };
+    let value = i64::try_from(value).map_err(|_| Error::SyntaxError(char_offset(s, digits)))?;
+    match i32::try_from(value) {
+        Ok(value) if !long => Ok(Some(Element::Integer(value))),
+        _ => Ok(Some(Element::Long(value))),
+    }
+}
+
+/// Parse a floating point literal.
+///
+/// Only accepts things that start like a number, so that words like `inf` and
+/// `NaN` aren't mistaken for floats. Anything which isn't finite, like `-inf`
+/// or `1e99`, is turned down too.
+fn parse_float(s: &str) -> Option<f32> {
+    let unsigned = s.strip_prefix('-').unwrap_or(s);
+    if unsigned.starts_with(|ch: char| ch.is_ascii_digit() || ch == '.') {
+        s.parse::<f32>().ok().filter(|f| f.is_finite())
+    } else {
+        None
+    }
+}
+
+/// Work out the CRC32 (as used by Ethernet and zip files) of some bytes,
+/// which are given in parts.
+///
+/// This works a bit at a time, so it doesn't need a lookup table.
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+impl<'a> Params<'a> {
+    /// A function which takes no parameters.
+    pub const NONE: Params<'static> = Params {
+        repr: ParamsRepr::Source(""),
+    };
+
+    /// Parse a comma-separated list of parameter names, like `a, b`.
+    pub fn new(list: &'a str) -> Result<Params<'a>, Error> {
+        let list = list.trim();
+        // A trailing comma would otherwise go unnoticed
+        if list.ends_with(',') {
+            return Err(Error::InvalidName);
+        }
+        let params = Params {
+            repr: ParamsRepr::Source(list),
+        };
+        let mut count = 0;
+        for name in params.iter() {
+            validate_name(name, NameStyle::Unicode)?;
+            if name.len() > 255 {
+                return Err(Error::NameTooLong);
+            }
+            count += 1;
+        }
+        // Every parameter needs a variable to live in
+        if count > MAX_VARIABLES {
+            return Err(Error::TooManyVariables);
+        }
+        Ok(params)
+    }
+
+    /// How many parameters are there?
+    pub fn len(&self) -> usize {
+        match self.repr {
+            ParamsRepr::Encoded { count, .. } => usize::from(count),
+            ParamsRepr::Source(_) => self.iter().count(),
+        }
+    }
+
+    /// Are there no parameters?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Go through the parameter names, in order.
+    pub fn iter(&self) -> ParamsIter<'a> {
+        ParamsIter { repr: self.repr }
+    }
+}
+
+impl<'a> PartialEq for Params<'a> {
+    fn eq(&self, other: &Params) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<'a> core::fmt::Debug for Params<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a> Iterator for ParamsIter<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.repr {
+            ParamsRepr::Encoded { count, data } => {
+                if *count == 0 {
+                    return None;
+                }
+                let (len, rest) = data.split_first()?;
+                let len = usize::from(*len);
+                let name = core::str::from_utf8(rest.get(0..len)?).ok()?;
+                *count -= 1;
+                *data = &rest[len..];
+                Some(name)
+            }
+            ParamsRepr::Source(list) => {
+                if list.is_empty() {
+                    return None;
+                }
+                let (name, rest) = list.split_once(',').unwrap_or((list, ""));
+                *list = rest;
+                Some(name.trim())
+            }
+        }
+    }
+}
+
+impl OpcodeCounts {
+    /// How many times the given opcode ran.
+    pub fn get(&self, opcode: u8) -> u64 {
+        self.counts[usize::from(opcode)]
+    }
+
+    /// How many opcodes ran, in total.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Step through each opcode which ran at least once, with how many times
+    /// it ran, in opcode order.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u64)> + '_ {
+        (0..=u8::MAX)
+            .zip(self.counts.iter().copied())
+            .filter(|(_, count)| *count != 0)
+    }
+}
+
+impl Default for OpcodeCounts {
+    fn default() -> OpcodeCounts {
+        OpcodeCounts { counts: [0; 256] }
+    }
+}
+
+impl<'a> VarScope<'a> {
+    const EMPTY: Value<'static> = Value::Nil;
+    const NOT_STASHED: Option<Value<'static>> = None;
+
+    /// Create a new, empty, set of variables.
+    pub fn new() -> VarScope<'a> {
+        VarScope {
+            names: [""; MAX_VARIABLES],
+            values: [Self::EMPTY; MAX_VARIABLES],
+            used: 0,
+            stash: [Self::NOT_STASHED; MAX_CHAIN],
+        }
+    }
+
+    /// Get the value of the named variable, if it has been set.
+    pub fn get(&self, name: &str) -> Option<&Value<'a>> {
+        self.names[0..self.used]
+            .iter()
+            .position(|n| *n == name)
+            .map(|idx| &self.values[idx])
+    }
+
+    /// Replace the value of the named variable with the result of `op`. The
+    /// variable must already be set.
+    fn update<F>(&mut self, name: &str, op: F) -> Result<(), Error>
+    where
+        F: FnOnce(Value<'a>) -> Result<Value<'a>, Error>,
+    {
+        let idx = self.names[0..self.used]
+            .iter()
+            .position(|n| *n == name)
+            .ok_or(Error::UndefinedVariable)?;
+        self.values[idx] = op(self.values[idx].clone())?;
+        Ok(())
+    }
+
+    /// Set the value of the named variable, creating it if required.
+    ///
+    /// Gives `Error::TooManyVariables` if there are already
+    /// `MAX_VARIABLES` variables and this is a new one.
+    pub fn set(&mut self, name: &'a str, value: Value<'a>) -> Result<(), Error> {
+        let idx = match self.names[0..self.used].iter().position(|n| *n == name) {
+            Some(idx) => idx,
+            None if self.used < MAX_VARIABLES => {
+                self.names[self.used] = name;
+                self.used += 1;
+                self.used - 1
+            }
+            None => return Err(Error::TooManyVariables),
+        };
+        self.values[idx] = value;
+        Ok(())
+    }
+
+    /// Remove all the variables.
+    pub fn clear(&mut self) {
+        for value in &mut self.values[0..self.used] {
+            *value = Self::EMPTY;
+        }
+        self.used = 0;
+        self.clear_stash();
+    }
+
+    /// Keep a value in one of the `Stash` slots.
+    ///
+    /// Gives `Error::TooManyVariables` if there's no such slot.
+    fn stash(&mut self, slot: u8, value: Value<'a>) -> Result<(), Error> {
+        let kept = self
+            .stash
+            .get_mut(usize::from(slot))
+            .ok_or(Error::TooManyVariables)?;
+        *kept = Some(value);
+        Ok(())
+    }
+
+    /// Get the value kept in one of the `Stash` slots.
+    ///
+    /// Gives `Error::UndefinedVariable` if nothing is kept there.
+    fn stashed(&self, slot: u8) -> Result<Value<'a>, Error> {
+        self.stash
+            .get(usize::from(slot))
+            .cloned()
+            .flatten()
+            .ok_or(Error::UndefinedVariable)
+    }
+
+    /// Throw away everything kept in the `Stash` slots.
+    fn clear_stash(&mut self) {
+        for kept in &mut self.stash {
+            *kept = None;
+        }
+    }
+}
+
+impl<'a> Default for VarScope<'a> {
+    fn default() -> VarScope<'a> {
+        VarScope::new()
+    }
+}
+
+impl<T> EvalStack<T> {
+    /// Create a new, empty, stack.
+    fn new() -> EvalStack<T> {
+        EvalStack {
+            #[cfg(feature = "alloc")]
+            items: Vec::new(),
+            #[cfg(not(feature = "alloc"))]
+            items: core::array::from_fn(|_| None),
+            #[cfg(not(feature = "alloc"))]
+            used: 0,
+        }
+    }
+
+    /// Put an item on top of the stack.
+    fn push(&mut self, item: T) -> Result<(), Error> {
+        #[cfg(feature = "alloc")]
+        self.items.push(item);
+        #[cfg(not(feature = "alloc"))]
+        {
+            let slot = self
+                .items
+                .get_mut(self.used)
+                .ok_or(Error::ExpressionTooDeep)?;
+            *slot = Some(item);
+            self.used += 1;
+        }
+        Ok(())
+    }
+
+    /// Take the item from the top of the stack.
+    fn pop(&mut self) -> Option<T> {
+        #[cfg(feature = "alloc")]
+        return self.items.pop();
+        #[cfg(not(feature = "alloc"))]
+        {
+            self.used = self.used.checked_sub(1)?;
+            self.items[self.used].take()
+        }
+    }
+
+    /// Look at the item on top of the stack.
+    fn last_mut(&mut self) -> Option<&mut T> {
+        #[cfg(feature = "alloc")]
+        return self.items.last_mut();
+        #[cfg(not(feature = "alloc"))]
+        {
+            let top = self.used.checked_sub(1)?;
+            self.items[top].as_mut()
+        }
+    }
+}
+
+impl<'a> Element<'a> {
+    /// How many expressions follow this element as its operands, or `None`
+    /// if this element can't appear in an expression.
+    fn operand_count(&self) -> Option<usize> {
+        match self {
+            Element::Integer(_)
+            | Element::Long(_)
+            | Element::Float(_)
+            | Element::StringLiteral(_)
+            | Element::Boolean(_)
+            | Element::Variable(_)
+            | Element::Stashed(_)
+            | Element::Nil => Some(0),
+            Element::Negate
+            | Element::Not
+            | Element::Len
+            | Element::ToFloat
+            | Element::ToInt
+            | Element::ToStr
+            | Element::ParseInt
+            | Element::ParseFloat
+            | Element::Sort
+            | Element::Reverse
+            | Element::Map(_)
+            | Element::Filter(_)
+            | Element::ToUpper
+            | Element::ToLower
+            | Element::Abs
+            | Element::Sqrt
+            | Element::Sin
+            | Element::Cos => Some(1),
+            Element::Call(_, num_args) | Element::Vector(num_args) => Some(usize::from(*num_args)),
+            Element::Add
+            | Element::Subtract
+            | Element::Multiply
+            | Element::Divide
+            | Element::BitAnd
+            | Element::BitOr
+            | Element::BitXor
+            | Element::ShiftLeft
+            | Element::ShiftRight
+            | Element::Equal
+            | Element::NotEqual
+            | Element::Less
+            | Element::LessEqual
+            | Element::Greater
+            | Element::GreaterEqual
+            | Element::And
+            | Element::Or
+            | Element::Index
+            | Element::Modulo
+            | Element::Pow
+            | Element::Push
+            | Element::EqualsIgnoreCase
+            | Element::Min
+            | Element::Max => Some(2),
+            Element::Select | Element::Substring => Some(3),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Value<'a> {
+    /// What kind of value is this?
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            Value::StringLiteral(_) => ValueKind::String,
+            #[cfg(feature = "alloc")]
+            Value::String(_) => ValueKind::String,
+            #[cfg(feature = "alloc")]
+            Value::Vector(_) => ValueKind::Vector,
+            Value::Integer(_) => ValueKind::Integer,
+            Value::Long(_) => ValueKind::Long,
+            Value::Float(_) => ValueKind::Float,
+            Value::Boolean(_) => ValueKind::Boolean,
+            Value::Nil => ValueKind::Nil,
+        }
+    }
+
+    /// Make a Vector holding the given values.
+    #[cfg(feature = "alloc")]
+    pub fn vector<I>(items: I) -> Value<'a>
+    where
+        I: IntoIterator<Item = Value<'a>>,
+    {
+        Value::Vector(items.into_iter().collect())
+    }
+
+    /// Get the number in an Integer.
+    pub fn as_integer(&self) -> Option<i32> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// Get the number in a Float.
+    pub fn as_float(&self) -> Option<f32> {
+        match self {
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    /// Get the contents of a Boolean.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// Get the contents of either kind of string.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::StringLiteral(s) => Some(s),
+            #[cfg(feature = "alloc")]
+            Value::String(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Is this value true or false?
+    ///
+    /// False, zero, empty strings, empty Vectors and Nil are false, and
+    /// everything else is true.
+    pub fn truthiness(&self) -> bool {
+        match self {
+            Value::StringLiteral(s) => !s.is_empty(),
+            #[cfg(feature = "alloc")]
+            Value::String(s) => !s.is_empty(),
+            #[cfg(feature = "alloc")]
+            Value::Vector(items) => !items.is_empty(),
+            Value::Integer(i) => *i != 0,
+            Value::Long(i) => *i != 0,
+            Value::Float(f) => *f != 0.0,
+            Value::Boolean(b) => *b,
+            Value::Nil => false,
+        }
+    }
+
+    /// Produce the error for an operation on these two values.
+    fn mismatch(&self, other: &Value) -> Error {
+        Error::TypeMismatch(self.kind(), other.kind())
+    }
+
+    /// If one value is a Long and the other is a Long or an Integer, get
+    /// them both as Longs.
+    fn as_longs(&self, other: &Value) -> Option<(i64, i64)> {
+        match (self, other) {
+            (Value::Long(a), Value::Long(b)) => Some((*a, *b)),
+            (Value::Long(a), Value::Integer(b)) => Some((*a, i64::from(*b))),
+            (Value::Integer(a), Value::Long(b)) => Some((i64::from(*a), *b)),
+            _ => None,
+        }
+    }
+
+    /// Wrap the result of some Long arithmetic, which is `None` if it
+    /// overflowed.
+    fn long_result(result: Option<i64>) -> Result<Value<'a>, Error> {
+        result.map(Value::Long).ok_or(Error::ArithmeticOverflow)
+    }
+
+    /// Add two values together.
+    ///
+    /// Integers are summed, and strings are concatenated. An Integer and a
+    /// Long (as with all the arithmetic) make a Long.
+    fn add(self, other: Value<'a>) -> Result<Value<'a>, Error> {
+        if let Some((a, b)) = self.as_longs(&other) {
+            return Self::long_result(a.checked_add(b));
+        }
+        match (&self, &other) {
+            (Value::Integer(a), Value::Integer(b)) => a
+                .checked_add(*b)
+                .map(Value::Integer)
+                .ok_or(Error::ArithmeticOverflow),
+            (Value::Float(a), Value::Float(b)) => Ok(Value::Float(a + b)),
+            #[cfg(feature = "alloc")]
+            _ => match (self.as_str(), other.as_str()) {
+                (Some(a), Some(b)) => {
+                    let mut result = String::with_capacity(a.len() + b.len());
+                    result.push_str(a);
+                    result.push_str(b);
+                    Ok(Value::String(result))
+                }
+                _ => Err(self.mismatch(&other)),
+            },
+            #[cfg(not(feature = "alloc"))]
+            _ => Err(self.mismatch(&other)),
+        }
+    }
+
+    /// Subtract one value from another.
+    fn subtract(self, other: Value<'a>) -> Result<Value<'a>, Error> {
+        if let Some((a, b)) = self.as_longs(&other) {
+            return Self::long_result(a.checked_sub(b));
+        }
+        match (&self, &other) {
+            (Value::Integer(a), Value::Integer(b)) => a
+                .checked_sub(*b)
+                .map(Value::Integer)
+                .ok_or(Error::ArithmeticOverflow),
+            (Value::Float(a), Value::Float(b)) => Ok(Value::Float(a - b)),
+            _ => Err(self.mismatch(&other)),
+        }
+    }
+
+    /// Multiply two values together.
+    ///
+    /// A String multiplied by an Integer `n` is the String repeated `n`
+    /// times (or empty, if `n` isn't positive).
+    fn multiply(self, other: Value<'a>) -> Result<Value<'a>, Error> {
+        if let Some((a, b)) = self.as_longs(&other) {
+            return Self::long_result(a.checked_mul(b));
+        }
+        match (&self, &other) {
+            #[cfg(feature = "alloc")]
+            (Value::StringLiteral(_) | Value::String(_), Value::Integer(n)) => {
+                let s = self.as_str().unwrap_or_default();
+                let count = (*n).max(0) as usize;
+                match s.len().checked_mul(count) {
+                    Some(len) if len <= MAX_STRING_LENGTH => Ok(Value::String(s.repeat(count))),
+                    _ => Err(Error::AllocationTooLarge),
+                }
+            }
+            (Value::Integer(a), Value::Integer(b)) => a
+                .checked_mul(*b)
+                .map(Value::Integer)
+                .ok_or(Error::ArithmeticOverflow),
+            (Value::Float(a), Value::Float(b)) => Ok(Value::Float(a * b)),
+            _ => Err(self.mismatch(&other)),
+        }
+    }
+
+    /// Divide one value by another.
+    ///
+    /// Unlike Integers, dividing a Float by zero is not an error - you get
+    /// an infinity (or NaN), as per IEEE-754.
+    fn divide(self, other: Value<'a>) -> Result<Value<'a>, Error> {
+        if let Some((a, b)) = self.as_longs(&other) {
+            if b == 0 {
+                return Err(Error::DivideByZero);
+            }
+            return Self::long_result(a.checked_div(b));
+        }
+        match (&self, &other) {
+            (Value::Integer(_), Value::Integer(0)) => Err(Error::DivideByZero),
+            // The only other failure is i32::MIN / -1
+            (Value::Integer(a), Value::Integer(b)) => a
+                .checked_div(*b)
+                .map(Value::Integer)
+                .ok_or(Error::ArithmeticOverflow),
+            (Value::Float(a), Value::Float(b)) => Ok(Value::Float(a / b)),
+            _ => Err(self.mismatch(&other)),
+        }
+    }
+
+    /// Find the remainder after dividing one Integer by another.
+    ///
+    /// The result has the same sign as the first Integer, as with Rust's `%`.
+    fn remainder(self, other: Value<'a>) -> Result<Value<'a>, Error> {
+        if let Some((a, b)) = self.as_longs(&other) {
+            if b == 0 {
+                return Err(Error::DivideByZero);
+            }
+            return Self::long_result(a.checked_rem(b));
+        }
+        match (&self, &other) {
+            (Value::Integer(_), Value::Integer(0)) => Err(Error::DivideByZero),
+            // The only other failure is i32::MIN % -1
+            (Value::Integer(a), Value::Integer(b)) => a
+                .checked_rem(*b)
+                .map(Value::Integer)
+                .ok_or(Error::ArithmeticOverflow),
+            _ => Err(self.mismatch(&other)),
+        }
+    }
+
+    /// Raise one value to the power of another.
+    fn power(self, other: Value<'a>) -> Result<Value<'a>, Error> {
+        match (&self, &other) {
+            (Value::Integer(a), Value::Integer(b)) => {
+                let exponent = u32::try_from(*b).map_err(|_| Error::NegativeExponent)?;
+                a.checked_pow(exponent)
+                    .map(Value::Integer)
+                    .ok_or(Error::ArithmeticOverflow)
+            }
+            (Value::Long(a), Value::Integer(b)) => {
+                let exponent = u32::try_from(*b).map_err(|_| Error::NegativeExponent)?;
+                Self::long_result(a.checked_pow(exponent))
+            }
+            #[cfg(feature = "std")]
+            (Value::Float(a), Value::Float(b)) => Ok(Value::Float(a.powf(*b))),
+            _ => Err(self.mismatch(&other)),
+        }
+    }
+
+    /// Convert an Integer or a Long to a Float.
+    ///
+    /// Large numbers may lose some precision.
+    fn to_float(&self) -> Result<Value<'a>, Error> {
+        match self {
+            Value::Integer(a) => Ok(Value::Float(*a as f32)),
+            Value::Long(a) => Ok(Value::Float(*a as f32)),
+            _ => Err(self.mismatch(self)),
+        }
+    }
+
+    /// Convert a Float to an Integer, rounding towards zero.
+    ///
+    /// Floats which don't fit in an Integer (including infinities and NaN)
+    /// produce `Error::ArithmeticOverflow`.
+    fn to_int(&self) -> Result<Value<'a>, Error> {
+        match self {
+            // NaN fails both of these tests
+            Value::Float(a) if *a >= i32::MIN as f32 && *a < -(i32::MIN as f32) => {
+                Ok(Value::Integer(*a as i32))
+            }
+            Value::Float(_) => Err(Error::ArithmeticOverflow),
+            _ => Err(self.mismatch(self)),
+        }
+    }
+
+    /// Convert any value to a String, the same way `Display` does.
+    ///
+    /// This only fails if there is no allocator.
+    fn to_str(&self) -> Result<Value<'a>, Error> {
+        #[cfg(feature = "alloc")]
+        return Ok(Value::String(self.to_string()));
+        #[cfg(not(feature = "alloc"))]
+        return Err(Error::NoAllocator);
+    }
+
+    /// Parse a String as an integer, the same way integer literals are
+    /// parsed. Numbers too big for an Integer produce a Long.
+    ///
+    /// Whitespace around the number is ignored.
+    fn parse_int(&self) -> Result<Value<'a>, Error> {
+        let s = self.as_str().ok_or_else(|| self.mismatch(self))?;
+        match parse_integer(s.trim()) {
+            Ok(Some(Element::Integer(i))) => Ok(Value::Integer(i)),
+            Ok(Some(Element::Long(i))) => Ok(Value::Long(i)),
+            _ => Err(Error::ParseValueError),
+        }
+    }
+
+    /// Parse a String as a Float.
+    ///
+    /// Whitespace around the number is ignored.
+    fn parse_float(&self) -> Result<Value<'a>, Error> {
+        let s = self.as_str().ok_or_else(|| self.mismatch(self))?;
+        parse_float(s.trim())
+            .map(Value::Float)
+            .ok_or(Error::ParseValueError)
+    }
+
+    /// Convert the ASCII letters in a String to upper case. Anything else
+    /// is left alone.
+    fn to_upper(&self) -> Result<Value<'a>, Error> {
+        match self.as_str() {
+            #[cfg(feature = "alloc")]
+            Some(s) => Ok(Value::String(s.to_ascii_uppercase())),
+            #[cfg(not(feature = "alloc"))]
+            Some(_) => Err(Error::NoAllocator),
+            None => Err(self.mismatch(self)),
+        }
+    }
+
+    /// Convert the ASCII letters in a String to lower case. Anything else
+    /// is left alone.
+    fn to_lower(&self) -> Result<Value<'a>, Error> {
+        match self.as_str() {
+            #[cfg(feature = "alloc")]
+            Some(s) => Ok(Value::String(s.to_ascii_lowercase())),
+            #[cfg(not(feature = "alloc"))]
+            Some(_) => Err(Error::NoAllocator),
+            None => Err(self.mismatch(self)),
+        }
+    }
+
+    /// Check whether two Strings are equal, ignoring ASCII case.
+    fn equals_ignore_case(&self, other: &Value<'a>) -> Result<Value<'a>, Error> {
+        match (self.as_str(), other.as_str()) {
+            (Some(a), Some(b)) => Ok(Value::Boolean(a.eq_ignore_ascii_case(b))),
+            _ => Err(self.mismatch(other)),
+        }
+    }
+
+    /// Apply a bitwise operation to two Integers.
+    fn bitwise(self, other: Value<'a>, op: fn(i32, i32) -> i32) -> Result<Value<'a>, Error> {
+        match (&self, &other) {
+            (Value::Integer(a), Value::Integer(b)) => Ok(Value::Integer(op(*a, *b))),
+            _ => Err(self.mismatch(&other)),
+        }
+    }
+
+    /// Shift an Integer by another Integer.
+    ///
+    /// The shift function returns `None` if the shift is out of range.
+    fn shift(self, other: Value<'a>, op: fn(i32, u32) -> Option<i32>) -> Result<Value<'a>, Error> {
+        match (&self, &other) {
+            (Value::Integer(a), Value::Integer(b)) => u32::try_from(*b)
+                .ok()
+                .and_then(|b| op(*a, b))
+                .map(Value::Integer)
+                .ok_or(Error::InvalidShift),
+            _ => Err(self.mismatch(&other)),
+        }
+    }
+
+    /// Compare two values, producing a Boolean which says whether the test
+    /// likes the ordering.
+    ///
+    /// The ordering is `None` if the values are unordered (i.e. a NaN is
+    /// involved).
+    fn compare(
+        self,
+        other: Value<'a>,
+        test: fn(Option<Ordering>) -> bool,
+    ) -> Result<Value<'a>, Error> {
+        let ordering = match (&self, &other) {
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            (Value::Long(_), _) | (_, Value::Long(_)) => match self.as_longs(&other) {
+                Some((a, b)) => Some(a.cmp(&b)),
+                None => return Err(self.mismatch(&other)),
+            },
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::Boolean(a), Value::Boolean(b)) => Some(a.cmp(b)),
+            _ => match (self.as_str(), other.as_str()) {
+                (Some(a), Some(b)) => Some(a.cmp(b)),
+                _ => return Err(self.mismatch(&other)),
+            },
+        };
+        Ok(Value::Boolean(test(ordering)))
+    }
+
+    /// Check whether two values are equal (or not equal, if `want_equal` is
+    /// false), producing a Boolean.
+    ///
+    /// Nil is equal to Nil and not equal to anything else. Other values are
+    /// compared as with `compare`.
+    fn equality(self, other: Value<'a>, want_equal: bool) -> Result<Value<'a>, Error> {
+        match (&self, &other) {
+            (Value::Nil, Value::Nil) => Ok(Value::Boolean(want_equal)),
+            (Value::Nil, _) | (_, Value::Nil) => Ok(Value::Boolean(!want_equal)),
+            _ if want_equal => self.compare(other, |o| o == Some(Ordering::Equal)),
+            _ => self.compare(other, |o| o != Some(Ordering::Equal)),
+        }
+    }
+
+    /// Is this value true, when used as a condition?
+    ///
+    /// Booleans are used as they are, and non-zero Integers and Longs are
+    /// true. Other kinds of value are not allowed.
+    fn is_true(&self) -> Result<bool, Error> {
+        match self {
+            Value::Boolean(_) | Value::Integer(_) | Value::Long(_) => Ok(self.truthiness()),
+            _ => Err(self.mismatch(self)),
+        }
+    }
+
+    /// Get an item from a Vector.
+    ///
+    /// Negative positions count back from the end, so -1 is the last item.
+    fn index(self, other: Value<'a>) -> Result<Value<'a>, Error> {
+        match (self, &other) {
+            #[cfg(feature = "alloc")]
+            (Value::Vector(mut items), Value::Integer(i)) => {
+                let position = if *i < 0 {
+                    items.len().checked_sub(i.unsigned_abs() as usize)
+                } else {
+                    Some(*i as usize)
+                };
+                match position {
+                    Some(position) if position < items.len() => Ok(items.swap_remove(position)),
+                    _ => Err(Error::IndexOutOfBounds),
+                }
+            }
+            (this, _) => Err(this.mismatch(&other)),
+        }
+    }
+
+    /// Get part of a String, from byte `start` up to (but not including) byte
+    /// `end`. Negative positions count back from the end of the String.
+    fn substring(self, start: Value<'a>, end: Value<'a>) -> Result<Value<'a>, Error> {
+        match self.substring_str(start, end) {
+            #[cfg(feature = "alloc")]
+            Ok(part) => Ok(Value::String(String::from(part))),
+            #[cfg(not(feature = "alloc"))]
+            Ok(_) => Err(Error::NoAllocator),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Find the part of a String that `substring` gives, without copying it.
+    fn substring_str(&self, start: Value<'a>, end: Value<'a>) -> Result<&str, Error> {
+        let (Value::Integer(start), Value::Integer(end)) = (&start, &end) else {
+            return Err(start.mismatch(&end));
+        };
+        let s = self.as_str().ok_or_else(|| self.mismatch(self))?;
+        let position = |i: i32| {
+            if i < 0 {
+                s.len().checked_sub(i.unsigned_abs() as usize)
+            } else {
+                Some(i as usize)
+            }
+        };
+        let (Some(start), Some(end)) = (position(*start), position(*end)) else {
+            return Err(Error::IndexOutOfBounds);
+        };
+        if start > end || end > s.len() {
+            return Err(Error::IndexOutOfBounds);
+        }
+        s.get(start..end).ok_or(Error::NotCharBoundary)
+    }
+
+    /// Add a value to the end of a Vector.
+    fn push(self, other: Value<'a>) -> Result<Value<'a>, Error> {
+        match self {
+            #[cfg(feature = "alloc")]
+            Value::Vector(mut items) => {
+                items.push(other);
+                Ok(Value::Vector(items))
+            }
+            this => Err(this.mismatch(&other)),
+        }
+    }
+
+    /// Put the items in a Vector in order.
+    ///
+    /// The items must all be the same kind of thing (all numbers, all
+    /// Strings, and so on), and mustn't include NaN, otherwise we give
+    /// `Error::TypeMismatch`.
+    fn sort(self) -> Result<Value<'a>, Error> {
+        match self {
+            #[cfg(feature = "alloc")]
+            Value::Vector(mut items) => {
+                if let Some(first) = items.first() {
+                    let kind = first.ordering_key().0;
+                    // Checking these first means the sort always sees a
+                    // total order
+                    for item in items.iter() {
+                        if item.ordering_key().0 != kind || item.partial_cmp(item).is_none() {
+                            return Err(first.mismatch(item));
+                        }
+                    }
+                }
+                items.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+                Ok(Value::Vector(items))
+            }
+            this => Err(this.mismatch(&this)),
+        }
+    }
+
+    /// Put the items in a Vector in reverse order.
+    fn reverse(self) -> Result<Value<'a>, Error> {
+        match self {
+            #[cfg(feature = "alloc")]
+            Value::Vector(mut items) => {
+                items.reverse();
+                Ok(Value::Vector(items))
+            }
+            this => Err(this.mismatch(&this)),
+        }
+    }
+
+    /// Get the length of a String (in bytes) or a Vector (in items).
+    fn length(&self) -> Result<Value<'a>, Error> {
+        let len = match self {
+            #[cfg(feature = "alloc")]
+            Value::Vector(items) => items.len(),
+            _ => self.as_str().ok_or_else(|| self.mismatch(self))?.len(),
+        };
+        i32::try_from(len)
+            .map(Value::Integer)
+            .map_err(|_| Error::ArithmeticOverflow)
+    }
+
+    /// Where this value goes when values are put in order. The first number
+    /// puts kinds of value in order, and the second separates the variants
+    /// within a kind.
+    fn ordering_key(&self) -> (u8, u8) {
+        match self {
+            Value::Nil => (0, 0),
+            Value::Boolean(_) => (1, 0),
+            Value::Integer(_) => (2, 0),
+            Value::Long(_) => (2, 1),
+            Value::Float(_) => (2, 2),
+            Value::StringLiteral(_) => (3, 0),
+            #[cfg(feature = "alloc")]
+            Value::String(_) => (3, 1),
+            #[cfg(feature = "alloc")]
+            Value::Vector(_) => (4, 0),
+        }
+    }
+
+    /// Compare two numbers of any kind by their value. Integers and Longs
+    /// are compared exactly, but comparing with a Float goes via `f64`.
+    fn compare_numbers(&self, other: &Value) -> Option<Ordering> {
+        fn whole(value: &Value) -> Option<i64> {
+            match value {
+                Value::Integer(i) => Some(i64::from(*i)),
+                Value::Long(i) => Some(*i),
+                _ => None,
+            }
+        }
+        fn real(value: &Value) -> Option<f64> {
+            match value {
+                Value::Float(f) => Some(f64::from(*f)),
+                _ => whole(value).map(|i| i as f64),
+            }
+        }
+        match (whole(self), whole(other)) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => real(self)?.partial_cmp(&real(other)?),
+        }
+    }
+
+    /// Get the contents of a Boolean, for the logical operations.
+    ///
+    /// Unlike `is_true`, no other kinds of value are allowed.
+    fn as_boolean(&self) -> Result<bool, Error> {
+        self.as_bool().ok_or_else(|| self.mismatch(self))
+    }
+
+    /// Negate a value.
+    fn negate(self) -> Result<Value<'a>, Error> {
+        match self {
+            Value::Long(a) => Self::long_result(a.checked_neg()),
+            Value::Integer(a) => a
+                .checked_neg()
+                .map(Value::Integer)
+                .ok_or(Error::ArithmeticOverflow),
+            Value::Float(a) => Ok(Value::Float(-a)),
+            _ => Err(self.mismatch(&self)),
+        }
+    }
+
+    /// Get the square root of a Float.
+    fn sqrt(&self) -> Result<Value<'a>, Error> {
+        match self {
+            #[cfg(feature = "std")]
+            Value::Float(a) => Ok(Value::Float(a.sqrt())),
+            #[cfg(all(feature = "math", not(feature = "std")))]
+            Value::Float(a) => Ok(Value::Float(libm::sqrtf(*a))),
+            _ => Err(self.mismatch(self)),
+        }
+    }
+
+    /// Get the sine of a Float, in radians.
+    fn sin(&self) -> Result<Value<'a>, Error> {
+        match self {
+            #[cfg(feature = "std")]
+            Value::Float(a) => Ok(Value::Float(a.sin())),
+            #[cfg(all(feature = "math", not(feature = "std")))]
+            Value::Float(a) => Ok(Value::Float(libm::sinf(*a))),
+            _ => Err(self.mismatch(self)),
+        }
+    }
+
+    /// Get the cosine of a Float, in radians.
+    fn cos(&self) -> Result<Value<'a>, Error> {
+        match self {
+            #[cfg(feature = "std")]
+            Value::Float(a) => Ok(Value::Float(a.cos())),
+            #[cfg(all(feature = "math", not(feature = "std")))]
+            Value::Float(a) => Ok(Value::Float(libm::cosf(*a))),
+            _ => Err(self.mismatch(self)),
+        }
+    }
+
+    /// Get the absolute value of a number.
+    fn abs(&self) -> Result<Value<'a>, Error> {
+        match self {
+            Value::Long(a) => Self::long_result(a.checked_abs()),
+            Value::Integer(a) => a
+                .checked_abs()
+                .map(Value::Integer)
+                .ok_or(Error::ArithmeticOverflow),
+            Value::Float(a) => Ok(Value::Float(a.abs())),
+            _ => Err(self.mismatch(self)),
+        }
+    }
+
+    /// Pick the larger (if `want_max`) or the smaller of two numbers of the
+    /// same type.
+    fn min_max(self, other: Value<'a>, want_max: bool) -> Result<Value<'a>, Error> {
+        let pick = |ordering: Ordering| (ordering == Ordering::Greater) == want_max;
+        match (&self, &other) {
+            (Value::Integer(a), Value::Integer(b)) if pick(a.cmp(b)) => Ok(self),
+            (Value::Long(a), Value::Long(b)) if pick(a.cmp(b)) => Ok(self),
+            (Value::Integer(_), Value::Integer(_)) | (Value::Long(_), Value::Long(_)) => Ok(other),
+            (Value::Float(a), Value::Float(b)) if want_max => Ok(Value::Float(a.max(*b))),
+            (Value::Float(a), Value::Float(b)) => Ok(Value::Float(a.min(*b))),
+            _ => Err(self.mismatch(&other)),
+        }
+    }
+}
+
+/// Values are shown the way a script would print them - strings don't get
+/// quotes, and Vectors are shown as `[a, b, c]`.
+impl<'a> core::fmt::Display for Value<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Value::StringLiteral(s) => write!(f, "{}", s),
+            #[cfg(feature = "alloc")]
+            Value::String(s) => write!(f, "{}", s),
+            #[cfg(feature = "alloc")]
+            Value::Vector(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Long(n) => write!(f, "{}", n),
+            Value::Float(n) => write!(f, "{:?}", n),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Nil => write!(f, "nil"),
+        }
+    }
+}
+
+/// Values are ordered by kind first: Nil, then Booleans, then numbers, then
+/// Strings, then Vectors. Numbers are ordered by value (whether they are
+/// Integers, Longs or Floats), Strings by their bytes, and Vectors item by
+/// item.
+///
+/// Values which only differ in their variant, like `Integer(1)` and
+/// `Long(1)`, aren't `==`, so they are ordered by variant. NaN can't be
+/// ordered.
+impl<'a> PartialOrd for Value<'a> {
+    fn partial_cmp(&self, other: &Value<'a>) -> Option<Ordering> {
+        let (kind, variant) = self.ordering_key();
+        let (other_kind, other_variant) = other.ordering_key();
+        if kind != other_kind {
+            return Some(kind.cmp(&other_kind));
+        }
+        let ordering = match (self, other) {
+            (Value::Boolean(a), Value::Boolean(b)) => a.cmp(b),
+            #[cfg(feature = "alloc")]
+            (Value::Vector(a), Value::Vector(b)) => a.iter().partial_cmp(b.iter())?,
+            _ => match (self.as_str(), other.as_str()) {
+                (Some(a), Some(b)) => a.cmp(b),
+                _ if kind == 0 => Ordering::Equal,
+                _ => self.compare_numbers(other)?,
+            },
+        };
+        Some(ordering.then(variant.cmp(&other_variant)))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'a> serde::Serialize for Value<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        // The variant indices must match the order of `OwnedValue`
+        match self {
+            Value::StringLiteral(s) => {
+                serializer.serialize_newtype_variant("Value", 0, "String", s)
+            }
+            Value::String(s) => serializer.serialize_newtype_variant("Value", 0, "String", s),
+            Value::Vector(v) => serializer.serialize_newtype_variant("Value", 1, "Vector", v),
+            Value::Integer(i) => serializer.serialize_newtype_variant("Value", 2, "Integer", i),
+            Value::Float(f) => serializer.serialize_newtype_variant("Value", 3, "Float", f),
+            Value::Boolean(b) => serializer.serialize_newtype_variant("Value", 4, "Boolean", b),
+            Value::Nil => serializer.serialize_unit_variant("Value", 5, "Nil"),
+            Value::Long(i) => serializer.serialize_newtype_variant("Value", 6, "Long", i),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, 'a> serde::Deserialize<'de> for Value<'a> {
+    fn deserialize<D>(deserializer: D) -> Result<Value<'a>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        <OwnedValue as serde::Deserialize>::deserialize(deserializer).map(Value::from)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'a> From<OwnedValue> for Value<'a> {
+    fn from(value: OwnedValue) -> Value<'a> {
+        match value {
+            OwnedValue::String(s) => Value::String(s),
+            OwnedValue::Vector(v) => Value::Vector(v.into_iter().map(Value::from).collect()),
+            OwnedValue::Integer(i) => Value::Integer(i),
+            OwnedValue::Float(f) => Value::Float(f),
+            OwnedValue::Boolean(b) => Value::Boolean(b),
+            OwnedValue::Nil => Value::Nil,
+            OwnedValue::Long(i) => Value::Long(i),
+        }
+    }
+}
+
+/// Check that a function or variable name is valid.
+///
+/// Names start with a letter or `_`, and contain only letters, digits and `_`.
+/// The `style` says whether letters and digits can be any Unicode ones, or
+/// only ASCII ones.
+pub fn validate_name(name: &str, style: NameStyle) -> Result<(), Error> {
+    if name.is_empty() {
+        return Err(Error::InvalidName);
+    }
+    for (position, ch) in name.chars().enumerate() {
+        let ok = if style == NameStyle::AsciiOnly && !ch.is_ascii() {
+            false
+        } else if position == 0 {
+            ch.is_alphabetic() || ch == '_'
+        } else {
+            ch.is_alphanumeric() || ch == '_'
+        };
+        if !ok {
+            return Err(Error::InvalidNameChar { ch, position });
+        }
+    }
+    Ok(())
+}
+
+impl<'a> core::convert::TryFrom<&'a str> for Element<'a> {
+    type Error = Error;
+
+    fn try_from(s: &'a str) -> Result<Element<'a>, Error> {
+        if s.eq_ignore_ascii_case("return") {
+            return Ok(Element::Return);
+        } else if s.eq_ignore_ascii_case("end") {
+            return Ok(Element::End);
+        } else if s.eq_ignore_ascii_case("nop") {
+            return Ok(Element::Nop);
+        } else if s.eq_ignore_ascii_case("if") {
+            return Ok(Element::If);
+        } else if s.eq_ignore_ascii_case("else") {
+            return Ok(Element::Else);
+        } else if s.eq_ignore_ascii_case("while") {
+            return Ok(Element::While);
+        } else if s.eq_ignore_ascii_case("break") {
+            return Ok(Element::Break);
+        } else if s.eq_ignore_ascii_case("true") {
+            return Ok(Element::Boolean(true));
+        } else if s.eq_ignore_ascii_case("false") {
+            return Ok(Element::Boolean(false));
+        } else if s == "+" {
+            return Ok(Element::Add);
+        } else if s == "*" {
+            return Ok(Element::Multiply);
+        } else if s == "/" {
+            return Ok(Element::Divide);
+        } else if s == "-" {
+            return Ok(Element::Subtract);
+        } else if s.eq_ignore_ascii_case("neg") {
+            return Ok(Element::Negate);
+        } else if s == "&" {
+            return Ok(Element::BitAnd);
+        } else if s == "|" {
+            return Ok(Element::BitOr);
+        } else if s == "^" {
+            return Ok(Element::BitXor);
+        } else if s == "<<" {
+            return Ok(Element::ShiftLeft);
+        } else if s == ">>" {
+            return Ok(Element::ShiftRight);
+        } else if s == "==" {
+            return Ok(Element::Equal);
+        } else if s == "!=" {
+            return Ok(Element::NotEqual);
+        } else if s == "<" {
+            return Ok(Element::Less);
+        } else if s == "<=" {
+            return Ok(Element::LessEqual);
+        } else if s == ">" {
+            return Ok(Element::Greater);
+        } else if s == ">=" {
+            return Ok(Element::GreaterEqual);
+        } else if s == "&&" {
+            return Ok(Element::And);
+        } else if s == "||" {
+            return Ok(Element::Or);
+        } else if s == "!" {
+            return Ok(Element::Not);
+        } else if let Some(rest) = s.strip_prefix('"') {
+            return parse_string_literal(rest);
+        } else if let Some(i) = parse_integer(s)? {
+            return Ok(i);
+        } else if let Some(f) = parse_float(s) {
+            return Ok(Element::Float(f));
+        } else if let Some(function) = s.strip_prefix("fn ") {
+            // The parameter list is optional
+            let (name, params) = match function.split_once('(') {
+                Some((name, params)) => {
+                    let params = params
+                        .strip_suffix(')')
+                        .ok_or_else(|| Error::SyntaxError(s.chars().count()))?;
+                    (name.trim(), Params::new(params)?)
+                }
+                None => (function, Params::NONE),
+            };
+            validate_name(name, NameStyle::Unicode)?;
+            return Ok(Element::Function { name, params });
+        } else if let Some(name) = s.strip_prefix("for ") {
+            validate_name(name, NameStyle::Unicode)?;
+            return Ok(Element::For(name));
+        } else if let Some(name) = s.strip_prefix("label ") {
+            validate_name(name, NameStyle::Unicode)?;
+            return Ok(Element::Label(name));
+        } else if let Some(name) = s.strip_prefix("goto ") {
+            validate_name(name, NameStyle::Unicode)?;
+            return Ok(Element::Goto(name));
+        } else if let Some(name) = s.strip_prefix("const ") {
+            validate_name(name, NameStyle::Unicode)?;
+            return Ok(Element::Const(name));
+        } else if let Some(name) = s.strip_prefix("map ") {
+            validate_name(name, NameStyle::Unicode)?;
+            return Ok(Element::Map(name));
+        } else if let Some(name) = s.strip_prefix("filter ") {
+            validate_name(name, NameStyle::Unicode)?;
+            return Ok(Element::Filter(name));
+        } else if let Some(name) = s.strip_prefix("let ") {
+            validate_name(name, NameStyle::Unicode)?;
+            return Ok(Element::Assign(name));
+        } else if let Some(name) = s.strip_suffix("+=") {
+            let name = name.trim_end();
+            validate_name(name, NameStyle::Unicode)?;
+            return Ok(Element::AddAssign(name));
+        } else if let Some(name) = s.strip_suffix("-=") {
+            let name = name.trim_end();
+            validate_name(name, NameStyle::Unicode)?;
+            return Ok(Element::SubAssign(name));
+        } else if let Some(call) = s.strip_prefix("call ") {
+            // The argument count is optional, and defaults to zero
+            let (name, num_args) = match call.split_once(' ') {
+                Some((name, num_args)) => (
+                    name,
+                    num_args
+                        .parse()
+                        .map_err(|_| Error::SyntaxError(char_offset(s, num_args)))?,
+                ),
+                None => (call, 0),
+            };
+            validate_name(name, NameStyle::Unicode)?;
+            return Ok(Element::Call(name, num_args));
+        } else if let Some(num_items) = s.strip_prefix("vec ") {
+            let num_items = num_items
+                .parse()
+                .map_err(|_| Error::SyntaxError(char_offset(s, num_items)))?;
+            return Ok(Element::Vector(num_items));
+        } else if let Some(slot) = s.strip_prefix("stash ") {
+            let slot = slot
+                .parse()
+                .map_err(|_| Error::SyntaxError(char_offset(s, slot)))?;
+            return Ok(Element::Stash(slot));
+        } else if let Some(slot) = s.strip_prefix("stashed ") {
+            let slot = slot
+                .parse()
+                .map_err(|_| Error::SyntaxError(char_offset(s, slot)))?;
+            return Ok(Element::Stashed(slot));
+        } else if s == "[]" {
+            return Ok(Element::Index);
+        } else if s.eq_ignore_ascii_case("len") {
+            return Ok(Element::Len);
+        } else if s == "%" {
+            return Ok(Element::Modulo);
+        } else if s == "**" {
+            return Ok(Element::Pow);
+        } else if s.eq_ignore_ascii_case("float") {
+            return Ok(Element::ToFloat);
+        } else if s.eq_ignore_ascii_case("int") {
+            return Ok(Element::ToInt);
+        } else if s.eq_ignore_ascii_case("print") {
+            return Ok(Element::Print);
+        } else if let Some(length) = s.strip_prefix("block ") {
+            let length = length
+                .parse()
+                .map_err(|_| Error::SyntaxError(char_offset(s, length)))?;
+            return Ok(Element::Block(length));
+        } else if s.eq_ignore_ascii_case("nil") {
+            return Ok(Element::Nil);
+        } else if s == "?" {
+            return Ok(Element::Select);
+        } else if s.eq_ignore_ascii_case("substring") {
+            return Ok(Element::Substring);
+        } else if s.eq_ignore_ascii_case("str") {
+            return Ok(Element::ToStr);
+        } else if s.eq_ignore_ascii_case("parse_int") {
+            return Ok(Element::ParseInt);
+        } else if s.eq_ignore_ascii_case("parse_float") {
+            return Ok(Element::ParseFloat);
+        } else if s.eq_ignore_ascii_case("sort") {
+            return Ok(Element::Sort);
+        } else if s.eq_ignore_ascii_case("reverse") {
+            return Ok(Element::Reverse);
+        } else if s.eq_ignore_ascii_case("push") {
+            return Ok(Element::Push);
+        } else if s.eq_ignore_ascii_case("match") {
+            return Ok(Element::Match);
+        } else if s.eq_ignore_ascii_case("case") {
+            return Ok(Element::Case);
+        } else if s.eq_ignore_ascii_case("equals_ignore_case") {
+            return Ok(Element::EqualsIgnoreCase);
+        } else if s.eq_ignore_ascii_case("to_upper") {
+            return Ok(Element::ToUpper);
+        } else if s.eq_ignore_ascii_case("to_lower") {
+            return Ok(Element::ToLower);
+        } else if let Some(crc) = s.strip_prefix("checksum ") {
+            let crc = crc
+                .strip_prefix("0x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .ok_or(Error::SyntaxError(char_offset(s, crc)))?;
+            return Ok(Element::Checksum(crc));
+        } else if s.eq_ignore_ascii_case("abs") {
+            return Ok(Element::Abs);
+        } else if s.eq_ignore_ascii_case("min") {
+            return Ok(Element::Min);
+        } else if s.eq_ignore_ascii_case("max") {
+            return Ok(Element::Max);
+        } else if s.eq_ignore_ascii_case("sqrt") {
+            return Ok(Element::Sqrt);
+        } else if s.eq_ignore_ascii_case("sin") {
+            return Ok(Element::Sin);
+        } else if s.eq_ignore_ascii_case("cos") {
+            return Ok(Element::Cos);
+        } else if s.eq_ignore_ascii_case("continue") {
+            return Ok(Element::Continue);
+        } else if validate_name(s, NameStyle::Unicode).is_ok() {
+            return Ok(Element::Variable(s));
+        }
+        // Point at the first thing that couldn't be part of a name
+        let offset = s
+            .chars()
+            .position(|ch| !(ch.is_alphanumeric() || ch == '_'))
+            .unwrap_or(0);
+        Err(Error::SyntaxError(offset))
+    }
+}
+
+/// Work out how many characters into `s` the sub-slice `part` starts.
+fn char_offset(s: &str, part: &str) -> usize {
+    let byte_offset = part.as_ptr() as usize - s.as_ptr() as usize;
+    s[..byte_offset].chars().count()
+}
+
+impl<'a> core::fmt::Display for Element<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Element::Nop => write!(f, "nop"),
+            Element::End => write!(f, "end"),
+            Element::Function { name, params } if params.is_empty() => write!(f, "fn {name}"),
+            Element::Function { name, params } => {
+                write!(f, "fn {name}(")?;
+                for (idx, param) in params.iter().enumerate() {
+                    if idx != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{param}")?;
+                }
+                write!(f, ")")
+            }
+            Element::Return => write!(f, "return"),
+            Element::Integer(i) => write!(f, "{i}"),
+            // Debug formatting keeps the trailing `.0` on whole numbers, so
+            // they don't read back in as integers.
+            Element::Float(value) => write!(f, "{value:?}"),
+            Element::Add => write!(f, "+"),
+            Element::Multiply => write!(f, "*"),
+            Element::Divide => write!(f, "/"),
+            Element::Subtract => write!(f, "-"),
+            Element::Negate => write!(f, "neg"),
+            Element::StringLiteral(s) => {
+                write!(f, "\"")?;
+                write_escaped(f, s)?;
+                write!(f, "\"")
+            }
+            Element::EscapedString(s) => write!(f, "\"{s}\""),
+            Element::BitAnd => write!(f, "&"),
+            Element::BitOr => write!(f, "|"),
+            Element::BitXor => write!(f, "^"),
+            Element::ShiftLeft => write!(f, "<<"),
+            Element::ShiftRight => write!(f, ">>"),
+            Element::If => write!(f, "if"),
+            Element::Else => write!(f, "else"),
+            Element::While => write!(f, "while"),
+            Element::Break => write!(f, "break"),
+            Element::For(name) => write!(f, "for {name}"),
+            Element::Continue => write!(f, "continue"),
+            Element::Variable(name) => write!(f, "{name}"),
+            Element::Assign(name) => write!(f, "let {name}"),
+            Element::Call(name, num_args) => write!(f, "call {name} {num_args}"),
+            Element::Equal => write!(f, "=="),
+            Element::NotEqual => write!(f, "!="),
+            Element::Less => write!(f, "<"),
+            Element::LessEqual => write!(f, "<="),
+            Element::Greater => write!(f, ">"),
+            Element::GreaterEqual => write!(f, ">="),
+            Element::And => write!(f, "&&"),
+            Element::Or => write!(f, "||"),
+            Element::Not => write!(f, "!"),
+            Element::Boolean(b) => write!(f, "{b}"),
+            Element::Vector(num_items) => write!(f, "vec {num_items}"),
+            Element::Index => write!(f, "[]"),
+            Element::Len => write!(f, "len"),
+            Element::Modulo => write!(f, "%"),
+            Element::Pow => write!(f, "**"),
+            Element::ToFloat => write!(f, "float"),
+            Element::ToInt => write!(f, "int"),
+            Element::Print => write!(f, "print"),
+            Element::Block(length) => write!(f, "block {length}"),
+            Element::Long(i) => write!(f, "{i}L"),
+            Element::Nil => write!(f, "nil"),
+            Element::Select => write!(f, "?"),
+            Element::Substring => write!(f, "substring"),
+            Element::ToStr => write!(f, "str"),
+            Element::ParseInt => write!(f, "parse_int"),
+            Element::ParseFloat => write!(f, "parse_float"),
+            Element::AddAssign(name) => write!(f, "{name} +="),
+            Element::SubAssign(name) => write!(f, "{name} -="),
+            Element::Const(name) => write!(f, "const {name}"),
+            Element::Sort => write!(f, "sort"),
+            Element::Reverse => write!(f, "reverse"),
+            Element::Map(name) => write!(f, "map {name}"),
+            Element::Filter(name) => write!(f, "filter {name}"),
+            Element::Push => write!(f, "push"),
+            Element::Match => write!(f, "match"),
+            Element::Case => write!(f, "case"),
+            Element::EqualsIgnoreCase => write!(f, "equals_ignore_case"),
+            Element::ToUpper => write!(f, "to_upper"),
+            Element::ToLower => write!(f, "to_lower"),
+            Element::Checksum(crc) => write!(f, "checksum 0x{crc:08x}"),
+            Element::Abs => write!(f, "abs"),
+            Element::Min => write!(f, "min"),
+            Element::Max => write!(f, "max"),
+            Element::Sqrt => write!(f, "sqrt"),
+            Element::Sin => write!(f, "sin"),
+            Element::Cos => write!(f, "cos"),
+            Element::Label(name) => write!(f, "label {name}"),
+            Element::Goto(name) => write!(f, "goto {name}"),
+            Element::Stash(slot) => write!(f, "stash {slot}"),
+            Element::Stashed(slot) => write!(f, "stashed {slot}"),
+        }
+    }
+}
+
+// Once we've returned `None`, the index never moves again.
+impl<'a> core::iter::FusedIterator for ElementIter<'a> {}
+
+/// Programs are equal if they hold exactly the same bytes. See
+/// `Program::eq_elements` for a looser comparison.
+impl<'a, 'b> PartialEq<Program<'b>> for Program<'a> {
+    fn eq(&self, other: &Program<'b>) -> bool {
+        self.data == other.data && self.endianness == other.endianness
+    }
+}
+
+impl<'a> Eq for Program<'a> {}
+
+impl<'a> core::fmt::Display for Program<'a> {
+    /// Writes out each element on its own line, with the contents of each
+    /// block indented. If the program ends with bytes we can't decode, we
+    /// write `<invalid>` and stop.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut depth: usize = 0;
+        let mut iter = self.iter_statements(0);
+        for (_, element) in iter.by_ref() {
+            // These close a block, so line up with whatever opened it
+            let indent = match element {
+                Element::End | Element::Else | Element::Case => depth.saturating_sub(1),
+                _ => depth,
+            };
+            writeln!(f, "{:width$}{}", "", element, width = indent * 4)?;
+            match element {
+                Element::Function { .. }
+                | Element::If
+                | Element::While
+                | Element::For(_)
+                | Element::Match => {
+                    depth += 1;
+                }
+                Element::End => {
+                    depth = depth.saturating_sub(1);
+                }
+                _ => {}
+            }
+        }
+        if iter.index < self.data.len() {
+            writeln!(f, "{:width$}<invalid>", "", width = depth * 4)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for ElementIter<'a> {
+    type Item = (usize, Element<'a>);
+
+    /// Decode the next element.
+    ///
+    /// If the next element is truncated or not understood, we return `None`
+    /// and leave the index pointing at it, so every later call also returns
+    /// `None`.
+    fn next(&mut self) -> Option<Self::Item> {
+        // Find what sort of statement is next
+        match self.program.data.get(self.index).cloned() {
+            Some(Program::FUNCTION_ID) => {
+                let name = self.program.read_string(self.index + 1)?;
+                let count_index = self.index + 2 + name.len();
+                let count = *self.program.data.get(count_index)?;
+                // Check all the parameter names are there and readable
+                let mut end_index = count_index + 1;
+                for _ in 0..count {
+                    let param = self.program.read_string(end_index)?;
+                    end_index += 1 + param.len();
+                }
+                let params = Params {
+                    repr: ParamsRepr::Encoded {
+                        count,
+                        data: &self.program.data[count_index + 1..end_index],
+                    },
+                };
+                let old_index = self.index;
+                self.index = end_index;
+                Some((old_index, Element::Function { name, params }))
+            }
+            Some(Program::NOP_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Nop))
+            }
+            Some(Program::END_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::End))
+            }
+            Some(Program::RETURN_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Return))
+            }
+            Some(Program::ADD_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Add))
+            }
+            Some(Program::MULTIPLY_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Multiply))
+            }
+            Some(Program::DIVIDE_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Divide))
+            }
+            Some(Program::SUBTRACT_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Subtract))
+            }
+            Some(Program::NEGATE_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Negate))
+            }
+            Some(Program::BIT_AND_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::BitAnd))
+            }
+            Some(Program::BIT_OR_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::BitOr))
+            }
+            Some(Program::BIT_XOR_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::BitXor))
+            }
+            Some(Program::SHIFT_LEFT_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::ShiftLeft))
+            }
+            Some(Program::SHIFT_RIGHT_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::ShiftRight))
+            }
+            Some(Program::IF_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::If))
+            }
+            Some(Program::ELSE_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Else))
+            }
+            Some(Program::WHILE_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::While))
+            }
+            Some(Program::BREAK_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Break))
+            }
+            Some(Program::FOR_ID) => {
+                if let Some(name) = self.program.read_string(self.index + 1) {
+                    let old_index = self.index;
+                    self.index += 2 + name.len();
+                    Some((old_index, Element::For(name)))
+                } else {
+                    None
+                }
+            }
+            Some(Program::CONTINUE_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Continue))
+            }
+            Some(Program::VARIABLE_ID) => {
+                if let Some(name) = self.program.read_string(self.index + 1) {
+                    let old_index = self.index;
+                    self.index += 2 + name.len();
+                    Some((old_index, Element::Variable(name)))
+                } else {
+                    None
+                }
+            }
+            Some(Program::ASSIGN_ID) => {
+                if let Some(name) = self.program.read_string(self.index + 1) {
+                    let old_index = self.index;
+                    self.index += 2 + name.len();
+                    Some((old_index, Element::Assign(name)))
+                } else {
+                    None
+                }
+            }
+            Some(Program::CALL_ID) => {
+                let name = self.program.read_string(self.index + 1)?;
+                let num_args = *self.program.data.get(self.index + 2 + name.len())?;
+                let old_index = self.index;
+                self.index += 3 + name.len();
+                Some((old_index, Element::Call(name, num_args)))
+            }
+            Some(Program::EQUAL_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Equal))
+            }
+            Some(Program::NOT_EQUAL_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::NotEqual))
+            }
+            Some(Program::LESS_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Less))
+            }
+            Some(Program::LESS_EQUAL_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::LessEqual))
+            }
+            Some(Program::GREATER_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Greater))
+            }
+            Some(Program::GREATER_EQUAL_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::GreaterEqual))
+            }
+            Some(Program::AND_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::And))
+            }
+            Some(Program::OR_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Or))
+            }
+            Some(Program::NOT_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Not))
+            }
+            Some(Program::FALSE_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Boolean(false)))
+            }
+            Some(Program::TRUE_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Boolean(true)))
+            }
+            Some(Program::VECTOR_ID) => {
+                let num_items = *self.program.data.get(self.index + 1)?;
+                let old_index = self.index;
+                self.index += 2;
+                Some((old_index, Element::Vector(num_items)))
+            }
+            Some(Program::INDEX_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Index))
+            }
+            Some(Program::LEN_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Len))
+            }
+            Some(Program::MODULO_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Modulo))
+            }
+            Some(Program::POW_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Pow))
+            }
+            Some(Program::TO_FLOAT_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::ToFloat))
+            }
+            Some(Program::TO_INT_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::ToInt))
+            }
+            Some(Program::PRINT_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Print))
+            }
+            Some(Program::BLOCK_ID) => {
+                let length = self.program.data.get(self.index + 1..self.index + 3)?;
+                let old_index = self.index;
+                self.index += 3;
+                Some((
+                    old_index,
+                    Element::Block(u16::from_be_bytes([length[0], length[1]])),
+                ))
+            }
+            Some(Program::INTEGER_VARINT_ID) => {
+                let mut value: i32 = 0;
+                let mut shift = 0;
+                let mut len = 1;
+                loop {
+                    let byte = *self.program.data.get(self.index + len)?;
+                    len += 1;
+                    value |= i32::from(byte & 0x7F) << shift;
+                    shift += 7;
+                    if (byte & 0x80) == 0 {
+                        // Do sign extension
+                        if shift < 32 && (byte & 0x40) != 0 {
+                            value |= -1 << shift;
+                        }
+                        break;
+                    }
+                    if shift >= 35 {
+                        // Too long for an i32
+                        return None;
+                    }
+                }
+                let old_index = self.index;
+                self.index += len;
+                Some((old_index, Element::Integer(value)))
+            }
+            Some(Program::LONG_ID) => {
+                let buffer = self.program.read_number::<8>(self.index + 1)?;
+                let old_index = self.index;
+                self.index += 9;
+                // Now in big endian order
+                Some((old_index, Element::Long(i64::from_be_bytes(buffer))))
+            }
+            Some(Program::NIL_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Nil))
+            }
+            Some(Program::SELECT_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Select))
+            }
+            Some(Program::SUBSTRING_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Substring))
+            }
+            Some(Program::TO_STR_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::ToStr))
+            }
+            Some(Program::PARSE_INT_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::ParseInt))
+            }
+            Some(Program::PARSE_FLOAT_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::ParseFloat))
+            }
+            Some(Program::ADD_ASSIGN_ID) => {
+                let name = self.program.read_string(self.index + 1)?;
+                let old_index = self.index;
+                self.index += 2 + name.len();
+                Some((old_index, Element::AddAssign(name)))
+            }
+            Some(Program::SUB_ASSIGN_ID) => {
+                let name = self.program.read_string(self.index + 1)?;
+                let old_index = self.index;
+                self.index += 2 + name.len();
+                Some((old_index, Element::SubAssign(name)))
+            }
+            Some(Program::CONST_ID) => {
+                let name = self.program.read_string(self.index + 1)?;
+                let old_index = self.index;
+                self.index += 2 + name.len();
+                Some((old_index, Element::Const(name)))
+            }
+            Some(Program::SORT_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Sort))
+            }
+            Some(Program::REVERSE_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Reverse))
+            }
+            Some(Program::MAP_ID) => {
+                let name = self.program.read_string(self.index + 1)?;
+                let old_index = self.index;
+                self.index += 2 + name.len();
+                Some((old_index, Element::Map(name)))
+            }
+            Some(Program::FILTER_ID) => {
+                let name = self.program.read_string(self.index + 1)?;
+                let old_index = self.index;
+                self.index += 2 + name.len();
+                Some((old_index, Element::Filter(name)))
+            }
+            Some(Program::PUSH_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Push))
+            }
+            Some(Program::MATCH_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Match))
+            }
+            Some(Program::CASE_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Case))
+            }
+            Some(Program::EQUALS_IGNORE_CASE_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::EqualsIgnoreCase))
+            }
+            Some(Program::TO_UPPER_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::ToUpper))
+            }
+            Some(Program::TO_LOWER_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::ToLower))
+            }
+            Some(Program::CHECKSUM_ID) => {
+                let bytes = self.program.data.get(self.index + 1..self.index + 5)?;
+                let old_index = self.index;
+                self.index += 5;
+                let crc = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                Some((old_index, Element::Checksum(crc)))
+            }
+            Some(Program::ABS_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Abs))
+            }
+            Some(Program::MIN_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Min))
+            }
+            Some(Program::MAX_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Max))
+            }
+            Some(Program::SQRT_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Sqrt))
+            }
+            Some(Program::SIN_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Sin))
+            }
+            Some(Program::COS_ID) => {
+                let old_index = self.index;
+                self.index += 1;
+                Some((old_index, Element::Cos))
+            }
+            Some(Program::LABEL_ID) => {
+                let name = self.program.read_string(self.index + 1)?;
+                let old_index = self.index;
+                self.index += 2 + name.len();
+                Some((old_index, Element::Label(name)))
+            }
+            Some(Program::GOTO_ID) => {
+                let name = self.program.read_string(self.index + 1)?;
+                let old_index = self.index;
+                self.index += 2 + name.len();
+                Some((old_index, Element::Goto(name)))
+            }
+            Some(Program::STASH_ID) => {
+                let slot = *self.program.data.get(self.index + 1)?;
+                let old_index = self.index;
+                self.index += 2;
+                Some((old_index, Element::Stash(slot)))
+            }
+            Some(Program::STASHED_ID) => {
+                let slot = *self.program.data.get(self.index + 1)?;
+                let old_index = self.index;
+                self.index += 2;
+                Some((old_index, Element::Stashed(slot)))
+            }
+            Some(Program::STRING_ID) => {
+                if let Some(s) = self.program.read_string(self.index + 1) {
+                    let old_index = self.index;
+                    self.index += 2 + s.len();
+                    Some((old_index, Element::StringLiteral(s)))
+                } else {
+                    None
+                }
+            }
+            Some(Program::INTEGER1_ID) => {
+                if let Some(i) = self.program.data.get(self.index + 1) {
+                    let old_index = self.index;
+                    self.index += 2;
+                    Some((old_index, Element::Integer(*i as i8 as i32)))
+                } else {
+                    None
+                }
+            }
+            Some(Program::INTEGER2_ID) => {
+                if let Some(i) = self.program.read_number::<2>(self.index + 1) {
+                    let old_index = self.index;
+                    self.index += 3;
+                    // Now in big endian order
+                    let value: i32 = ((u16::from(i[0]) << 8) | u16::from(i[1])) as i16 as i32;
+                    Some((old_index, Element::Integer(value)))
+                } else {
+                    None
+                }
+            }
+            Some(Program::INTEGER3_ID) => {
+                if let Some(i) = self.program.read_number::<3>(self.index + 1) {
+                    let old_index = self.index;
+                    self.index += 4;
+                    // Now in big endian order
+                    let value: u32 =
+                        (u32::from(i[0]) << 16) | (u32::from(i[1]) << 8) | u32::from(i[2]);
+                    // Do sign extension
+                    let value: i32 = if (value & 0x0080_0000) != 0 {
+                        // it's negative
+                        (value | 0xFF000000) as i32
+                    } else {
+                        value as i32
+                    };
+                    Some((old_index, Element::Integer(value)))
+                } else {
+                    None
+                }
+            }
+            Some(Program::INTEGER4_ID) => {
+                if let Some(i) = self.program.read_number::<4>(self.index + 1) {
+                    let old_index = self.index;
+                    self.index += 5;
+                    // Now in big endian order
+                    let value: i32 = ((u32::from(i[0]) << 24)
+                        | (u32::from(i[1]) << 16)
+                        | (u32::from(i[2]) << 8)
+                        | u32::from(i[3])) as i32;
+                    Some((old_index, Element::Integer(value)))
+                } else {
+                    None
+                }
+            }
+            Some(Program::FLOAT_ID) => {
+                if let Some(i) = self.program.data.get(self.index + 1..self.index + 5) {
+                    let old_index = self.index;
+                    self.index += 5;
+                    // Stored as big endian IEEE-754
+                    let value = f32::from_be_bytes([i[0], i[1], i[2], i[3]]);
+                    Some((old_index, Element::Float(value)))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Every element is at least one byte long, which gives us an upper
+    /// bound. The remaining bytes might not decode at all (we stop at the
+    /// first one that doesn't), so dividing them by the longest element size
+    /// wouldn't be a safe lower bound - it has to be zero.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.program.data.len().saturating_sub(self.index);
+        (0, Some(remaining))
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
     use core::convert::TryInto;
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    #[test]
+    fn element_nop() {
+        assert_eq!(Ok(Element::Nop), "nop".try_into());
+        assert_eq!(Element::Nop.to_string(), "nop");
+    }
+
+    #[test]
+    fn element_end() {
+        assert_eq!(Ok(Element::End), "end".try_into());
+        assert_eq!(Element::End.to_string(), "end");
+    }
+
+    #[test]
+    fn element_function() {
+        assert_eq!(
+            Ok(Element::Function {
+                name: "test123",
+                params: Params::NONE,
+            }),
+            "fn test123".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::InvalidNameChar {
+                ch: '!',
+                position: 7
+            }),
+            "fn test123!".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::InvalidNameChar {
+                ch: ' ',
+                position: 4
+            }),
+            "fn test 123".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::InvalidNameChar {
+                ch: '-',
+                position: 4
+            }),
+            "fn test-123".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::InvalidNameChar {
+                ch: '1',
+                position: 0
+            }),
+            "fn 123test".try_into()
+        );
+        assert_eq!(
+            Element::Function {
+                name: "test123",
+                params: Params::NONE,
+            }
+            .to_string(),
+            "fn test123"
+        );
+    }
+
+    #[test]
+    fn name_styles() {
+        assert_eq!(validate_name("café", NameStyle::Unicode), Ok(()));
+        assert_eq!(
+            validate_name("café", NameStyle::AsciiOnly),
+            Err(Error::InvalidNameChar {
+                ch: 'é',
+                position: 3
+            })
+        );
+        assert_eq!(validate_name("_cafe2", NameStyle::AsciiOnly), Ok(()));
+        assert_eq!(
+            validate_name("2cafe", NameStyle::AsciiOnly),
+            Err(Error::InvalidNameChar {
+                ch: '2',
+                position: 0
+            })
+        );
+
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut space);
+        let cafe = Element::Function {
+            name: "café",
+            params: Params::NONE,
+        };
+        builder.insert(&cafe).unwrap();
+        builder.set_name_style(NameStyle::AsciiOnly);
+        let used = builder.used();
+        assert_eq!(
+            builder.insert(&cafe),
+            Err(Error::InvalidNameChar {
+                ch: 'é',
+                position: 3
+            })
+        );
+        let with_param = Element::Function {
+            name: "cafe",
+            params: Params::new("thé").unwrap(),
+        };
+        assert_eq!(
+            builder.insert(&with_param),
+            Err(Error::InvalidNameChar {
+                ch: 'é',
+                position: 2
+            })
+        );
+        assert_eq!(builder.used(), used);
+    }
+
+    #[test]
+    fn element_function_params() {
+        let element: Element = "fn add(a, b)".try_into().unwrap();
+        assert_eq!(
+            element,
+            Element::Function {
+                name: "add",
+                params: Params::new("a,b").unwrap(),
+            }
+        );
+        assert_eq!(element.to_string(), "fn add(a, b)");
+        assert_eq!(
+            Ok(Element::Function {
+                name: "add",
+                params: Params::NONE,
+            }),
+            "fn add()".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::InvalidNameChar {
+                ch: '1',
+                position: 0
+            }),
+            "fn add(a, 1b)".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::InvalidName),
+            "fn add(a, )".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::SyntaxError(11)),
+            "fn add(a, b".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::TooManyVariables),
+            "fn add(a,b,c,d,e,f,g,h,i,j,k,l,m,n,o,p,q)".try_into()
+        );
+    }
+
+    #[test]
+    fn insert_function_params() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        let element = Element::Function {
+            name: "add",
+            params: Params::new("a, bb").unwrap(),
+        };
+        builder.insert(&element).unwrap();
+        builder.insert(&Element::End).unwrap();
+        let expected = 2 + 3 + 1 + 2 + 3 + 1;
+        assert_eq!(builder.used(), expected);
+        let p = Program::new(&space[0..expected]);
+        let mut iter = p.iter_statements(0);
+        let (index, decoded) = iter.next().unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(decoded, element);
+        assert_eq!(decoded.to_string(), "fn add(a, bb)");
+        if let Element::Function { params, .. } = decoded {
+            assert_eq!(params.len(), 2);
+            assert_eq!(params.iter().collect::<Vec<_>>(), ["a", "bb"]);
+        }
+        assert_eq!(iter.next(), Some((11, Element::End)));
+        assert_eq!(iter.next(), None);
+        // The parameters are not written if the whole thing doesn't fit
+        let mut space = [0u8; 10];
+        let mut builder = ProgramBuilder::new(&mut space);
+        assert_eq!(builder.insert(&element), Err(Error::InsufficientSpace));
+        assert_eq!(builder.used(), 0);
+    }
+
+    #[test]
+    fn element_return() {
+        assert_eq!(Ok(Element::Return), "return".try_into());
+        assert_eq!(Element::Return.to_string(), "return");
+    }
+
+    #[test]
+    fn element_integer() {
+        assert_eq!(Ok(Element::Integer(1234)), "1234".try_into());
+        assert_eq!(Element::Integer(1234).to_string(), "1234");
+    }
+
+    #[test]
+    fn element_integer_radix() {
+        assert_eq!(Ok(Element::Integer(255)), "0xff".try_into());
+        assert_eq!(Ok(Element::Integer(255)), "0xFF".try_into());
+        assert_eq!(Ok(Element::Integer(10)), "0b1010".try_into());
+        assert_eq!(Ok(Element::Integer(8)), "0o10".try_into());
+        assert_eq!(Ok(Element::Integer(1000)), "1_000".try_into());
+        assert_eq!(Ok(Element::Integer(1_000_000)), "1_000_000".try_into());
+        assert_eq!(Ok(Element::Integer(-16)), "-0x10".try_into());
+        assert_eq!(Ok(Element::Integer(0xABCD)), "0xAB_CD".try_into());
+        assert_eq!(Ok(Element::Integer(i32::MAX)), "0x7FFF_FFFF".try_into());
+        assert_eq!(Ok(Element::Integer(i32::MIN)), "-0x8000_0000".try_into());
+        assert_eq!(Ok(Element::Integer(i32::MIN)), "-2147483648".try_into());
+        // Too big for an i64, pointing at the digits
+        assert_eq!(
+            Element::try_from("0x8000_0000_0000_0000"),
+            Err(Error::SyntaxError(2))
+        );
+        assert_eq!(
+            Element::try_from("0x1_0000_0000_0000_0000"),
+            Err(Error::SyntaxError(2))
+        );
+        assert_eq!(
+            Element::try_from("-0o1_777_777_777_777_777_777_777"),
+            Err(Error::SyntaxError(3))
+        );
+        assert_eq!(
+            Element::try_from("9223372036854775808"),
+            Err(Error::SyntaxError(0))
+        );
+        // Not a digit in that base
+        assert_eq!(Element::try_from("0b102"), Err(Error::SyntaxError(4)));
+        assert_eq!(Element::try_from("0x"), Err(Error::SyntaxError(2)));
+    }
+
+    #[test]
+    fn element_long() {
+        // Too big for an i32
+        assert_eq!(Ok(Element::Long(0x8000_0000)), "0x8000_0000".try_into());
+        assert_eq!(Ok(Element::Long(2147483648)), "2147483648".try_into());
+        assert_eq!(Ok(Element::Long(-2147483649)), "-2147483649".try_into());
+        assert_eq!(
+            Ok(Element::Long(i64::MAX)),
+            "9223372036854775807".try_into()
+        );
+        assert_eq!(
+            Ok(Element::Long(i64::MIN)),
+            "-9223372036854775808".try_into()
+        );
+        // Small ones need an `L`
+        assert_eq!(Ok(Element::Long(5)), "5L".try_into());
+        assert_eq!(Ok(Element::Long(-16)), "-0x10L".try_into());
+        assert_eq!(Element::Long(5).to_string(), "5L");
+
+        let mut space = [0u8; 9];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.insert(&Element::Long(-2)).unwrap();
+        assert_eq!(builder.used(), 9);
+        let p = Program::new(&space);
+        assert_eq!(p.iter_statements(0).next(), Some((0, Element::Long(-2))));
+        assert_eq!(Program::new(&space[0..8]).iter_statements(0).next(), None);
+    }
+
+    #[test]
+    fn element_float() {
+        assert_eq!(Ok(Element::Float(2.75)), "2.75".try_into());
+        assert_eq!(Ok(Element::Float(-0.5)), "-0.5".try_into());
+        assert_eq!(Ok(Element::Float(1.0)), "1.0".try_into());
+        assert_eq!(Ok(Element::Integer(1234)), "1234".try_into());
+        assert_eq!(Ok(Element::Variable("inf")), "inf".try_into());
+        for text in &["-inf", "-NaN", "-infinity", "1e99", "-1e99"] {
+            assert!(!matches!(Element::try_from(*text), Ok(Element::Float(_))));
+        }
+        assert_eq!(Element::Float(2.75).to_string(), "2.75");
+        assert_eq!(Element::Float(-0.5).to_string(), "-0.5");
+        assert_eq!(Element::Float(1.0).to_string(), "1.0");
+    }
+
+    #[test]
+    fn element_add() {
+        assert_eq!(Ok(Element::Add), "+".try_into());
+        assert_eq!(Element::Add.to_string(), "+");
+    }
+
+    #[test]
+    fn element_multiply() {
+        assert_eq!(Ok(Element::Multiply), "*".try_into());
+        assert_eq!(Element::Multiply.to_string(), "*");
+    }
+
+    #[test]
+    fn element_divide() {
+        assert_eq!(Ok(Element::Divide), "/".try_into());
+        assert_eq!(Element::Divide.to_string(), "/");
+    }
+
+    #[test]
+    fn element_subtract() {
+        assert_eq!(Ok(Element::Subtract), "-".try_into());
+        assert_eq!(Element::Subtract.to_string(), "-");
+        // Negative numbers are still numbers
+        assert_eq!(Ok(Element::Integer(-1)), "-1".try_into());
+    }
+
+    #[test]
+    fn element_negate() {
+        assert_eq!(Ok(Element::Negate), "neg".try_into());
+        assert_eq!(Element::Negate.to_string(), "neg");
+    }
+
+    #[test]
+    fn element_string_literal() {
+        assert_eq!(Ok(Element::StringLiteral("hello")), "\"hello\"".try_into());
+        assert_eq!(Ok(Element::StringLiteral("")), "\"\"".try_into());
+        assert_eq!(
+            Err::<Element, Error>(Error::SyntaxError(6)),
+            "\"hello".try_into()
+        );
+        assert_eq!(Element::StringLiteral("hello").to_string(), "\"hello\"");
+        assert_eq!(
+            Ok(Element::EscapedString("a\\tb\\\\")),
+            "\"a\\tb\\\\\"".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::SyntaxError(2)),
+            "\"a\\qb\"".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::SyntaxError(1)),
+            "\"\\xff\"".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::SyntaxError(6)),
+            "\"abc\\\"".try_into()
+        );
+        assert_eq!(
+            Element::StringLiteral("say \"hi\"\n").to_string(),
+            "\"say \\\"hi\\\"\\n\""
+        );
+    }
+
+    #[test]
+    fn string_escapes_round_trip() {
+        let source = "\
+fn main
+    return
+    \"line\\nbreak \\\"quoted\\\"; \\x41\"
+end
+";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(
+            p.run("main"),
+            Ok(Value::StringLiteral("line\nbreak \"quoted\"; A"))
+        );
+        // `\x41` comes back as the `A` it stands for
+        let expected = "\
+fn main
+    return
+    \"line\\nbreak \\\"quoted\\\"; A\"
+end
+";
+        assert_eq!(p.to_string(), expected);
+        let mut space2 = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space2);
+        builder.assemble(expected).unwrap();
+        assert_eq!(builder.used(), used);
+        assert_eq!(&space2[0..used], &space[0..used]);
+    }
+
+    #[test]
+    fn round_trip_arithmetic() {
+        for element in &[
+            Element::Add,
+            Element::Subtract,
+            Element::Multiply,
+            Element::Divide,
+            Element::Negate,
+            Element::BitAnd,
+            Element::BitOr,
+            Element::BitXor,
+            Element::ShiftLeft,
+            Element::ShiftRight,
+            Element::Equal,
+            Element::NotEqual,
+            Element::Less,
+            Element::LessEqual,
+            Element::Greater,
+            Element::GreaterEqual,
+            Element::And,
+            Element::Or,
+            Element::Not,
+            Element::Index,
+            Element::Len,
+            Element::Modulo,
+            Element::Pow,
+            Element::ToFloat,
+            Element::ToInt,
+            Element::Nil,
+            Element::Select,
+            Element::Substring,
+            Element::ToStr,
+            Element::ParseInt,
+            Element::ParseFloat,
+            Element::Sort,
+            Element::Reverse,
+            Element::Push,
+            Element::Match,
+            Element::Case,
+            Element::EqualsIgnoreCase,
+            Element::ToUpper,
+            Element::ToLower,
+            Element::Abs,
+            Element::Min,
+            Element::Max,
+            Element::Sqrt,
+            Element::Sin,
+            Element::Cos,
+        ] {
+            let mut space = [0u8; 1];
+            let mut builder = ProgramBuilder::new(&mut space);
+            builder.insert(element).unwrap();
+            let p = Program::new(&space);
+            assert_eq!(p.iter_statements(0).next(), Some((0, element.clone())));
+            let text = element.to_string();
+            assert_eq!(Ok(element.clone()), text.as_str().try_into());
+        }
+    }
+
+    #[test]
+    fn element_if() {
+        assert_eq!(Ok(Element::If), "if".try_into());
+        assert_eq!(Element::If.to_string(), "if");
+    }
+
+    #[test]
+    fn element_else() {
+        assert_eq!(Ok(Element::Else), "else".try_into());
+        assert_eq!(Element::Else.to_string(), "else");
+    }
+
+    #[test]
+    fn element_while() {
+        assert_eq!(Ok(Element::While), "while".try_into());
+        assert_eq!(Element::While.to_string(), "while");
+    }
+
+    #[test]
+    fn element_break() {
+        assert_eq!(Ok(Element::Break), "break".try_into());
+        assert_eq!(Element::Break.to_string(), "break");
+    }
+
+    #[test]
+    fn element_for() {
+        assert_eq!(Ok(Element::For("idx")), "for idx".try_into());
+        assert_eq!(
+            Err::<Element, Error>(Error::InvalidNameChar {
+                ch: '1',
+                position: 0
+            }),
+            "for 1x".try_into()
+        );
+        assert_eq!(Element::For("idx").to_string(), "for idx");
+    }
+
+    #[test]
+    fn element_label_goto() {
+        assert_eq!(Ok(Element::Label("top")), "label top".try_into());
+        assert_eq!(Ok(Element::Goto("top")), "goto top".try_into());
+        assert_eq!(
+            Err::<Element, Error>(Error::InvalidNameChar {
+                ch: '1',
+                position: 0
+            }),
+            "goto 1x".try_into()
+        );
+        assert_eq!(Element::Label("top").to_string(), "label top");
+        assert_eq!(Element::Goto("top").to_string(), "goto top");
+    }
+
+    #[test]
+    fn element_continue() {
+        assert_eq!(Ok(Element::Continue), "continue".try_into());
+        assert_eq!(Element::Continue.to_string(), "continue");
+    }
+
+    #[test]
+    fn element_variable() {
+        assert_eq!(Ok(Element::Variable("x_1")), "x_1".try_into());
+        assert_eq!(Ok(Element::Variable("café")), "café".try_into());
+        assert_eq!(
+            Err::<Element, Error>(Error::SyntaxError(1)),
+            "x-1".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::SyntaxError(2)),
+            "éé@".try_into()
+        );
+        assert_eq!(Err::<Element, Error>(Error::SyntaxError(0)), "@".try_into());
+        assert_eq!(Element::Variable("x_1").to_string(), "x_1");
+    }
+
+    #[test]
+    fn element_assign() {
+        assert_eq!(Ok(Element::Assign("x")), "let x".try_into());
+        assert_eq!(
+            Err::<Element, Error>(Error::InvalidNameChar {
+                ch: ' ',
+                position: 1
+            }),
+            "let x y".try_into()
+        );
+        assert_eq!(Element::Assign("x").to_string(), "let x");
+    }
+
+    #[test]
+    fn element_add_assign() {
+        assert_eq!(Ok(Element::AddAssign("x")), "x +=".try_into());
+        assert_eq!(Ok(Element::AddAssign("x")), "x+=".try_into());
+        assert_eq!(Ok(Element::SubAssign("x")), "x -=".try_into());
+        assert_eq!(Err::<Element, Error>(Error::InvalidName), "+=".try_into());
+        assert_eq!(Element::AddAssign("x").to_string(), "x +=");
+        assert_eq!(Element::SubAssign("x").to_string(), "x -=");
+        let mut space = [0u8; 8];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.insert(&Element::SubAssign("abc")).unwrap();
+        let used = builder.used();
+        assert_eq!(used, 5);
+        let p = Program::new(&space[0..used]);
+        assert_eq!(
+            p.iter_statements(0).next(),
+            Some((0, Element::SubAssign("abc")))
+        );
+    }
+
+    #[test]
+    fn element_const() {
+        assert_eq!(Ok(Element::Const("PIN")), "const PIN".try_into());
+        assert_eq!(Element::Const("PIN").to_string(), "const PIN");
+    }
+
+    #[test]
+    fn element_map_filter() {
+        assert_eq!(Ok(Element::Map("double")), "map double".try_into());
+        assert_eq!(Ok(Element::Filter("even")), "filter even".try_into());
+        assert_eq!(Element::Map("double").to_string(), "map double");
+        assert_eq!(Element::Filter("even").to_string(), "filter even");
+    }
+
+    #[test]
+    fn element_call() {
+        assert_eq!(Ok(Element::Call("add", 2)), "call add 2".try_into());
+        assert_eq!(Ok(Element::Call("foo", 0)), "call foo".try_into());
+        assert_eq!(
+            Err::<Element, Error>(Error::SyntaxError(9)),
+            "call add two".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::SyntaxError(9)),
+            "call add 256".try_into()
+        );
+        // Offsets are in characters, not bytes
+        assert_eq!(
+            Err::<Element, Error>(Error::SyntaxError(9)),
+            "call ädd x".try_into()
+        );
+        assert_eq!(Element::Call("add", 2).to_string(), "call add 2");
+    }
+
+    #[test]
+    fn empty_program() {
+        let mut space = [0u8; 64];
+        let builder = ProgramBuilder::new(&mut space);
+        assert_eq!(builder.used(), 0);
+        assert_eq!(builder.free(), space.len());
+    }
+
+    #[test]
+    fn insert_function() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert(&Element::Function {
+                name: "foo",
+                params: Params::NONE,
+            })
+            .unwrap();
+        assert_eq!(builder.used(), 6);
+        let p = Program::new(&space[0..6]);
+        assert_eq!(
+            p.iter_statements(0).next(),
+            Some((
+                0,
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn insert_two_functions() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert(&Element::Function {
+                name: "foo",
+                params: Params::NONE,
+            })
+            .unwrap();
+        builder.insert(&Element::End).unwrap();
+        builder
+            .insert(&Element::Function {
+                name: "testé",
+                params: Params::NONE,
+            })
+            .unwrap();
+        builder.insert(&Element::End).unwrap();
+        let expected = 3 + 3 + 1 + 3 + 6 + 1;
+        assert_eq!(builder.used(), expected);
+        let p = Program::new(&space[0..expected]);
+        let mut iter = p.iter_statements(0);
+        assert_eq!(
+            iter.next(),
+            Some((
+                0,
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                }
+            ))
+        );
+        assert_eq!(iter.next(), Some((6, Element::End)));
+        assert_eq!(
+            iter.next(),
+            Some((
+                7,
+                Element::Function {
+                    name: "testé",
+                    params: Params::NONE,
+                }
+            ))
+        );
+        assert_eq!(iter.next(), Some((16, Element::End)));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn create_program() {
+        let data = [
+            Program::FUNCTION_ID,
+            0x05, // 5 byte string
+            b'f', // string bytes
+            b'o',
+            b'o',
+            0xc2, // including a UTF-8 encoded £
+            0xa3,
+            0x00, // no parameters
+            Program::RETURN_ID,
+            Program::INTEGER1_ID,
+            0x01,
+            Program::END_ID,
+        ];
+        let p = Program::new(&data);
+        assert_eq!(p.run("bar"), Err(Error::function_not_found("bar")));
+        assert_eq!(p.run("foo£"), Ok(Value::Integer(0x01)));
+    }
+
+    #[test]
+    fn disassemble_program() {
+        let data = [
+            Program::FUNCTION_ID,
+            0x03,
+            b'f',
+            b'o',
+            b'o',
+            0x01, // one parameter
+            0x01,
+            b'x',
+            Program::IF_ID,
+            Program::LESS_ID,
+            Program::VARIABLE_ID,
+            0x01,
+            b'x',
+            Program::INTEGER1_ID,
+            0x02,
+            Program::RETURN_ID,
+            Program::STRING_ID,
+            0x03,
+            b'y',
+            b'e',
+            b's',
+            Program::ELSE_ID,
+            Program::RETURN_ID,
+            Program::FLOAT_ID,
+            0x3F,
+            0xC0,
+            0x00,
+            0x00,
+            Program::END_ID,
+            Program::END_ID,
+            0xFF, // not a valid opcode
+        ];
+        let p = Program::new(&data);
+        let expected = "\
+fn foo(x)
+    if
+        <
+        x
+        2
+        return
+        \"yes\"
+    else
+        return
+        1.5
+    end
+end
+<invalid>
+";
+        assert_eq!(p.to_string(), expected);
+        #[cfg(feature = "alloc")]
+        assert_eq!(p.disassemble(), expected);
+        // Just the valid part
+        let p = Program::new(&data[0..data.len() - 1]);
+        assert_eq!(p.to_string(), &expected[0..expected.len() - 10]);
+        // Stops part way through an Integer
+        let p = Program::new(&data[0..14]);
+        assert_eq!(
+            p.to_string(),
+            "fn foo(x)\n    if\n        <\n        x\n        <invalid>\n"
+        );
+    }
+
+    #[test]
+    fn assemble_program() {
+        let source = "fn foo\nreturn\n42\nend\n";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run("foo"), Ok(Value::Integer(42)));
+        assert_eq!(p.to_string(), "fn foo\n    return\n    42\nend\n");
+    }
+
+    #[test]
+    fn assemble_comments() {
+        let source = "\
+# A whole line comment
+fn foo
+    // Another one
+    print
+    \"# not a comment\" # but this is
+    return
+    42  # the answer
+end // done
+";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(
+            p.to_string(),
+            "fn foo\n    print\n    \"# not a comment\"\n    return\n    42\nend\n"
+        );
+        // Errors still give the right line
+        let mut builder = ProgramBuilder::new(&mut space);
+        assert_eq!(
+            builder.assemble("# comment\n\n42 # fine\n?? # not fine\n"),
+            Err(Error::ParseError { line: 4, offset: 0 })
+        );
+    }
+
+    #[test]
+    fn strip_comments() {
+        assert_eq!(strip_comment("42  # the answer"), "42  ");
+        assert_eq!(strip_comment("42 // the answer"), "42 ");
+        assert_eq!(strip_comment("# all comment"), "");
+        assert_eq!(strip_comment("\"a # b\""), "\"a # b\"");
+        assert_eq!(strip_comment("\"a // b\" // c"), "\"a // b\" ");
+        assert_eq!(strip_comment("/ 2"), "/ 2");
+        assert_eq!(strip_comment("no comment"), "no comment");
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn parse_lines() {
+        assert_eq!(
+            parse_line("return; 42"),
+            Ok(std::vec![Element::Return, Element::Integer(42)])
+        );
+        assert_eq!(
+            parse_line("print;\"a;b\" ; ;"),
+            Ok(std::vec![Element::Print, Element::StringLiteral("a;b")])
+        );
+        assert_eq!(parse_line("  "), Ok(Vec::new()));
+        assert!(parse_line("return; ??").is_err());
+    }
+
+    #[test]
+    fn assemble_semicolons() {
+        let source = "fn foo; return; \"a;b\"  # ; not split\nend;";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run("foo"), Ok(Value::StringLiteral("a;b")));
+        let mut builder = ProgramBuilder::new(&mut space);
+        assert_eq!(
+            builder.assemble("fn foo\nreturn; ??"),
+            Err(Error::ParseError { line: 2, offset: 8 })
+        );
+    }
+
+    #[test]
+    fn assemble_round_trip() {
+        let source = "\
+fn add(a, b)
+    return
+    + a b
+end
+
+fn foo
+    let x
+    call add 2
+    1
+    2
+    if
+        == x 3
+        return
+        \"three\"
+    end
+    return
+    x
+end
+";
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        assert_eq!(
+            builder.assemble(source),
+            Err(Error::ParseError { line: 3, offset: 4 })
+        );
+        let source = source
+            .replace("+ a b", "+\na\nb")
+            .replace("== x 3", "==\nx\n3");
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(&source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run("foo"), Ok(Value::StringLiteral("three")));
+        // Disassembling and assembling again gives the same bytes
+        let text = p.to_string();
+        let mut space2 = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space2);
+        builder.assemble(&text).unwrap();
+        assert_eq!(builder.used(), used);
+        assert_eq!(space[0..used], space2[0..used]);
+    }
+
+    #[test]
+    fn truncated_string() {
+        let data = [
+            Program::FUNCTION_ID,
+            0x05, // claims 5 bytes, but only 3 follow
+            b'f',
+            b'o',
+            b'o',
+        ];
+        let p = Program::new(&data);
+        assert_eq!(p.iter_statements(0).next(), None);
+        assert_eq!(p.run("foo"), Err(Error::function_not_found("foo")));
+        let data = [Program::STRING_ID, 0xFF, b'x'];
+        let p = Program::new(&data);
+        assert_eq!(p.iter_statements(0).count(), 0);
+        assert_eq!(p.to_string(), "<invalid>\n");
+    }
+
+    #[test]
+    fn truncated_programs() {
+        let source = "\
+fn add(a, b)
+return
++
+a
+b
+end
+fn foo
+let x
+call add 2
+1000
+2.5
+let y
+\"hello\"
+while
+false
+nop
+end
+for i
+1
+70000
+end
+return
+x
+end
+";
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let full_count = Program::new(&space[0..used]).iter_statements(0).count();
+        for len in 0..used {
+            let p = Program::new(&space[0..len]);
+            let mut iter = p.iter_statements(0);
+            let count = iter.by_ref().count();
+            assert!(count <= full_count);
+            // Nothing more comes out once we've stopped
+            assert_eq!(iter.next(), None);
+            assert_eq!(iter.next(), None);
+            // None of these should panic
+            let _ = p.to_string();
+            let _ = p.run_with_limit("foo", 10);
+        }
+    }
+
+    #[test]
+    fn statement_count() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .assemble("fn foo\nreturn\n+\n1\n2.5\nend\n")
+            .unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.statement_count(), 6);
+        let mut iter = p.iter_statements(0);
+        loop {
+            let remaining = p.iter_statements(iter.index).count();
+            assert_eq!(iter.size_hint(), (0, Some(used - iter.index)));
+            assert!(remaining <= used - iter.index);
+            if iter.next().is_none() {
+                break;
+            }
+        }
+        // One-byte elements meet the upper bound exactly
+        let nops = [Program::NOP_ID; 4];
+        let p = Program::new(&nops);
+        assert_eq!(p.iter_statements(0).size_hint(), (0, Some(4)));
+        assert_eq!(p.statement_count(), 4);
+        // Even a single byte might not decode, so the lower bound stays zero
+        let p = Program::new(&[0xFF]);
+        assert_eq!(p.iter_statements(0).size_hint(), (0, Some(1)));
+        assert_eq!(p.statement_count(), 0);
+        assert_eq!(Program::new(&[]).statement_count(), 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let values = [
+            Value::String(String::from("hello")),
+            Value::Vector(std::vec![
+                Value::Integer(1),
+                Value::String(String::from("two")),
+                Value::Vector(std::vec![Value::Nil]),
+            ]),
+            Value::Integer(-5),
+            Value::Float(1.5),
+            Value::Boolean(true),
+            Value::Nil,
+        ];
+        for value in values.iter() {
+            let json = serde_json::to_string(value).unwrap();
+            let decoded: Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(&decoded, value);
+        }
+        // Literals look like any other string, but come back owned
+        let json = serde_json::to_string(&Value::StringLiteral("hello")).unwrap();
+        assert_eq!(json, r#"{"String":"hello"}"#);
+        let decoded: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, Value::String(String::from("hello")));
+        assert_eq!(serde_json::to_string(&Value::Nil).unwrap(), r#""Nil""#);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn function_not_found_name() {
+        let p = Program::new(&[]);
+        assert_eq!(
+            p.run("missing"),
+            Err(Error::FunctionNotFound(String::from("missing")))
+        );
+    }
+
+    #[test]
+    fn insert_is_atomic() {
+        let mut space = [0u8; 8];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.insert(&Element::Nop).unwrap();
+        // Needs 2 + 6 + 1 bytes, but there's only 7 left
+        assert_eq!(
+            builder.insert(&Element::Function {
+                name: "foobar",
+                params: Params::NONE,
+            }),
+            Err(Error::InsufficientSpace)
+        );
+        assert_eq!(builder.used(), 1);
+        // The name fits, but the parameters don't
+        assert_eq!(
+            builder.insert(&Element::Function {
+                name: "f",
+                params: Params::new("a, b").unwrap(),
+            }),
+            Err(Error::InsufficientSpace)
+        );
+        assert_eq!(builder.used(), 1);
+        for element in &[
+            Element::Integer(i32::MAX),
+            Element::Float(1.0),
+            Element::StringLiteral("hello world"),
+            Element::Call("function", 1),
+        ] {
+            let mut space = [0u8; 4];
+            let mut builder = ProgramBuilder::new(&mut space);
+            assert_eq!(builder.insert(element), Err(Error::InsufficientSpace));
+            assert_eq!(builder.used(), 0);
+        }
+        // And the space that was there is still usable
+        builder
+            .insert(&Element::Function {
+                name: "f",
+                params: Params::new("a").unwrap(),
+            })
+            .unwrap();
+        assert_eq!(builder.used(), 1 + 4 + 2);
+    }
+
+    #[test]
+    fn insert_all() {
+        let mut space = [0u8; 8];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.insert(&Element::Nop).unwrap();
+        builder
+            .insert_all(&[Element::Return, Element::Integer(1)])
+            .unwrap();
+        assert_eq!(builder.used(), 4);
+        // The last one doesn't fit, so none of them go in
+        assert_eq!(
+            builder.insert_all(&[Element::Nop, Element::Nop, Element::Integer(1000)]),
+            Err(Error::InsufficientSpace)
+        );
+        assert_eq!(builder.used(), 4);
+        // Any other error rolls back too
+        let long_name = "x".repeat(256);
+        assert_eq!(
+            builder.insert_all(&[Element::Nop, Element::Variable(&long_name)]),
+            Err(Error::NameTooLong)
+        );
+        assert_eq!(builder.used(), 4);
+    }
+
+    #[test]
+    fn list_functions() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .assemble(
+                "fn first\nwhile\n1\nif\n1\nbreak\nend\nend\nend\nfn second(a)\nreturn\na\nend\n",
+            )
+            .unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut functions = p.functions();
+        assert_eq!(functions.next(), Some("first"));
+        assert_eq!(functions.next(), Some("second"));
+        assert_eq!(functions.next(), None);
+        assert_eq!(functions.next(), None);
+        // The second function isn't closed
+        let p = Program::new(&space[0..used - 1]);
+        assert_eq!(p.functions().collect::<Vec<_>>(), ["first", "second"]);
+        // The first function isn't closed, so we can't find the second
+        let p = Program::new(&space[0..12]);
+        assert_eq!(p.functions().collect::<Vec<_>>(), ["first"]);
+        assert_eq!(Program::new(&[]).functions().count(), 0);
+    }
+
+    #[test]
+    fn function_handle() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .assemble("fn foo\nreturn\n+\n1\n2\nend\nfn bar(x)\nreturn\nx\nend\n")
+            .unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let handle = p.find_function("foo").unwrap();
+        for _ in 0..3 {
+            assert_eq!(p.run_handle(handle), p.run("foo"));
+        }
+        assert_eq!(p.run_handle(handle), Ok(Value::Integer(3)));
+        assert_eq!(
+            p.run_handle(p.find_function("bar").unwrap()),
+            Err(Error::ArityMismatch)
+        );
+        assert_eq!(p.find_function("baz"), None);
+        // A handle only works with the program it came from
+        let other = Program::new(&space[0..used - 1]);
+        assert_eq!(other.run_handle(handle), Err(Error::InvalidHandle));
+        let copy = space;
+        let other = Program::new(&copy[0..used]);
+        assert_eq!(other.run_handle(handle), Err(Error::InvalidHandle));
+    }
+
+    #[test]
+    fn run_at_index() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .assemble("fn foo\nreturn\n+\n1\n2\nend\nfn bar(x)\nreturn\nx\nend\n")
+            .unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let index = p.function_index("foo").unwrap();
+        assert_eq!(index, 6);
+        assert_eq!(p.run_at(index), Ok(Value::Integer(3)));
+        let index = p.function_index("bar").unwrap();
+        assert_eq!(p.run_at(index), Err(Error::ArityMismatch));
+        assert_eq!(p.function_index("baz"), None);
+        // Part way through the name of `foo`
+        assert_eq!(p.run_at(3), Err(Error::SequenceError(3)));
+        // Part way through the `1`
+        assert_eq!(p.run_at(9), Err(Error::SequenceError(9)));
+        // An element, but not the start of a function
+        assert_eq!(p.run_at(7), Err(Error::SequenceError(7)));
+        assert_eq!(p.run_at(used + 1), Err(Error::SequenceError(used + 1)));
+    }
+
+    #[test]
+    fn num_statements() {
+        let data = [
+            Program::FUNCTION_ID,
+            0x03,
+            b'f',
+            b'o',
+            b'o',
+            0x00,
+            Program::NOP_ID,
+            Program::INTEGER2_ID,
+            0x01,
+            0x02,
+            Program::END_ID,
+        ];
+        let p = Program::new(&data);
+        assert_eq!(p.iter_statements(0).count(), 4);
+    }
+
+    #[test]
+    fn get_integer1() {
+        let data = [Program::INTEGER1_ID, 0x03];
+        let p = Program::new(&data);
+        assert_eq!(
+            p.iter_statements(0).next(),
+            Some((0, Element::Integer(0x03)))
+        );
+    }
+
+    #[test]
+    fn get_integer2() {
+        let data = [Program::INTEGER2_ID, 0x03, 0x04];
+        let p = Program::new(&data);
+        assert_eq!(
+            p.iter_statements(0).next(),
+            Some((0, Element::Integer(0x0304)))
+        );
+    }
+
+    #[test]
+    fn get_integer3() {
+        let data = [Program::INTEGER3_ID, 0x03, 0x04, 0x05];
+        let p = Program::new(&data);
+        assert_eq!(
+            p.iter_statements(0).next(),
+            Some((0, Element::Integer(0x030405)))
+        );
+    }
+
+    #[test]
+    fn get_integer4() {
+        let data = [Program::INTEGER4_ID, 0x03, 0x04, 0x05, 0x06];
+        let p = Program::new(&data);
+        assert_eq!(
+            p.iter_statements(0).next(),
+            Some((0, Element::Integer(0x03040506)))
+        );
+    }
+
+    #[test]
+    fn get_negative_ntegeri8() {
+        let data = [Program::INTEGER1_ID, 0xFF];
+        let p = Program::new(&data);
+        assert_eq!(p.iter_statements(0).next(), Some((0, Element::Integer(-1))));
+    }
+
+    #[test]
+    fn get_negative_integer16() {
+        let data = [Program::INTEGER2_ID, 0xFF, 0xFE];
+        let p = Program::new(&data);
+        assert_eq!(p.iter_statements(0).next(), Some((0, Element::Integer(-2))));
+    }
+
+    #[test]
+    fn get_negative_integer24() {
+        let data = [Program::INTEGER3_ID, 0xFF, 0xFF, 0xFD];
+        let p = Program::new(&data);
+        assert_eq!(p.iter_statements(0).next(), Some((0, Element::Integer(-3))));
+    }
+
+    #[test]
+    fn get_negative_integer32() {
+        let data = [Program::INTEGER4_ID, 0xFF, 0xFF, 0xFF, 0xFC];
+        let p = Program::new(&data);
+        assert_eq!(p.iter_statements(0).next(), Some((0, Element::Integer(-4))));
+    }
+
+    #[test]
+    fn get_float() {
+        let data = [Program::FLOAT_ID, 0x40, 0x49, 0x0F, 0xDB];
+        let p = Program::new(&data);
+        assert_eq!(
+            p.iter_statements(0).next(),
+            Some((0, Element::Float(core::f32::consts::PI)))
+        );
+    }
+
+    #[test]
+    fn test_float_encoding() {
+        for value in [1.5, -0.25, f32::MAX, f32::MIN_POSITIVE] {
+            let mut space = [0u8; 5];
+            let mut builder = ProgramBuilder::new(&mut space);
+            builder.insert(&Element::Float(value)).unwrap();
+            assert_eq!(builder.used(), 5);
+            assert_eq!(space[0], Program::FLOAT_ID);
+            assert_eq!(&space[1..], &value.to_be_bytes());
+            let p = Program::new(&space);
+            match p.iter_statements(0).next() {
+                Some((0, Element::Float(f))) => assert_eq!(f.to_bits(), value.to_bits()),
+                other => panic!("Bad decode of {}: {:?}", value, other),
+            }
+        }
+        // These have no spelling in source text
+        let mut space = [0u8; 5];
+        let mut builder = ProgramBuilder::new(&mut space);
+        for value in [f32::INFINITY, f32::NEG_INFINITY, f32::NAN] {
+            assert_eq!(
+                builder.insert(&Element::Float(value)),
+                Err(Error::NonFiniteFloat)
+            );
+        }
+        assert_eq!(builder.used(), 0);
+    }
+
+    #[test]
+    fn return_integer_literal() {
+        let data = [
+            Program::FUNCTION_ID,
+            0x03,
+            b'f',
+            b'o',
+            b'o',
+            0x00,
+            Program::RETURN_ID,
+            Program::INTEGER1_ID,
+            0x0F,
+            Program::END_ID,
+        ];
+        let p = Program::new(&data);
+        assert_eq!(p.run("foo"), Ok(Value::Integer(15)));
+    }
+
+    #[test]
+    fn add_integers() {
+        let data = [
+            Program::ADD_ID,
+            Program::INTEGER1_ID,
+            0x02,
+            Program::INTEGER1_ID,
+            0x03,
+        ];
+        let p = Program::new(&data);
+        assert_eq!(
+            p.evaluate_expression(0, &mut Context::default(), &VarScope::new()),
+            Ok((5, Value::Integer(5)))
+        );
+    }
+
+    #[test]
+    fn add_nested() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        for element in &[
+            Element::Function {
+                name: "foo",
+                params: Params::NONE,
+            },
+            Element::Return,
+            Element::Add,
+            Element::Add,
+            Element::Integer(1),
+            Element::Integer(2),
+            Element::Integer(3),
+            Element::End,
+        ] {
+            builder.insert(element).unwrap();
+        }
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run("foo"), Ok(Value::Integer(6)));
+    }
+
+    #[test]
+    fn add_missing_operand() {
+        let data = [
+            Program::RETURN_ID,
+            Program::ADD_ID,
+            Program::INTEGER1_ID,
+            0x02,
+            Program::END_ID,
+        ];
+        let p = Program::new(&data);
+        assert_eq!(p.run_from_index(0), Err(Error::SequenceError(4)));
+    }
+
+    /// Build a function called `foo` which returns the given expression,
+    /// then check what running it produces.
+    fn check_expression(expression: &[Element], expected: Result<Value, Error>) {
+        let mut space = [0u8; 256];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert(&Element::Function {
+                name: "foo",
+                params: Params::NONE,
+            })
+            .unwrap();
+        builder.insert(&Element::Return).unwrap();
+        for element in expression {
+            builder.insert(element).unwrap();
+        }
+        builder.insert(&Element::End).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run("foo"), expected, "{:?}", expression);
+    }
+
+    #[test]
+    fn deeply_nested_add() {
+        // Add(Add(Add(...), 1), 1), nested thousands deep
+        const DEPTH: usize = 10_000;
+        let mut space = std::vec![0u8; DEPTH * 3 + 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert(&Element::Function {
+                name: "foo",
+                params: Params::NONE,
+            })
+            .unwrap();
+        builder.insert(&Element::Return).unwrap();
+        for _ in 0..DEPTH {
+            builder.insert(&Element::Add).unwrap();
+        }
+        for _ in 0..=DEPTH {
+            builder.insert(&Element::Integer(1)).unwrap();
+        }
+        builder.insert(&Element::End).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        #[cfg(feature = "alloc")]
+        assert_eq!(p.run("foo"), Ok(Value::Integer(DEPTH as i32 + 1)));
+        #[cfg(not(feature = "alloc"))]
+        assert_eq!(p.run("foo"), Err(Error::ExpressionTooDeep));
+    }
+
+    #[test]
+    fn multiply_integers() {
+        check_expression(
+            &[Element::Multiply, Element::Integer(-6), Element::Integer(7)],
+            Ok(Value::Integer(-42)),
+        );
+    }
+
+    #[test]
+    fn return_string_literal() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        for element in &[
+            Element::Function {
+                name: "foo",
+                params: Params::NONE,
+            },
+            Element::Return,
+            Element::StringLiteral("Hello £"),
+            Element::End,
+        ] {
+            builder.insert(element).unwrap();
+        }
+        let used = builder.used();
+        // The value borrows from the program data, not the `Program`
+        let value = {
+            let p = Program::new(&space[0..used]);
+            p.run("foo")
+        };
+        assert_eq!(value, Ok(Value::StringLiteral("Hello £")));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn add_strings() {
+        check_expression(
+            &[
+                Element::Add,
+                Element::StringLiteral("foo"),
+                Element::StringLiteral("bar"),
+            ],
+            Ok(Value::String("foobar".to_string())),
+        );
+        // Owned strings can be added too
+        check_expression(
+            &[
+                Element::Add,
+                Element::Add,
+                Element::StringLiteral("a"),
+                Element::StringLiteral("b"),
+                Element::StringLiteral("c"),
+            ],
+            Ok(Value::String("abc".to_string())),
+        );
+    }
+
+    #[cfg(not(feature = "alloc"))]
+    #[test]
+    fn add_strings_without_alloc() {
+        check_expression(
+            &[
+                Element::Add,
+                Element::StringLiteral("foo"),
+                Element::StringLiteral("bar"),
+            ],
+            Err(Error::TypeMismatch(ValueKind::String, ValueKind::String)),
+        );
+        check_expression(
+            &[
+                Element::Multiply,
+                Element::StringLiteral("foo"),
+                Element::Integer(2),
+            ],
+            Err(Error::TypeMismatch(ValueKind::String, ValueKind::Integer)),
+        );
+    }
+
+    #[test]
+    fn add_string_and_integer() {
+        check_expression(
+            &[
+                Element::Add,
+                Element::StringLiteral("foo"),
+                Element::Integer(1),
+            ],
+            Err(Error::TypeMismatch(ValueKind::String, ValueKind::Integer)),
+        );
+        check_expression(
+            &[
+                Element::Add,
+                Element::Integer(1),
+                Element::StringLiteral("foo"),
+            ],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::String)),
+        );
+    }
+
+    #[test]
+    fn float_arithmetic() {
+        check_expression(
+            &[Element::Add, Element::Float(1.5), Element::Float(2.25)],
+            Ok(Value::Float(3.75)),
+        );
+        check_expression(
+            &[Element::Subtract, Element::Float(1.5), Element::Float(2.25)],
+            Ok(Value::Float(-0.75)),
+        );
+        check_expression(
+            &[Element::Multiply, Element::Float(1.5), Element::Float(-2.0)],
+            Ok(Value::Float(-3.0)),
+        );
+        check_expression(
+            &[Element::Divide, Element::Float(3.0), Element::Float(2.0)],
+            Ok(Value::Float(1.5)),
+        );
+        check_expression(
+            &[Element::Negate, Element::Float(3.0)],
+            Ok(Value::Float(-3.0)),
+        );
+    }
+
+    #[test]
+    fn float_divide_by_zero() {
+        check_expression(
+            &[Element::Divide, Element::Float(1.0), Element::Float(0.0)],
+            Ok(Value::Float(f32::INFINITY)),
+        );
+        check_expression(
+            &[Element::Divide, Element::Float(-1.0), Element::Float(0.0)],
+            Ok(Value::Float(f32::NEG_INFINITY)),
+        );
+    }
+
+    #[test]
+    fn mixed_integer_float() {
+        check_expression(
+            &[Element::Add, Element::Integer(1), Element::Float(1.0)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Float)),
+        );
+        check_expression(
+            &[Element::Divide, Element::Float(1.0), Element::Integer(1)],
+            Err(Error::TypeMismatch(ValueKind::Float, ValueKind::Integer)),
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn repeat_string() {
+        check_expression(
+            &[
+                Element::Multiply,
+                Element::StringLiteral("ab"),
+                Element::Integer(3),
+            ],
+            Ok(Value::String("ababab".to_string())),
+        );
+        check_expression(
+            &[
+                Element::Multiply,
+                Element::StringLiteral("x"),
+                Element::Integer(0),
+            ],
+            Ok(Value::String(String::new())),
+        );
+        check_expression(
+            &[
+                Element::Multiply,
+                Element::StringLiteral("x"),
+                Element::Integer(-5),
+            ],
+            Ok(Value::String(String::new())),
+        );
+        check_expression(
+            &[
+                Element::Multiply,
+                Element::Integer(3),
+                Element::StringLiteral("ab"),
+            ],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::String)),
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn repeat_string_too_large() {
+        check_expression(
+            &[
+                Element::Multiply,
+                Element::StringLiteral("x"),
+                Element::Integer(MAX_STRING_LENGTH as i32),
+            ],
+            Ok(Value::String("x".repeat(MAX_STRING_LENGTH))),
+        );
+        check_expression(
+            &[
+                Element::Multiply,
+                Element::StringLiteral("ab"),
+                Element::Integer(MAX_STRING_LENGTH as i32),
+            ],
+            Err(Error::AllocationTooLarge),
+        );
+        check_expression(
+            &[
+                Element::Multiply,
+                Element::StringLiteral("ab"),
+                Element::Integer(i32::MAX),
+            ],
+            Err(Error::AllocationTooLarge),
+        );
+    }
+
+    #[test]
+    fn bitwise_integers() {
+        check_expression(
+            &[
+                Element::BitAnd,
+                Element::Integer(0b1100),
+                Element::Integer(0b1010),
+            ],
+            Ok(Value::Integer(0b1000)),
+        );
+        check_expression(
+            &[
+                Element::BitOr,
+                Element::Integer(0b1100),
+                Element::Integer(0b1010),
+            ],
+            Ok(Value::Integer(0b1110)),
+        );
+        check_expression(
+            &[
+                Element::BitXor,
+                Element::Integer(0b1100),
+                Element::Integer(0b1010),
+            ],
+            Ok(Value::Integer(0b0110)),
+        );
+        check_expression(
+            &[
+                Element::BitAnd,
+                Element::Integer(-1),
+                Element::Integer(i32::MIN),
+            ],
+            Ok(Value::Integer(i32::MIN)),
+        );
+        check_expression(
+            &[Element::BitOr, Element::Float(1.0), Element::Integer(1)],
+            Err(Error::TypeMismatch(ValueKind::Float, ValueKind::Integer)),
+        );
+        check_expression(
+            &[
+                Element::BitXor,
+                Element::Integer(1),
+                Element::StringLiteral("x"),
+            ],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::String)),
+        );
+    }
+
+    #[test]
+    fn shift_integers() {
+        check_expression(
+            &[Element::ShiftLeft, Element::Integer(1), Element::Integer(4)],
+            Ok(Value::Integer(16)),
+        );
+        check_expression(
+            &[
+                Element::ShiftRight,
+                Element::Integer(-8),
+                Element::Integer(1),
+            ],
+            Ok(Value::Integer(-4)),
+        );
+        // Bits fall off the top
+        check_expression(
+            &[
+                Element::ShiftLeft,
+                Element::Integer(3),
+                Element::Integer(31),
+            ],
+            Ok(Value::Integer(i32::MIN)),
+        );
+        check_expression(
+            &[
+                Element::ShiftRight,
+                Element::Integer(i32::MIN),
+                Element::Integer(31),
+            ],
+            Ok(Value::Integer(-1)),
+        );
+    }
+
+    #[test]
+    fn compare_integers() {
+        let cases = [
+            (Element::Equal, [false, true, false]),
+            (Element::NotEqual, [true, false, true]),
+            (Element::Less, [true, false, false]),
+            (Element::LessEqual, [true, true, false]),
+            (Element::Greater, [false, false, true]),
+            (Element::GreaterEqual, [false, true, true]),
+        ];
+        for (element, expected) in cases.iter() {
+            for (b, expected) in [5, 3, 1].iter().zip(expected.iter()) {
+                check_expression(
+                    &[element.clone(), Element::Integer(3), Element::Integer(*b)],
+                    Ok(Value::Boolean(*expected)),
+                );
+            }
+        }
+        // 3 < 5 == true
+        check_expression(
+            &[
+                Element::Equal,
+                Element::Less,
+                Element::Integer(3),
+                Element::Integer(5),
+                Element::Boolean(true),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+    }
+
+    #[test]
+    fn compare_floats() {
+        check_expression(
+            &[Element::Less, Element::Float(1.5), Element::Float(2.5)],
+            Ok(Value::Boolean(true)),
+        );
+        check_expression(
+            &[
+                Element::GreaterEqual,
+                Element::Float(1.5),
+                Element::Float(2.5),
+            ],
+            Ok(Value::Boolean(false)),
+        );
+        // NaN isn't equal to anything, not even itself
+        check_expression(
+            &[
+                Element::Equal,
+                Element::Divide,
+                Element::Float(0.0),
+                Element::Float(0.0),
+                Element::Float(1.0),
+            ],
+            Ok(Value::Boolean(false)),
+        );
+        check_expression(
+            &[
+                Element::NotEqual,
+                Element::Divide,
+                Element::Float(0.0),
+                Element::Float(0.0),
+                Element::Float(1.0),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+    }
+
+    #[test]
+    fn compare_strings() {
+        check_expression(
+            &[
+                Element::Equal,
+                Element::StringLiteral("hello"),
+                Element::StringLiteral("hello"),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+        check_expression(
+            &[
+                Element::Equal,
+                Element::StringLiteral("hello"),
+                Element::StringLiteral("world"),
+            ],
+            Ok(Value::Boolean(false)),
+        );
+        check_expression(
+            &[
+                Element::Less,
+                Element::StringLiteral("apple"),
+                Element::StringLiteral("banana"),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+        // A String made at run-time compares like a literal
+        #[cfg(feature = "alloc")]
+        check_expression(
+            &[
+                Element::Equal,
+                Element::Add,
+                Element::StringLiteral("hel"),
+                Element::StringLiteral("lo"),
+                Element::StringLiteral("hello"),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+    }
+
+    #[test]
+    fn element_boolean() {
+        assert_eq!(Ok(Element::Boolean(true)), "true".try_into());
+        assert_eq!(Ok(Element::Boolean(false)), "false".try_into());
+        assert_eq!(Element::Boolean(true).to_string(), "true");
+        assert_eq!(Element::Boolean(false).to_string(), "false");
+        for b in &[false, true] {
+            let mut space = [0u8; 1];
+            let mut builder = ProgramBuilder::new(&mut space);
+            builder.insert(&Element::Boolean(*b)).unwrap();
+            let p = Program::new(&space);
+            assert_eq!(p.iter_statements(0).next(), Some((0, Element::Boolean(*b))));
+        }
+    }
+
+    #[test]
+    fn logical_operations() {
+        // true && false == false
+        check_expression(
+            &[
+                Element::Equal,
+                Element::And,
+                Element::Boolean(true),
+                Element::Boolean(false),
+                Element::Boolean(false),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+        for (a, b) in &[(false, false), (false, true), (true, false), (true, true)] {
+            check_expression(
+                &[Element::And, Element::Boolean(*a), Element::Boolean(*b)],
+                Ok(Value::Boolean(*a && *b)),
+            );
+            check_expression(
+                &[Element::Or, Element::Boolean(*a), Element::Boolean(*b)],
+                Ok(Value::Boolean(*a || *b)),
+            );
+        }
+        check_expression(
+            &[Element::Not, Element::Boolean(true)],
+            Ok(Value::Boolean(false)),
+        );
+        check_expression(
+            &[Element::Not, Element::Integer(1)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Integer)),
+        );
+        check_expression(
+            &[Element::And, Element::Boolean(true), Element::Integer(1)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Integer)),
+        );
+    }
+
+    #[test]
+    fn logical_short_circuit() {
+        // The right-hand side would fail if it was evaluated
+        check_expression(
+            &[
+                Element::And,
+                Element::Boolean(false),
+                Element::Divide,
+                Element::Integer(1),
+                Element::Integer(0),
+            ],
+            Ok(Value::Boolean(false)),
+        );
+        check_expression(
+            &[
+                Element::Or,
+                Element::Boolean(true),
+                Element::Call("missing", 1),
+                Element::Variable("x"),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+        // Evaluation carries on after the skipped expression
+        check_expression(
+            &[
+                Element::Or,
+                Element::And,
+                Element::Boolean(false),
+                Element::Not,
+                Element::Variable("x"),
+                Element::Boolean(true),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+    }
+
+    #[test]
+    fn boolean_conditions() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::If,
+                Element::Less,
+                Element::Integer(1),
+                Element::Integer(2),
+                Element::Return,
+                Element::StringLiteral("yes"),
+                Element::End,
+                Element::Return,
+                Element::StringLiteral("no"),
+                Element::End,
+            ],
+            Ok(Value::StringLiteral("yes")),
+        );
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::While,
+                Element::Boolean(false),
+                Element::Return,
+                Element::Integer(1),
+                Element::End,
+                Element::Return,
+                Element::Integer(2),
+                Element::End,
+            ],
+            Ok(Value::Integer(2)),
+        );
+    }
+
+    #[test]
+    fn element_vector() {
+        assert_eq!(Ok(Element::Vector(3)), "vec 3".try_into());
+        assert_eq!(Element::Vector(3).to_string(), "vec 3");
+        assert_eq!(
+            Err::<Element, Error>(Error::SyntaxError(4)),
+            "vec x".try_into()
+        );
+        assert_eq!(
+            Err::<Element, Error>(Error::SyntaxError(4)),
+            "vec 256".try_into()
+        );
+        let mut space = [0u8; 2];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.insert(&Element::Vector(200)).unwrap();
+        let p = Program::new(&space);
+        assert_eq!(p.iter_statements(0).next(), Some((0, Element::Vector(200))));
+        assert_eq!(Ok(Element::Index), "[]".try_into());
+        assert_eq!(Element::Index.to_string(), "[]");
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn vector_literal() {
+        check_expression(
+            &[
+                Element::Vector(3),
+                Element::Integer(1),
+                Element::Integer(2),
+                Element::Integer(3),
+            ],
+            Ok(Value::Vector(std::vec![
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Integer(3),
+            ])),
+        );
+        check_expression(&[Element::Vector(0)], Ok(Value::Vector(Vec::new())));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn vector_index() {
+        // [1,2,3][0] == 1
+        check_expression(
+            &[
+                Element::Equal,
+                Element::Index,
+                Element::Vector(3),
+                Element::Integer(1),
+                Element::Integer(2),
+                Element::Integer(3),
+                Element::Integer(0),
+                Element::Integer(1),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+        // [1,2,3][-1] == 3
+        check_expression(
+            &[
+                Element::Equal,
+                Element::Index,
+                Element::Vector(3),
+                Element::Integer(1),
+                Element::Integer(2),
+                Element::Integer(3),
+                Element::Integer(-1),
+                Element::Integer(3),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+        for i in &[3, -4, i32::MAX, i32::MIN] {
+            check_expression(
+                &[
+                    Element::Index,
+                    Element::Vector(3),
+                    Element::Integer(1),
+                    Element::Integer(2),
+                    Element::Integer(3),
+                    Element::Integer(*i),
+                ],
+                Err(Error::IndexOutOfBounds),
+            );
+        }
+        check_expression(
+            &[
+                Element::Index,
+                Element::Vector(1),
+                Element::Integer(1),
+                Element::Float(0.0),
+            ],
+            Err(Error::TypeMismatch(ValueKind::Vector, ValueKind::Float)),
+        );
+    }
+
+    #[test]
+    fn string_length() {
+        check_expression(
+            &[Element::Len, Element::StringLiteral("héllo")],
+            Ok(Value::Integer(6)),
+        );
+        check_expression(
+            &[Element::Len, Element::StringLiteral("")],
+            Ok(Value::Integer(0)),
+        );
+        check_expression(
+            &[Element::Len, Element::Integer(1)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Integer)),
+        );
+        check_expression(
+            &[Element::Len, Element::Float(1.0)],
+            Err(Error::TypeMismatch(ValueKind::Float, ValueKind::Float)),
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn vector_length() {
+        // len([1,2,3]) == 3
+        check_expression(
+            &[
+                Element::Equal,
+                Element::Len,
+                Element::Vector(3),
+                Element::Integer(1),
+                Element::Integer(2),
+                Element::Integer(3),
+                Element::Integer(3),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+        check_expression(
+            &[
+                Element::Len,
+                Element::Add,
+                Element::StringLiteral("ab"),
+                Element::StringLiteral("c"),
+            ],
+            Ok(Value::Integer(3)),
+        );
+    }
+
+    #[test]
+    fn index_mismatch() {
+        check_expression(
+            &[Element::Index, Element::Integer(1), Element::Integer(0)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Integer)),
+        );
+    }
+
+    #[cfg(not(feature = "alloc"))]
+    #[test]
+    fn vector_without_alloc() {
+        check_expression(
+            &[Element::Vector(1), Element::Integer(1)],
+            Err(Error::NoAllocator),
+        );
+    }
+
+    #[test]
+    fn compare_mismatch() {
+        check_expression(
+            &[Element::Equal, Element::Integer(1), Element::Float(1.0)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Float)),
+        );
+        check_expression(
+            &[
+                Element::Less,
+                Element::StringLiteral("1"),
+                Element::Integer(1),
+            ],
+            Err(Error::TypeMismatch(ValueKind::String, ValueKind::Integer)),
+        );
+    }
+
+    #[test]
+    fn invalid_shift() {
+        for element in &[Element::ShiftLeft, Element::ShiftRight] {
+            for amount in &[-1, 32, i32::MAX, i32::MIN] {
+                check_expression(
+                    &[
+                        element.clone(),
+                        Element::Integer(1),
+                        Element::Integer(*amount),
+                    ],
+                    Err(Error::InvalidShift),
+                );
+            }
+        }
+        check_expression(
+            &[Element::ShiftLeft, Element::Integer(1), Element::Float(1.0)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Float)),
+        );
+    }
+
+    #[test]
+    fn type_mismatch() {
+        check_expression(
+            &[
+                Element::Multiply,
+                Element::Float(1.0),
+                Element::StringLiteral("foo"),
+            ],
+            Err(Error::TypeMismatch(ValueKind::Float, ValueKind::String)),
+        );
+        check_expression(
+            &[Element::Subtract, Element::Integer(1), Element::Float(1.0)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Float)),
+        );
+        check_expression(
+            &[
+                Element::Divide,
+                Element::StringLiteral("a"),
+                Element::StringLiteral("b"),
+            ],
+            Err(Error::TypeMismatch(ValueKind::String, ValueKind::String)),
+        );
+        check_expression(
+            &[Element::Negate, Element::StringLiteral("a")],
+            Err(Error::TypeMismatch(ValueKind::String, ValueKind::String)),
+        );
+    }
+
+    #[test]
+    fn subtract_integers() {
+        check_expression(
+            &[Element::Subtract, Element::Integer(3), Element::Integer(5)],
+            Ok(Value::Integer(-2)),
+        );
+        check_expression(
+            &[
+                Element::Subtract,
+                Element::Integer(i32::MIN),
+                Element::Integer(1),
+            ],
+            Err(Error::ArithmeticOverflow),
+        );
+    }
+
+    #[test]
+    fn negate_integers() {
+        check_expression(
+            &[Element::Negate, Element::Integer(5)],
+            Ok(Value::Integer(-5)),
+        );
+        check_expression(
+            &[Element::Negate, Element::Integer(i32::MAX)],
+            Ok(Value::Integer(-i32::MAX)),
+        );
+        check_expression(
+            &[Element::Negate, Element::Integer(i32::MIN)],
+            Err(Error::ArithmeticOverflow),
+        );
+    }
+
+    #[test]
+    fn abs_values() {
+        check_expression(&[Element::Abs, Element::Integer(-5)], Ok(Value::Integer(5)));
+        check_expression(&[Element::Abs, Element::Integer(5)], Ok(Value::Integer(5)));
+        check_expression(
+            &[Element::Abs, Element::Integer(i32::MIN + 1)],
+            Ok(Value::Integer(i32::MAX)),
+        );
+        check_expression(
+            &[Element::Abs, Element::Integer(i32::MIN)],
+            Err(Error::ArithmeticOverflow),
+        );
+        check_expression(&[Element::Abs, Element::Float(-1.5)], Ok(Value::Float(1.5)));
+        check_expression(&[Element::Abs, Element::Long(-1)], Ok(Value::Long(1)));
+        check_expression(
+            &[Element::Abs, Element::StringLiteral("-1")],
+            Err(Error::TypeMismatch(ValueKind::String, ValueKind::String)),
+        );
+    }
+
+    #[test]
+    fn min_max_values() {
+        let check = |a: Element<'static>, b: Element<'static>, min: Value, max: Value| {
+            check_expression(&[Element::Min, a.clone(), b.clone()], Ok(min));
+            check_expression(&[Element::Max, a, b], Ok(max));
+        };
+        check(
+            Element::Integer(3),
+            Element::Integer(-7),
+            Value::Integer(-7),
+            Value::Integer(3),
+        );
+        check(
+            Element::Integer(2),
+            Element::Integer(2),
+            Value::Integer(2),
+            Value::Integer(2),
+        );
+        check(
+            Element::Float(0.5),
+            Element::Float(1.5),
+            Value::Float(0.5),
+            Value::Float(1.5),
+        );
+        check(
+            Element::Long(1 << 40),
+            Element::Long(-1),
+            Value::Long(-1),
+            Value::Long(1 << 40),
+        );
+        // No mixing types
+        check_expression(
+            &[Element::Min, Element::Integer(1), Element::Float(2.0)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Float)),
+        );
+        check_expression(
+            &[
+                Element::Max,
+                Element::StringLiteral("a"),
+                Element::StringLiteral("b"),
+            ],
+            Err(Error::TypeMismatch(ValueKind::String, ValueKind::String)),
+        );
+    }
+
+    #[test]
+    fn divide_integers() {
+        check_expression(
+            &[Element::Divide, Element::Integer(42), Element::Integer(6)],
+            Ok(Value::Integer(7)),
+        );
+        // Truncates towards zero
+        check_expression(
+            &[Element::Divide, Element::Integer(7), Element::Integer(2)],
+            Ok(Value::Integer(3)),
+        );
+        check_expression(
+            &[Element::Divide, Element::Integer(-7), Element::Integer(2)],
+            Ok(Value::Integer(-3)),
+        );
+    }
+
+    #[test]
+    fn divide_by_zero() {
+        check_expression(
+            &[Element::Divide, Element::Integer(1), Element::Integer(0)],
+            Err(Error::DivideByZero),
+        );
+    }
+
+    #[test]
+    fn divide_overflow() {
+        check_expression(
+            &[
+                Element::Divide,
+                Element::Integer(i32::MIN),
+                Element::Integer(-1),
+            ],
+            Err(Error::ArithmeticOverflow),
+        );
+    }
+
+    #[test]
+    fn modulo_integers() {
+        // 7 % 3 == 1
+        check_expression(
+            &[
+                Element::Equal,
+                Element::Modulo,
+                Element::Integer(7),
+                Element::Integer(3),
+                Element::Integer(1),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+        // Takes the sign of the first operand
+        check_expression(
+            &[Element::Modulo, Element::Integer(-7), Element::Integer(3)],
+            Ok(Value::Integer(-1)),
+        );
+        check_expression(
+            &[Element::Modulo, Element::Integer(7), Element::Integer(-3)],
+            Ok(Value::Integer(1)),
+        );
+        check_expression(
+            &[Element::Modulo, Element::Integer(1), Element::Integer(0)],
+            Err(Error::DivideByZero),
+        );
+        check_expression(
+            &[
+                Element::Modulo,
+                Element::Integer(i32::MIN),
+                Element::Integer(-1),
+            ],
+            Err(Error::ArithmeticOverflow),
+        );
+        check_expression(
+            &[Element::Modulo, Element::Float(7.0), Element::Float(3.0)],
+            Err(Error::TypeMismatch(ValueKind::Float, ValueKind::Float)),
+        );
+    }
+
+    #[test]
+    fn power_integers() {
+        // 2 ** 10 == 1024
+        check_expression(
+            &[
+                Element::Equal,
+                Element::Pow,
+                Element::Integer(2),
+                Element::Integer(10),
+                Element::Integer(1024),
+            ],
+            Ok(Value::Boolean(true)),
+        );
+        check_expression(
+            &[Element::Pow, Element::Integer(-3), Element::Integer(3)],
+            Ok(Value::Integer(-27)),
+        );
+        check_expression(
+            &[Element::Pow, Element::Integer(5), Element::Integer(0)],
+            Ok(Value::Integer(1)),
+        );
+        check_expression(
+            &[Element::Pow, Element::Integer(2), Element::Integer(31)],
+            Err(Error::ArithmeticOverflow),
+        );
+        check_expression(
+            &[Element::Pow, Element::Integer(2), Element::Integer(-1)],
+            Err(Error::NegativeExponent),
+        );
+        check_expression(
+            &[Element::Pow, Element::Integer(2), Element::Float(2.0)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Float)),
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn power_floats() {
+        check_expression(
+            &[Element::Pow, Element::Float(2.0), Element::Float(0.5)],
+            Ok(Value::Float(core::f32::consts::SQRT_2)),
+        );
+        check_expression(
+            &[Element::Pow, Element::Float(2.0), Element::Float(-1.0)],
+            Ok(Value::Float(0.5)),
+        );
+    }
+
+    #[cfg(any(feature = "std", feature = "math"))]
+    #[test]
+    fn float_math() {
+        let eval = |op: Element, x: f32| {
+            let mut space = [0u8; 32];
+            let mut builder = ProgramBuilder::new(&mut space);
+            builder
+                .insert_all(&[
+                    Element::Function {
+                        name: "foo",
+                        params: Params::NONE,
+                    },
+                    Element::Return,
+                    op,
+                    Element::Float(x),
+                    Element::End,
+                ])
+                .unwrap();
+            let used = builder.used();
+            match Program::new(&space[0..used]).run("foo") {
+                Ok(Value::Float(f)) => f,
+                other => panic!("{:?}", other),
+            }
+        };
+        assert!((eval(Element::Sqrt, 2.0) - core::f32::consts::SQRT_2).abs() < 1e-6);
+        assert!((eval(Element::Sqrt, 9.0) - 3.0).abs() < 1e-6);
+        assert!(eval(Element::Sqrt, -1.0).is_nan());
+        assert!(eval(Element::Sin, 0.0).abs() < 1e-6);
+        assert!((eval(Element::Sin, core::f32::consts::FRAC_PI_2) - 1.0).abs() < 1e-6);
+        assert!((eval(Element::Cos, 0.0) - 1.0).abs() < 1e-6);
+        assert!((eval(Element::Cos, core::f32::consts::PI) + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn float_math_types() {
+        check_expression(
+            &[Element::Sqrt, Element::Integer(4)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Integer)),
+        );
+        // Without `std` or `math` there's nothing to do the sums
+        #[cfg(not(any(feature = "std", feature = "math")))]
+        check_expression(
+            &[Element::Sin, Element::Float(0.0)],
+            Err(Error::TypeMismatch(ValueKind::Float, ValueKind::Float)),
+        );
+    }
+
+    #[test]
+    fn convert_to_float() {
+        check_expression(
+            &[Element::ToFloat, Element::Integer(-3)],
+            Ok(Value::Float(-3.0)),
+        );
+        check_expression(
+            &[
+                Element::Add,
+                Element::ToFloat,
+                Element::Integer(1),
+                Element::Float(0.5),
+            ],
+            Ok(Value::Float(1.5)),
+        );
+        check_expression(
+            &[Element::ToFloat, Element::Float(1.0)],
+            Err(Error::TypeMismatch(ValueKind::Float, ValueKind::Float)),
+        );
+    }
+
+    #[test]
+    fn convert_to_int() {
+        // Rounds towards zero
+        check_expression(
+            &[Element::ToInt, Element::Float(2.75)],
+            Ok(Value::Integer(2)),
+        );
+        check_expression(
+            &[Element::ToInt, Element::Float(-2.75)],
+            Ok(Value::Integer(-2)),
+        );
+        // The largest Floats that fit
+        check_expression(
+            &[Element::ToInt, Element::Float(-2147483648.0)],
+            Ok(Value::Integer(i32::MIN)),
+        );
+        check_expression(
+            &[Element::ToInt, Element::Float(2147483520.0)],
+            Ok(Value::Integer(2147483520)),
+        );
+        for f in &[2147483648.0, -2147483904.0] {
+            check_expression(
+                &[Element::ToInt, Element::Float(*f)],
+                Err(Error::ArithmeticOverflow),
+            );
+        }
+        // Can't be inserted, but can be made while running
+        for f in &[f32::INFINITY, f32::NAN] {
+            assert_eq!(Value::Float(*f).to_int(), Err(Error::ArithmeticOverflow));
+        }
+        check_expression(
+            &[Element::ToInt, Element::Integer(1)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Integer)),
+        );
+    }
+
+    #[test]
+    fn integer_overflow() {
+        for expression in &[
+            [
+                Element::Add,
+                Element::Integer(i32::MAX),
+                Element::Integer(1),
+            ],
+            [
+                Element::Add,
+                Element::Integer(i32::MIN),
+                Element::Integer(-1),
+            ],
+            [
+                Element::Multiply,
+                Element::Integer(i32::MAX),
+                Element::Integer(2),
+            ],
+            [
+                Element::Multiply,
+                Element::Integer(i32::MIN),
+                Element::Integer(-1),
+            ],
+        ] {
+            check_expression(expression, Err(Error::ArithmeticOverflow));
+        }
+        // Right on the boundaries is fine
+        check_expression(
+            &[
+                Element::Add,
+                Element::Integer(i32::MAX - 1),
+                Element::Integer(1),
+            ],
+            Ok(Value::Integer(i32::MAX)),
+        );
+        check_expression(
+            &[
+                Element::Add,
+                Element::Integer(i32::MIN + 1),
+                Element::Integer(-1),
+            ],
+            Ok(Value::Integer(i32::MIN)),
+        );
+    }
+
+    /// Build a program from the given elements, then check what running the
+    /// function `foo` produces.
+    fn check_program(elements: &[Element], expected: Result<Value, Error>) {
+        let mut space = [0u8; 256];
+        let mut builder = ProgramBuilder::new(&mut space);
+        for element in elements {
+            builder.insert(element).unwrap();
+        }
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run("foo"), expected, "{:?}", elements);
+    }
+
+    #[test]
+    fn if_statement() {
+        for (condition, expected) in &[(1, Value::Integer(10)), (0, Value::Integer(20))] {
+            check_program(
+                &[
+                    Element::Function {
+                        name: "foo",
+                        params: Params::NONE,
+                    },
+                    Element::If,
+                    Element::Integer(*condition),
+                    Element::Return,
+                    Element::Integer(10),
+                    Element::End,
+                    Element::Return,
+                    Element::Integer(20),
+                    Element::End,
+                ],
+                Ok(expected.clone()),
+            );
+        }
+    }
+
+    #[test]
+    fn if_statement_nested() {
+        // The skipped block contains another block, whose `End` must not
+        // be mistaken for the end of the outer block.
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::If,
+                Element::Integer(0),
+                Element::If,
+                Element::Integer(1),
+                Element::Return,
+                Element::Integer(10),
+                Element::End,
+                Element::Return,
+                Element::Integer(20),
+                Element::End,
+                Element::Return,
+                Element::Integer(30),
+                Element::End,
+            ],
+            Ok(Value::Integer(30)),
+        );
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::If,
+                Element::Integer(-1),
+                Element::If,
+                Element::Integer(0),
+                Element::Return,
+                Element::Integer(10),
+                Element::End,
+                Element::Nop,
+                Element::End,
+                Element::Return,
+                Element::Integer(30),
+                Element::End,
+            ],
+            Ok(Value::Integer(30)),
+        );
+    }
+
+    #[test]
+    fn match_statement() {
+        let source = "\
+fn classify(x)
+    match
+    x
+    case
+    1
+        return
+        \"one\"
+    case
+    +
+    1
+    1
+        return
+        \"two\"
+    else
+        return
+        \"many\"
+    end
+end
+fn count_ones
+    let total
+    0
+    for i
+    1
+    7
+        match
+        %
+        i
+        3
+        case
+        1
+            let total
+            +
+            total
+            1
+        case
+        2
+            continue
+        end
+    end
+    return
+    total
+end
+";
+        let mut space = [0u8; 192];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.validate(), Ok(()));
+        for (arg, expected) in [(1, "one"), (2, "two"), (3, "many"), (-1, "many")] {
+            assert_eq!(
+                p.run_with_args("classify", &[Value::Integer(arg)]),
+                Ok(Value::StringLiteral(expected))
+            );
+        }
+        // No `Else`, so a value that matches nothing runs no arm
+        assert_eq!(p.run("count_ones"), Ok(Value::Integer(3)));
+        let mut interpreter = p.interpreter("count_ones").unwrap();
+        let result = loop {
+            match interpreter.step().unwrap() {
+                StepResult::Continue => {}
+                other => break other,
+            }
+        };
+        assert_eq!(result, StepResult::Returned(Value::Integer(3)));
+    }
+
+    #[test]
+    fn match_stray_elements() {
+        for source in [
+            // A statement before the first arm
+            "fn foo\nmatch\n1\nreturn\n1\ncase\n1\nend\nend\n",
+            // A `Case` after the `Else`
+            "fn foo\nmatch\n1\nelse\ncase\n1\nend\nend\n",
+            // A `Case` outside a `Match`
+            "fn foo\ncase\n1\nend\n",
+        ] {
+            let mut space = [0u8; 64];
+            let mut builder = ProgramBuilder::new(&mut space);
+            builder.assemble(source).unwrap();
+            let used = builder.used();
+            let p = Program::new(&space[0..used]);
+            assert!(matches!(p.validate(), Err(Error::SequenceError(_))));
+        }
+    }
+
+    #[test]
+    fn if_else_statement() {
+        for (condition, expected) in &[(1, Value::Integer(10)), (0, Value::Integer(20))] {
+            check_program(
+                &[
+                    Element::Function {
+                        name: "foo",
+                        params: Params::NONE,
+                    },
+                    Element::If,
+                    Element::Integer(*condition),
+                    Element::Return,
+                    Element::Integer(10),
+                    Element::Else,
+                    Element::Return,
+                    Element::Integer(20),
+                    Element::End,
+                    Element::Return,
+                    Element::Integer(30),
+                    Element::End,
+                ],
+                Ok(expected.clone()),
+            );
+        }
+    }
+
+    #[test]
+    fn if_else_fall_through() {
+        // Neither branch returns, so we carry on after the `End`
+        for condition in &[0, 1] {
+            check_program(
+                &[
+                    Element::Function {
+                        name: "foo",
+                        params: Params::NONE,
+                    },
+                    Element::If,
+                    Element::Integer(*condition),
+                    Element::Nop,
+                    Element::Else,
+                    Element::Nop,
+                    Element::End,
+                    Element::Return,
+                    Element::Integer(30),
+                    Element::End,
+                ],
+                Ok(Value::Integer(30)),
+            );
+        }
+    }
+
+    #[test]
+    fn if_else_nested() {
+        // Each inner `if` has its own `else`, which mustn't be mistaken for
+        // the outer one when skipping.
+        for (outer, inner, expected) in &[
+            (1, 1, Value::Integer(1)),
+            (1, 0, Value::Integer(2)),
+            (0, 1, Value::Integer(3)),
+            (0, 0, Value::Integer(4)),
+        ] {
+            check_program(
+                &[
+                    Element::Function {
+                        name: "foo",
+                        params: Params::NONE,
+                    },
+                    Element::If,
+                    Element::Integer(*outer),
+                    Element::If,
+                    Element::Integer(*inner),
+                    Element::Return,
+                    Element::Integer(1),
+                    Element::Else,
+                    Element::Return,
+                    Element::Integer(2),
+                    Element::End,
+                    Element::Else,
+                    Element::If,
+                    Element::Integer(*inner),
+                    Element::Return,
+                    Element::Integer(3),
+                    Element::Else,
+                    Element::Return,
+                    Element::Integer(4),
+                    Element::End,
+                    Element::End,
+                    Element::End,
+                ],
+                Ok(expected.clone()),
+            );
+        }
+    }
+
+    #[test]
+    fn if_else_else() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::If,
+                Element::Integer(0),
+                Element::Else,
+                Element::Else,
+                Element::End,
+                Element::End,
+            ],
+            Err(Error::SequenceError(10)),
+        );
+    }
+
+    #[test]
+    fn while_false() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::While,
+                Element::Integer(0),
+                Element::Return,
+                Element::Integer(10),
+                Element::End,
+                Element::Return,
+                Element::Integer(20),
+                Element::End,
+            ],
+            Ok(Value::Integer(20)),
+        );
+    }
+
+    #[test]
+    fn while_break() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::While,
+                Element::Integer(1),
+                Element::If,
+                Element::Integer(1),
+                Element::Break,
+                Element::End,
+                Element::Return,
+                Element::Integer(10),
+                Element::End,
+                Element::Return,
+                Element::Integer(20),
+                Element::End,
+            ],
+            Ok(Value::Integer(20)),
+        );
+    }
+
+    #[test]
+    fn while_nested_break() {
+        // Only the inner loop is exited
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::While,
+                Element::Integer(1),
+                Element::While,
+                Element::Integer(1),
+                Element::Break,
+                Element::End,
+                Element::Return,
+                Element::Integer(10),
+                Element::End,
+                Element::Return,
+                Element::Integer(20),
+                Element::End,
+            ],
+            Ok(Value::Integer(10)),
+        );
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Break,
+                Element::End,
+            ],
+            Err(Error::SequenceError(6)),
+        );
+    }
+
+    #[test]
+    fn while_iteration_limit() {
+        let data = [
+            Program::FUNCTION_ID,
+            0x03,
+            b'f',
+            b'o',
+            b'o',
+            0x00,
+            Program::WHILE_ID,
+            Program::INTEGER1_ID,
+            0x01,
+            Program::NOP_ID,
+            Program::END_ID,
+            Program::END_ID,
+        ];
+        let p = Program::new(&data);
+        assert_eq!(
+            p.run_with_limit("foo", 100),
+            Err(Error::IterationLimitExceeded)
+        );
+        assert_eq!(
+            p.run_with_limit("bar", 100),
+            Err(Error::function_not_found("bar"))
+        );
+    }
+
+    #[test]
+    fn insert_function_offsets() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new_with_options(&mut space, Endianness::Big).unwrap();
+        let first = builder.insert_function("first", Params::NONE).unwrap();
+        builder
+            .insert_all(&[Element::Return, Element::Integer(1), Element::End])
+            .unwrap();
+        let second = builder
+            .insert_function("second", Params::new("a, b").unwrap())
+            .unwrap();
+        builder.insert(&Element::End).unwrap();
+        assert_eq!(
+            builder.insert_function("first", Params::NONE),
+            Err(Error::DuplicateFunction)
+        );
+        let used = builder.used();
+        let p = Program::try_new(&space[0..used]).unwrap();
+        assert_eq!(p.function_index("first"), Some(first));
+        assert_eq!(p.function_index("second"), Some(second));
+        assert_eq!(p.run_at(first), Ok(Value::Integer(1)));
+    }
+
+    #[test]
+    fn run_into() {
+        let source = "\
+fn greet
+    let name
+    \"world\"
+    return
+    +
+    +
+    \"Hello, \"
+    name
+    \"!\"
+end
+";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut scratch = [0u8; 32];
+        assert_eq!(
+            p.run_into("greet", &mut scratch),
+            Ok(Value::StringLiteral("Hello, world!"))
+        );
+        // "Hello, world" is made first, and then the whole thing
+        assert_eq!(&scratch[0..12], b"Hello, world");
+        assert_eq!(&scratch[12..25], b"Hello, world!");
+        let mut scratch = [0u8; 16];
+        assert_eq!(
+            p.run_into("greet", &mut scratch),
+            Err(Error::InsufficientSpace)
+        );
+    }
+
+    #[test]
+    fn run_into_string_functions() {
+        let source = "\
+fn shout
+    return
+    to_upper
+    \"Hello\"
+end
+fn whisper
+    return
+    to_lower
+    \"Hello\"
+end
+fn number
+    return
+    +
+    str
+    42
+    \"!\"
+end
+fn middle
+    return
+    substring
+    \"Hello\"
+    1
+    -1
+end
+";
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut scratch = [0u8; 8];
+        assert_eq!(
+            p.run_into("shout", &mut scratch),
+            Ok(Value::StringLiteral("HELLO"))
+        );
+        assert_eq!(
+            p.run_into("whisper", &mut scratch),
+            Ok(Value::StringLiteral("hello"))
+        );
+        // "42" is made first, then "42!"
+        assert_eq!(
+            p.run_into("number", &mut scratch),
+            Ok(Value::StringLiteral("42!"))
+        );
+        assert_eq!(&scratch[0..5], b"4242!");
+        assert_eq!(
+            p.run_into("middle", &mut scratch),
+            Ok(Value::StringLiteral("ell"))
+        );
+        let mut scratch = [0u8; 4];
+        assert_eq!(
+            p.run_into("number", &mut scratch),
+            Err(Error::InsufficientSpace)
+        );
+        let mut scratch = [0u8; 1];
+        assert_eq!(
+            p.run_into("number", &mut scratch),
+            Err(Error::InsufficientSpace)
+        );
+    }
+
+    #[test]
+    fn assemble_lines() {
+        let lines = [
+            "fn main",
+            "    let x",
+            "    20",
+            "    return",
+            "    *",
+            "    x",
+            "    2",
+            "end",
+        ];
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble_lines(lines.iter().copied()).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run("main"), Ok(Value::Integer(40)));
+
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut space);
+        assert_eq!(
+            builder.assemble_lines(["fn main", "    return", "    @"].iter().copied()),
+            Err(Error::ParseError { line: 3, offset: 4 })
+        );
+        // The lines before the bad one are kept
+        assert_eq!(builder.used(), 8);
+    }
+
+    #[test]
+    fn estimate_size() {
+        let source = "\
+fn main(a, b)
+    let big
+    100000
+    let long
+    5000000000
+    let half
+    0.5
+    print
+    \"tab\\there\"
+    for i
+    -1
+    200
+        big +=
+        call other 1; i
+    end
+    return
+    vec 2
+    a
+    b
+end
+fn other(x)
+    return
+    x
+end
+";
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        assert_eq!(super::estimate_size(source), Ok(builder.used()));
+        assert_eq!(super::estimate_size(""), Ok(0));
+        assert_eq!(
+            super::estimate_size("fn main\n@\n"),
+            Err(Error::ParseError { line: 2, offset: 0 })
+        );
+        // Anything `assemble` turns down is turned down here too
+        let chain = "fn main\nwhile\n1 < 2 < 3\nend\nend\n";
+        assert_eq!(
+            super::estimate_size(chain),
+            Err(Error::ChainedComparison { line: 3 })
+        );
+        assert_eq!(
+            super::estimate_size("fn main\nprint\n1e999\n"),
+            Err(Error::ParseError { line: 3, offset: 0 })
+        );
+        let chain = "fn main\nreturn\n1 < 2 < 3\nend\n";
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(chain).unwrap();
+        assert_eq!(super::estimate_size(chain), Ok(builder.used()));
+    }
+
+    #[test]
+    fn run_main() {
+        let source = "\
+fn helper
+    return
+    1
+end
+fn main
+    return
+    +
+    call helper 0
+    2
+end
+";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run_main(), Ok(Value::Integer(3)));
+
+        let mut space = [0u8; 16];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble("fn helper\nend\n").unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run_main(), Err(Error::function_not_found("main")));
+    }
+
+    #[test]
+    fn run_budgeted() {
+        let source = "\
+fn spin
+    while
+    true
+        nop
+    end
+end
+fn foo
+    let x
+    1
+    return
+    x
+end
+";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(
+            p.run_budgeted("spin", 1000),
+            Err(Error::InstructionBudgetExceeded)
+        );
+        assert_eq!(p.run_budgeted("foo", 2), Ok(Value::Integer(1)));
+        assert_eq!(
+            p.run_budgeted("foo", 1),
+            Err(Error::InstructionBudgetExceeded)
+        );
+    }
+
+    #[test]
+    fn run_profiled() {
+        let source = "\
+fn main
+    let total
+    0
+    for i
+    1
+    3
+        total +=
+        i
+    end
+    return
+    total
+end
+";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let (value, counts) = p.run_profiled("main").unwrap();
+        assert_eq!(value, Value::Integer(6));
+        assert_eq!(counts.get(Program::ASSIGN_ID), 1);
+        assert_eq!(counts.get(Program::FOR_ID), 1);
+        assert_eq!(counts.get(Program::ADD_ASSIGN_ID), 3);
+        assert_eq!(counts.get(Program::VARIABLE_ID), 4);
+        assert_eq!(counts.get(Program::END_ID), 3);
+        assert_eq!(counts.get(Program::RETURN_ID), 1);
+        // 0, 1 and 3, each once
+        assert_eq!(counts.get(Program::INTEGER1_ID), 3);
+        assert_eq!(counts.total(), 16);
+        assert_eq!(counts.iter().count(), 7);
+        assert_eq!(
+            p.run_profiled("nope").map(|(value, _)| value),
+            Err(Error::function_not_found("nope"))
+        );
+    }
+
+    #[test]
+    fn goto() {
+        let source = "\
+fn forward
+    let x
+    1
+    goto done
+    let x
+    2
+    label done
+    return
+    x
+end
+fn backward
+    let x
+    0
+    label again
+    x +=
+    1
+    if
+    <
+    x
+    3
+        goto again
+    end
+    return
+    x
+end
+fn missing
+    while
+    true
+        goto nowhere
+    end
+end
+fn spin
+    label top
+    goto top
+end
+";
+        let mut space = [0u8; 160];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.validate(), Ok(()));
+        assert_eq!(p.run("forward"), Ok(Value::Integer(1)));
+        assert_eq!(p.run("backward"), Ok(Value::Integer(3)));
+        assert_eq!(p.run("missing"), Err(Error::LabelNotFound));
+        assert_eq!(
+            p.run_budgeted("spin", 1000),
+            Err(Error::InstructionBudgetExceeded)
+        );
+        assert_eq!(
+            p.run_with_limit("spin", 1000),
+            Err(Error::IterationLimitExceeded)
+        );
+        // Jumps back twice, and jumping forwards doesn't count
+        assert_eq!(p.run_with_limit("backward", 2), Ok(Value::Integer(3)));
+        assert_eq!(
+            p.run_with_limit("backward", 1),
+            Err(Error::IterationLimitExceeded)
+        );
+        assert_eq!(p.run_with_limit("forward", 0), Ok(Value::Integer(1)));
+        // The interpreter leaves the `If` when it jumps back
+        let mut interpreter = p.interpreter("backward").unwrap();
+        let result = loop {
+            match interpreter.step().unwrap() {
+                StepResult::Continue => {}
+                other => break other,
+            }
+        };
+        assert_eq!(result, StepResult::Returned(Value::Integer(3)));
+    }
+
+    #[test]
+    fn for_loop() {
+        // Skip every go round but the last, which returns the loop variable
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::For("i"),
+                Element::Integer(1),
+                Element::Integer(5),
+                Element::If,
+                Element::Subtract,
+                Element::Variable("i"),
+                Element::Integer(5),
+                Element::Continue,
+                Element::End,
+                Element::Return,
+                Element::Variable("i"),
+                Element::End,
+                Element::Return,
+                Element::Integer(0),
+                Element::End,
+            ],
+            Ok(Value::Integer(5)),
+        );
+        // A loop whose start is past its end doesn't run at all
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::For("i"),
+                Element::Integer(5),
+                Element::Integer(1),
+                Element::Return,
+                Element::Variable("i"),
+                Element::End,
+                Element::Return,
+                Element::Integer(0),
+                Element::End,
+            ],
+            Ok(Value::Integer(0)),
+        );
+    }
+
+    #[test]
+    fn for_loop_iterations() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        for element in &[
+            Element::Function {
+                name: "foo",
+                params: Params::NONE,
+            },
+            Element::For("i"),
+            Element::Integer(1),
+            Element::Integer(5),
+            Element::Nop,
+            Element::End,
+            Element::Return,
+            Element::Variable("i"),
+            Element::End,
+        ] {
+            builder.insert(element).unwrap();
+        }
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        // Goes round 5 times, leaving the variable set
+        assert_eq!(p.run_with_limit("foo", 5), Ok(Value::Integer(5)));
+        assert_eq!(
+            p.run_with_limit("foo", 4),
+            Err(Error::IterationLimitExceeded)
+        );
+    }
+
+    #[test]
+    fn for_loop_break() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::For("i"),
+                Element::Integer(-3),
+                Element::Integer(i32::MAX),
+                Element::If,
+                Element::Variable("i"),
+                Element::Continue,
+                Element::End,
+                Element::Break,
+                Element::End,
+                Element::Return,
+                Element::Variable("i"),
+                Element::End,
+            ],
+            Ok(Value::Integer(0)),
+        );
+    }
+
+    #[test]
+    fn for_loop_empty() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::For("i"),
+                Element::Integer(5),
+                Element::Integer(1),
+                Element::Return,
+                Element::Integer(1),
+                Element::End,
+                Element::Return,
+                Element::Integer(2),
+                Element::End,
+            ],
+            Ok(Value::Integer(2)),
+        );
+    }
+
+    #[test]
+    fn for_loop_bad_range() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::For("i"),
+                Element::Integer(1),
+                Element::Float(5.0),
+                Element::End,
+                Element::End,
+            ],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Float)),
+        );
+    }
+
+    #[test]
+    fn assign_variable() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Assign("x"),
+                Element::Integer(5),
+                Element::Assign("y"),
+                Element::StringLiteral("hello"),
+                Element::Assign("x"),
+                Element::Add,
+                Element::Variable("x"),
+                Element::Integer(1),
+                Element::Return,
+                Element::Variable("x"),
+                Element::End,
+            ],
+            Ok(Value::Integer(6)),
+        );
+    }
+
+    #[test]
+    fn for_loop_sum() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Assign("total"),
+                Element::Integer(0),
+                Element::For("i"),
+                Element::Integer(1),
+                Element::Integer(5),
+                Element::Assign("total"),
+                Element::Add,
+                Element::Variable("total"),
+                Element::Variable("i"),
+                Element::End,
+                Element::Return,
+                Element::Variable("total"),
+                Element::End,
+            ],
+            Ok(Value::Integer(15)),
+        );
+    }
+
+    #[test]
+    fn while_counting() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Assign("count"),
+                Element::Integer(10),
+                Element::Assign("total"),
+                Element::Integer(0),
+                Element::While,
+                Element::Variable("count"),
+                Element::Assign("total"),
+                Element::Add,
+                Element::Variable("total"),
+                Element::Integer(2),
+                Element::Assign("count"),
+                Element::Subtract,
+                Element::Variable("count"),
+                Element::Integer(1),
+                Element::End,
+                Element::Return,
+                Element::Variable("total"),
+                Element::End,
+            ],
+            Ok(Value::Integer(20)),
+        );
+    }
+
+    #[test]
+    fn too_many_variables() {
+        const NAMES: [&str; MAX_VARIABLES + 1] = [
+            "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q",
+        ];
+        let mut scope = VarScope::new();
+        for name in &NAMES[0..MAX_VARIABLES] {
+            scope.set(name, Value::Nil).unwrap();
+        }
+        // Updating existing variables is fine
+        scope.set("a", Value::Integer(1)).unwrap();
+        assert_eq!(scope.get("a"), Some(&Value::Integer(1)));
+        assert_eq!(
+            scope.set(NAMES[MAX_VARIABLES], Value::Nil),
+            Err(Error::TooManyVariables)
+        );
+    }
+
+    #[test]
+    fn run_with_scope() {
+        let source = "\
+fn store
+    let count
+    +
+    count
+    1
+    let last
+    \"stored\"
+end
+fn load
+    return
+    count
+end
+";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut scope = VarScope::new();
+        scope.set("count", Value::Integer(10)).unwrap();
+        assert_eq!(p.run_with_scope("store", &mut scope), Ok(Value::Nil));
+        assert_eq!(p.run_with_scope("store", &mut scope), Ok(Value::Nil));
+        assert_eq!(p.run_with_scope("load", &mut scope), Ok(Value::Integer(12)));
+        assert_eq!(scope.get("last"), Some(&Value::StringLiteral("stored")));
+        // A fresh scope each time forgets everything
+        assert_eq!(p.run("load"), Err(Error::UndefinedVariable));
+        scope.clear();
+        assert_eq!(scope.get("count"), None);
+        assert_eq!(
+            p.run_with_scope("load", &mut scope),
+            Err(Error::UndefinedVariable)
+        );
+    }
+
+    #[test]
+    fn call_function() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "five",
+                    params: Params::NONE,
+                },
+                Element::Return,
+                Element::Integer(5),
+                Element::End,
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Return,
+                Element::Add,
+                Element::Call("five", 0),
+                Element::Call("five", 0),
+                Element::End,
+            ],
+            Ok(Value::Integer(10)),
+        );
+    }
+
+    #[test]
+    fn call_function_scope() {
+        // Each call gets its own variables
+        check_program(
+            &[
+                Element::Function {
+                    name: "bar",
+                    params: Params::NONE,
+                },
+                Element::Assign("x"),
+                Element::Integer(5),
+                Element::End,
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Assign("x"),
+                Element::Integer(1),
+                Element::Assign("y"),
+                Element::Call("bar", 0),
+                Element::Return,
+                Element::Variable("x"),
+                Element::End,
+            ],
+            Ok(Value::Integer(1)),
+        );
+    }
+
+    #[test]
+    fn call_function_args() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "add",
+                    params: Params::new("a, b").unwrap(),
+                },
+                Element::Return,
+                Element::Add,
+                Element::Variable("a"),
+                Element::Variable("b"),
+                Element::End,
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Return,
+                Element::Call("add", 2),
+                Element::Integer(2),
+                Element::Integer(3),
+                Element::End,
+            ],
+            Ok(Value::Integer(5)),
+        );
+    }
+
+    #[test]
+    fn call_function_errors() {
+        check_expression(
+            &[Element::Call("bar", 0)],
+            Err(Error::function_not_found("bar")),
+        );
+        check_expression(
+            &[Element::Call("foo", 1), Element::Integer(1)],
+            Err(Error::ArityMismatch),
+        );
+        // Can't run a function that wants arguments
+        let elements = [
+            Element::Function {
+                name: "foo",
+                params: Params::new("a").unwrap(),
+            },
+            Element::Return,
+            Element::Variable("a"),
+            Element::End,
+        ];
+        check_program(&elements, Err(Error::ArityMismatch));
+    }
+
+    #[test]
+    fn run_with_args() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert_all(&[
+                Element::Function {
+                    name: "foo",
+                    params: Params::new("a, b").unwrap(),
+                },
+                Element::Return,
+                Element::Subtract,
+                Element::Variable("a"),
+                Element::Variable("b"),
+                Element::End,
+            ])
+            .unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(
+            p.run_with_args("foo", &[Value::Integer(10), Value::Integer(3)]),
+            Ok(Value::Integer(7))
+        );
+        assert_eq!(
+            p.run_with_args("foo", &[Value::Integer(10)]),
+            Err(Error::ArityMismatch)
+        );
+        assert_eq!(p.run("foo"), Err(Error::ArityMismatch));
+    }
+
+    #[test]
+    fn chained_comparison() {
+        assert_eq!(Element::try_from("1 < b < 3"), Err(Error::SyntaxError(1)));
+        assert_eq!(Ok(Element::Stash(1)), "stash 1".try_into());
+        assert_eq!(Ok(Element::Stashed(2)), "stashed 2".try_into());
+        assert_eq!(Element::Stash(1).to_string(), "stash 1");
+        assert_eq!(Element::Stashed(2).to_string(), "stashed 2");
+        let source = "\
+fn left
+    print
+    \"a\"
+    return
+    1
+end
+fn middle
+    print
+    \"b\"
+    return
+    2
+end
+fn chain
+    return
+    call left 0 < call middle 0 < 3
+end
+fn fails
+    let x
+    3 < call middle 0 <= 4
+    return
+    x
+end
+fn single
+    return
+    &&
+    true
+    1 >= 2
+end
+fn three
+    if
+    1 < 2 < 3 == 3
+        return
+        \"yes\"
+    end
+end
+fn keeps
+    let _chain1
+    \"mine\"
+    let y
+    1 < 2 < 3
+    return
+    _chain1
+end
+fn cleared
+    let y
+    1 < 2 < 3
+    return
+    stashed 0
+end
+";
+        let mut space = [0u8; 512];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        assert_eq!(super::estimate_size(source), Ok(used));
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.validate(), Ok(()));
+        // Left to right, with `b` worked out once even though it's in both
+        // comparisons
+        let mut out = std::string::String::new();
+        assert_eq!(
+            p.run_with_output("chain", &mut out),
+            Ok(Value::Boolean(true))
+        );
+        assert_eq!(out, "ab");
+        let mut out = std::string::String::new();
+        assert_eq!(
+            p.run_with_output("fails", &mut out),
+            Ok(Value::Boolean(false))
+        );
+        assert_eq!(out, "b");
+        assert_eq!(p.run("single"), Ok(Value::Boolean(false)));
+        assert_eq!(p.run("three"), Ok(Value::StringLiteral("yes")));
+        let body: std::vec::Vec<Element> = p
+            .iter_statements(p.function_index("chain").unwrap())
+            .map(|(_, element)| element)
+            .take_while(|element| *element != Element::End)
+            .collect();
+        assert_eq!(
+            body,
+            [
+                Element::Stash(0),
+                Element::Call("left", 0),
+                Element::Stash(1),
+                Element::Call("middle", 0),
+                Element::Return,
+                Element::And,
+                Element::Less,
+                Element::Stashed(0),
+                Element::Stashed(1),
+                Element::Less,
+                Element::Stashed(1),
+                Element::Integer(3),
+            ]
+        );
+        // The stashed operands aren't variables, and are gone once the
+        // statement has run
+        let mut scope = VarScope::new();
+        assert_eq!(
+            p.run_with_scope("keeps", &mut scope),
+            Ok(Value::StringLiteral("mine"))
+        );
+        assert_eq!(scope.used, 2);
+        assert_eq!(scope.get("y"), Some(&Value::Boolean(true)));
+        assert_eq!(p.run("cleared"), Err(Error::UndefinedVariable));
+        let mut interpreter = p.interpreter("cleared").unwrap();
+        assert_eq!(interpreter.step(), Ok(StepResult::Continue));
+        assert_eq!(interpreter.step(), Ok(StepResult::Continue));
+        assert_eq!(interpreter.step(), Ok(StepResult::Continue));
+        assert_eq!(interpreter.step(), Err(Error::UndefinedVariable));
+        // The disassembly reads back in as the same program
+        let text = p.to_string();
+        let mut copy = [0u8; 512];
+        let mut builder = ProgramBuilder::new(&mut copy);
+        builder.assemble(&text).unwrap();
+        assert_eq!(builder.snapshot_bytes(), &space[0..used]);
+
+        // The operands can only go before a statement which is run once
+        for (bad, line) in &[
+            ("fn f\n    while\n    1 < 2 < 3\n    end\nend\n", 3),
+            ("fn f\n    return\n    !\n    1 < 2 < 3\nend\n", 4),
+        ] {
+            let mut space = [0u8; 64];
+            let mut builder = ProgramBuilder::new(&mut space);
+            assert_eq!(
+                builder.assemble(bad),
+                Err(Error::ChainedComparison { line: *line })
+            );
+        }
+        // Parse errors point at the comparison or operand which is wrong
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        assert_eq!(
+            builder.assemble("fn f\n    return\n    1 < 2 < 3 < 4 < 5 < 6\nend\n"),
+            Err(Error::ParseError {
+                line: 3,
+                offset: 22
+            })
+        );
+        assert_eq!(
+            builder.assemble("fn g\n    return\n    1 < + < 3\nend\n"),
+            Err(Error::ParseError { line: 3, offset: 8 })
+        );
+        assert_eq!(
+            builder.assemble("fn h\n    return\n    1 < \"a\\q\"\nend\n"),
+            Err(Error::ParseError {
+                line: 3,
+                offset: 10
+            })
+        );
+    }
+
+    #[test]
+    fn print_statement() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert_all(&[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Print,
+                Element::StringLiteral("x="),
+                Element::Print,
+                Element::Add,
+                Element::Integer(1),
+                Element::Integer(2),
+                Element::Print,
+                Element::Float(0.5),
+                Element::Return,
+                Element::Integer(0),
+                Element::End,
+            ])
+            .unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut out = std::string::String::new();
+        assert_eq!(p.run_with_output("foo", &mut out), Ok(Value::Integer(0)));
+        assert_eq!(out, "x=30.5");
+        // Without an output, the text goes nowhere
+        assert_eq!(p.run("foo"), Ok(Value::Integer(0)));
+        assert_eq!(Ok(Element::Print), "print".try_into());
+        assert_eq!(Element::Print.to_string(), "print");
+    }
+
+    #[test]
+    fn host_functions() {
+        struct MockHost;
+
+        impl HostFunctions for MockHost {
+            fn call<'a>(&mut self, name: &str, args: &[Value<'a>]) -> Result<Value<'a>, Error> {
+                match (name, args) {
+                    ("double", [Value::Integer(x)]) => Ok(Value::Integer(x * 2)),
+                    ("double", _) => Err(Error::ArityMismatch),
+                    _ => Err(Error::function_not_found(name)),
+                }
+            }
+        }
+
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert_all(&[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Return,
+                Element::Call("double", 1),
+                Element::Integer(21),
+                Element::End,
+                Element::Function {
+                    name: "bar",
+                    params: Params::NONE,
+                },
+                Element::Return,
+                Element::Call("triple", 1),
+                Element::Integer(21),
+                Element::End,
+            ])
+            .unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(
+            p.run_with_host("foo", &mut MockHost),
+            Ok(Value::Integer(42))
+        );
+        // Neither the script nor the host has this one
+        assert_eq!(
+            p.run_with_host("bar", &mut MockHost),
+            Err(Error::function_not_found("triple"))
+        );
+        // Without a host, there's nobody to ask
+        assert_eq!(p.run("foo"), Err(Error::function_not_found("double")));
+    }
+
+    #[test]
+    fn block_back_patching() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert(&Element::Function {
+                name: "foo",
+                params: Params::NONE,
+            })
+            .unwrap();
+        let outer = builder.begin_block(&Element::If).unwrap();
+        builder.insert(&Element::Integer(0)).unwrap();
+        let inner = builder.begin_block(&Element::If).unwrap();
+        builder
+            .insert_all(&[Element::Integer(1), Element::Return, Element::Integer(10)])
+            .unwrap();
+        builder.end_block(inner).unwrap();
+        builder.end_block(outer).unwrap();
+        builder
+            .insert_all(&[Element::Return, Element::Integer(20), Element::End])
+            .unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let elements: Vec<Element> = p.iter_statements(0).map(|(_, e)| e).collect();
+        assert_eq!(
+            elements,
+            [
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                // If, 0, the inner block, End
+                Element::Block(1 + 2 + (3 + 7) + 1),
+                Element::If,
+                Element::Integer(0),
+                // If, 1, Return, 10, End
+                Element::Block(1 + 2 + 1 + 2 + 1),
+                Element::If,
+                Element::Integer(1),
+                Element::Return,
+                Element::Integer(10),
+                Element::End,
+                Element::End,
+                Element::Return,
+                Element::Integer(20),
+                Element::End,
+            ]
+        );
+        // The skipped block is jumped over, rather than read
+        assert_eq!(p.run("foo"), Ok(Value::Integer(20)));
+        assert_eq!(Ok(Element::Block(300)), "block 300".try_into());
+        assert_eq!(Element::Block(300).to_string(), "block 300");
+    }
+
+    #[test]
+    fn block_too_large() {
+        let mut space = std::vec![0u8; 70000];
+        let mut builder = ProgramBuilder::new(&mut space);
+        let marker = builder.begin_block(&Element::While).unwrap();
+        for _ in 0..65534 {
+            builder.insert(&Element::Nop).unwrap();
+        }
+        let used = builder.used();
+        assert_eq!(builder.end_block(marker), Err(Error::BlockTooLarge));
+        assert_eq!(builder.used(), used);
+        // Exactly 65535 bytes is fine
+        let mut builder = ProgramBuilder::new(&mut space);
+        let marker = builder.begin_block(&Element::While).unwrap();
+        for _ in 0..65533 {
+            builder.insert(&Element::Nop).unwrap();
+        }
+        assert_eq!(builder.end_block(marker), Ok(()));
+        assert_eq!(&space[0..3], &[Program::BLOCK_ID, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn validate_program() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert(&Element::Function {
+                name: "foo",
+                params: Params::NONE,
+            })
+            .unwrap();
+        let marker = builder.begin_block(&Element::If).unwrap();
+        builder
+            .insert_all(&[
+                Element::Integer(1),
+                Element::Return,
+                Element::StringLiteral("yes"),
+                Element::Else,
+                Element::Print,
+                Element::Integer(2),
+            ])
+            .unwrap();
+        builder.end_block(marker).unwrap();
+        builder
+            .insert_all(&[Element::Return, Element::Integer(20), Element::End])
+            .unwrap();
+        let used = builder.used();
+        assert_eq!(Program::new(&space[0..used]).validate(), Ok(()));
+        assert_eq!(Program::new(&[]).validate(), Ok(()));
+
+        // Unclosed function
+        let p = Program::new(&space[0..used - 1]);
+        assert_eq!(p.validate(), Err(Error::SequenceError(used - 1)));
+        // Truncated string, part way through "yes"
+        let p = Program::new(&space[0..17]);
+        assert_eq!(p.validate(), Err(Error::SequenceError(13)));
+        // Unknown opcode
+        let mut bad = [0u8; 64];
+        bad[0..used].copy_from_slice(&space[0..used]);
+        bad[used - 1] = 0xFF;
+        let p = Program::new(&bad[0..used]);
+        assert_eq!(p.validate(), Err(Error::SequenceError(used - 1)));
+        // Wrong block length
+        bad[0..used].copy_from_slice(&space[0..used]);
+        bad[8] += 1;
+        let p = Program::new(&bad[0..used]);
+        assert_eq!(p.validate(), Err(Error::SequenceError(6)));
+    }
+
+    #[test]
+    fn validate_invalid_utf8() {
+        // fn main, "\xff", end
+        let data = [
+            Program::FUNCTION_ID,
+            4,
+            b'm',
+            b'a',
+            b'i',
+            b'n',
+            0,
+            Program::STRING_ID,
+            1,
+            0xFF,
+            Program::END_ID,
+        ];
+        let program = Program::new(&data);
+        assert_eq!(program.validate(), Err(Error::InvalidUtf8 { index: 7 }));
+        // A truncated string is still a sequence error
+        let program = Program::new(&data[..9]);
+        assert_eq!(program.validate(), Err(Error::SequenceError(7)));
+    }
+
+    #[test]
+    fn validate_stray_elements() {
+        let foo = Element::Function {
+            name: "foo",
+            params: Params::NONE,
+        };
+        for (elements, error_index) in [
+            // End outside a function
+            (&[Element::End][..], 0),
+            // Return with nothing to return
+            (&[foo.clone(), Element::Return, Element::End][..], 7),
+            // Expression where a statement should be
+            (&[foo.clone(), Element::Integer(1), Element::End][..], 6),
+            // Else outside an If
+            (&[foo.clone(), Element::Else, Element::End][..], 6),
+            // Two Elses
+            (
+                &[
+                    foo.clone(),
+                    Element::If,
+                    Element::Boolean(true),
+                    Element::Else,
+                    Element::Else,
+                    Element::End,
+                    Element::End,
+                ][..],
+                9,
+            ),
+            // Nested function
+            (
+                &[foo.clone(), foo.clone(), Element::End, Element::End][..],
+                6,
+            ),
+        ] {
+            let mut space = [0u8; 64];
+            let mut builder = ProgramBuilder::new(&mut space);
+            // The nested function has the same name as the outer one
+            builder.set_allow_duplicate_functions(true);
+            builder.insert_all(elements).unwrap();
+            let used = builder.used();
+            let p = Program::new(&space[0..used]);
+            assert_eq!(
+                p.validate(),
+                Err(Error::SequenceError(error_index)),
+                "{:?}",
+                elements
+            );
+        }
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(&[b"1234", b"56789"]), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+        assert_eq!(
+            Ok(Element::Checksum(0xCBF4_3926)),
+            "checksum 0xcbf43926".try_into()
+        );
+        assert_eq!(Element::Checksum(0x1234).to_string(), "checksum 0x00001234");
+        assert!(Element::try_from("checksum 1234").is_err());
+
+        let source = "fn foo\nreturn\n1000\nend\n";
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new_with_options(&mut space, Endianness::Big).unwrap();
+        builder.assemble(source).unwrap();
+        let used = builder.finalize_with_crc().unwrap();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.verify_crc(), Ok(()));
+        assert_eq!(p.validate(), Ok(()));
+        assert_eq!(p.run("foo"), Ok(Value::Integer(1000)));
+        // Flip a bit anywhere, including in the header
+        for index in [4, 12, used - 1] {
+            let mut corrupt = space;
+            corrupt[index] ^= 0x10;
+            assert_eq!(
+                Program::new(&corrupt[0..used]).verify_crc(),
+                Err(Error::ChecksumMismatch),
+                "{}",
+                index
+            );
+        }
+
+        // Without a header, and without a checksum
+        let mut space = [0u8; 16];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let unchecked = builder.used();
+        let used = builder.finalize_with_crc().unwrap();
+        assert_eq!(used, unchecked + 5);
+        assert_eq!(Program::new(&space[0..used]).verify_crc(), Ok(()));
+        assert_eq!(
+            Program::new(&space[0..unchecked]).verify_crc(),
+            Err(Error::ChecksumMismatch)
+        );
+        // A checksum in the middle is no good
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.insert(&Element::Checksum(0)).unwrap();
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        assert_eq!(
+            Program::new(&space[0..used]).validate(),
+            Err(Error::SequenceError(0))
+        );
+        // No room for the checksum
+        let mut space = [0u8; 12];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        assert_eq!(builder.finalize_with_crc(), Err(Error::InsufficientSpace));
+    }
+
+    #[test]
+    fn integer_literals() {
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert(&Element::Function {
+                name: "foo",
+                params: Params::NONE,
+            })
+            .unwrap();
+        let marker = builder.begin_block(&Element::If).unwrap();
+        builder
+            .insert_all(&[
+                Element::Less,
+                Element::Integer(70000),
+                Element::Long(5),
+                Element::Print,
+                Element::StringLiteral("42"),
+            ])
+            .unwrap();
+        builder.end_block(marker).unwrap();
+        builder.assemble("return\n+\n-3\n70000\nend\n").unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert!(p.integer_literals().eq([70000, -3, 70000]));
+    }
+
+    #[test]
+    fn copy_program() {
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new_with_options(&mut space, Endianness::Little).unwrap();
+        builder.assemble("fn foo\nreturn\n70000\nend\n").unwrap();
+        // Take a copy part way through
+        let mut copy = [0u8; 32];
+        let snapshot = builder.snapshot_bytes();
+        copy[0..snapshot.len()].copy_from_slice(snapshot);
+        let snapshot_len = snapshot.len();
+        builder.assemble("fn bar\nreturn\n2\nend\n").unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        #[allow(clippy::clone_on_copy)]
+        let q = p.clone();
+        let r = p;
+        for program in [p, q, r] {
+            assert_eq!(program.run("foo"), Ok(Value::Integer(70000)));
+            assert_eq!(program.run("bar"), Ok(Value::Integer(2)));
+        }
+        let p = Program::new(&copy[0..snapshot_len]);
+        assert_eq!(p.run("foo"), Ok(Value::Integer(70000)));
+        assert_eq!(p.run("bar"), Err(Error::function_not_found("bar")));
+    }
+
+    #[test]
+    fn error_display() {
+        assert_eq!(
+            Error::SequenceError(12).to_string(),
+            "unexpected element at index 12"
+        );
+        assert_eq!(
+            Error::TypeMismatch(ValueKind::Integer, ValueKind::String).to_string(),
+            "type mismatch between Integer and String"
+        );
+        assert_eq!(
+            Error::ParseError { line: 3, offset: 5 }.to_string(),
+            "parse error on line 3, at offset 5"
+        );
+        #[cfg(feature = "alloc")]
+        assert_eq!(
+            Error::function_not_found("foo").to_string(),
+            "no function called \"foo\""
+        );
+        #[cfg(feature = "std")]
+        {
+            let boxed: std::boxed::Box<dyn std::error::Error> = Error::DivideByZero.into();
+            assert_eq!(boxed.to_string(), "divide by zero");
+        }
+    }
+
+    #[test]
+    fn new_with_header() {
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new_with_header(&mut space).unwrap();
+        builder
+            .assemble("fn main\n    return\n    1\nend\n")
+            .unwrap();
+        let used = builder.used();
+        assert_eq!(&space[0..4], &PROGRAM_MAGIC);
+        assert_eq!(space[4], PROGRAM_VERSION);
+        let p = Program::try_new(&space[0..used]).unwrap();
+        assert_eq!(p.run("main"), Ok(Value::Integer(1)));
+        assert!(Program::new(&space[0..used]) == p);
+        // Without a header, only `Program::new` will take it
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .assemble("fn main\n    return\n    1\nend\n")
+            .unwrap();
+        let used = builder.used();
+        assert_eq!(
+            Program::try_new(&space[0..used]).err(),
+            Some(Error::BadMagic)
+        );
+        assert_eq!(
+            Program::new(&space[0..used]).run("main"),
+            Ok(Value::Integer(1))
+        );
+        let mut space = [0u8; HEADER_LEN - 1];
+        assert_eq!(
+            ProgramBuilder::new_with_header(&mut space).err(),
+            Some(Error::InsufficientSpace)
+        );
+    }
+
+    #[test]
+    fn try_new() {
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new_with_options(&mut space, Endianness::Big).unwrap();
+        builder
+            .insert_all(&[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Return,
+                Element::Integer(1),
+                Element::End,
+                Element::Function {
+                    name: "bar",
+                    params: Params::NONE,
+                },
+                Element::End,
+            ])
+            .unwrap();
+        let used = builder.used();
+        let p = Program::try_new(&space[0..used]).unwrap();
+        assert_eq!(p.run("foo"), Ok(Value::Integer(1)));
+        // Indices start after the header
+        assert_eq!(p.iter_statements(0).next().map(|(i, _)| i), Some(0));
+        assert!(Program::new(&space[0..used]) == p);
+        // Chop the final function's name short, leaving just "b"
+        assert_eq!(
+            Program::try_new(&space[0..HEADER_LEN + 13]).err(),
+            Some(Error::SequenceError(10))
+        );
+        // Raw data doesn't have a header
+        let raw = &space[HEADER_LEN..used];
+        assert_eq!(Program::new(raw).run("foo"), Ok(Value::Integer(1)));
+        assert_eq!(Program::try_new(raw).err(), Some(Error::BadMagic));
+        assert_eq!(Program::try_new(b"NEO").err(), Some(Error::BadMagic));
+        assert_eq!(Program::try_new(b"NEO1").err(), Some(Error::BadMagic));
+        // A version from the future
+        space[4] = PROGRAM_VERSION + 1;
+        assert_eq!(
+            Program::try_new(&space[0..used]).err(),
+            Some(Error::UnsupportedVersion)
+        );
+    }
+
+    #[test]
+    fn value_accessors() {
+        let values = [
+            Value::StringLiteral("hi"),
+            Value::Integer(5),
+            Value::Float(1.5),
+            Value::Boolean(true),
+            Value::Nil,
+        ];
+        let integers: Vec<_> = values.iter().map(Value::as_integer).collect();
+        assert_eq!(integers, [None, Some(5), None, None, None]);
+        let floats: Vec<_> = values.iter().map(Value::as_float).collect();
+        assert_eq!(floats, [None, None, Some(1.5), None, None]);
+        let bools: Vec<_> = values.iter().map(Value::as_bool).collect();
+        assert_eq!(bools, [None, None, None, Some(true), None]);
+        let strs: Vec<_> = values.iter().map(Value::as_str).collect();
+        assert_eq!(strs, [Some("hi"), None, None, None, None]);
+        let truths: Vec<_> = values.iter().map(Value::truthiness).collect();
+        assert_eq!(truths, [true, true, true, true, false]);
+
+        let falsy = [
+            Value::StringLiteral(""),
+            Value::Integer(0),
+            Value::Float(0.0),
+            Value::Boolean(false),
+        ];
+        assert!(falsy.iter().all(|v| !v.truthiness()));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn value_accessors_heap() {
+        let s = Value::String("hi".to_string());
+        assert_eq!(s.as_str(), Some("hi"));
+        assert_eq!(s.as_integer(), None);
+        assert!(s.truthiness());
+        assert!(!Value::String(String::new()).truthiness());
+
+        let v = Value::Vector(std::vec![Value::Integer(1)]);
+        assert_eq!(v.as_str(), None);
+        assert_eq!(v.as_integer(), None);
+        assert_eq!(v.as_float(), None);
+        assert_eq!(v.as_bool(), None);
+        assert!(v.truthiness());
+        assert!(!Value::Vector(Vec::new()).truthiness());
+    }
+
+    #[test]
+    fn value_display() {
+        assert_eq!(Value::Integer(-42).to_string(), "-42");
+        assert_eq!(Value::Float(1.5).to_string(), "1.5");
+        assert_eq!(Value::Float(2.0).to_string(), "2.0");
+        assert_eq!(Value::StringLiteral("hello").to_string(), "hello");
+        assert_eq!(Value::Boolean(true).to_string(), "true");
+        assert_eq!(Value::Boolean(false).to_string(), "false");
+        assert_eq!(Value::Nil.to_string(), "nil");
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn value_display_heap() {
+        assert_eq!(Value::String("hello".to_string()).to_string(), "hello");
+        assert_eq!(Value::Vector(Vec::new()).to_string(), "[]");
+        let v = Value::Vector(std::vec![
+            Value::Integer(1),
+            Value::StringLiteral("a"),
+            Value::Vector(std::vec![Value::Boolean(true), Value::Nil]),
+        ]);
+        assert_eq!(v.to_string(), "[1, a, [true, nil]]");
+    }
+
+    #[test]
+    fn value_ordering() {
+        use core::cmp::Ordering;
+        // Within kinds
+        assert!(Value::Integer(-1) < Value::Integer(2));
+        assert!(Value::Integer(2) < Value::Float(2.5));
+        assert!(Value::Long(1 << 40) > Value::Float(1.0e9));
+        assert!(Value::Boolean(false) < Value::Boolean(true));
+        assert!(Value::StringLiteral("abc") < Value::StringLiteral("abd"));
+        assert!(Value::StringLiteral("ab") < Value::StringLiteral("abc"));
+        assert_eq!(Value::Nil.partial_cmp(&Value::Nil), Some(Ordering::Equal));
+        assert_eq!(
+            Value::Integer(3).partial_cmp(&Value::Integer(3)),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(Value::Float(f32::NAN).partial_cmp(&Value::Float(1.0)), None);
+        // The same number in different variants isn't equal
+        assert!(Value::Integer(1) < Value::Long(1));
+        assert!(Value::Long(1) < Value::Float(1.0));
+        // Across kinds
+        assert!(Value::Nil < Value::Boolean(false));
+        assert!(Value::Boolean(true) < Value::Integer(i32::MIN));
+        assert!(Value::Float(f32::INFINITY) < Value::StringLiteral(""));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn value_vector_ordering() {
+        let v = Value::vector([Value::Integer(1), Value::StringLiteral("a")]);
+        assert_eq!(
+            v,
+            Value::Vector(std::vec![Value::Integer(1), Value::StringLiteral("a")])
+        );
+        assert!(Value::StringLiteral("z") < v);
+        assert!(v < Value::vector([Value::Integer(2)]));
+        assert!(v > Value::vector([Value::Integer(1)]));
+        assert!(Value::vector([]) < v);
+        assert!(Value::StringLiteral("a") < Value::String("a".to_string()));
+        assert_eq!(v.partial_cmp(&v.clone()), Some(core::cmp::Ordering::Equal));
+        let mut values = std::vec![
+            Value::vector([Value::Nil]),
+            Value::Integer(3),
+            Value::Float(1.5),
+            Value::Nil,
+        ];
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            values,
+            [
+                Value::Nil,
+                Value::Float(1.5),
+                Value::Integer(3),
+                Value::vector([Value::Nil]),
+            ]
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn owned_program() {
+        let mut space = std::vec![0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert_all(&[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Return,
+                Element::StringLiteral("hello"),
+                Element::End,
+            ])
+            .unwrap();
+        let owned = builder.into_owned();
+        drop(space);
+        assert_eq!(owned.run("foo"), Ok(Value::StringLiteral("hello")));
+        assert_eq!(owned.functions().collect::<Vec<_>>(), ["foo"]);
+        assert_eq!(owned.iter_statements(0).count(), 4);
+        assert_eq!(OwnedProgram::from(&owned.as_program()), owned);
+    }
+
+    #[test]
+    fn iter_with_spans() {
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert_all(&[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Return,
+                Element::Integer(1000),
+                Element::End,
+            ])
+            .unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut spans = p.iter_with_spans().map(|(start, len, _)| (start, len));
+        // Opcode, name length, name and parameter count
+        assert_eq!(spans.next(), Some((0, 6)));
+        assert_eq!(spans.next(), Some((6, 1)));
+        // Opcode and two bytes of integer
+        assert_eq!(spans.next(), Some((7, 3)));
+        assert_eq!(spans.next(), Some((10, 1)));
+        assert_eq!(spans.next(), None);
+        // It stops at anything it can't decode
+        let p = Program::new(&space[0..used - 2]);
+        assert_eq!(p.iter_with_spans().count(), 2);
+    }
+
+    #[test]
+    fn iter_toplevel() {
+        let source = "\
+fn foo
+    let x
+    1
+    while
+        <
+        x
+        10
+        if
+            ==
+            x
+            5
+            break
+        end
+        let x
+        +
+        x
+        1
+    end
+    return
+    x
+end
+fn bar(a, b)
+    print
+    a
+    return
+    b
+end
+";
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut toplevel = p.iter_toplevel();
+        assert_eq!(
+            toplevel.next(),
+            Some((
+                0,
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                }
+            ))
+        );
+        let (index, bar) = toplevel.next().unwrap();
+        assert_eq!(p.iter_statements(index).next(), Some((index, bar.clone())));
+        assert_eq!(
+            bar,
+            Element::Function {
+                name: "bar",
+                params: Params::new("a, b").unwrap(),
+            }
+        );
+        assert_eq!(toplevel.next(), None);
+    }
+
+    #[test]
+    fn long_arithmetic() {
+        check_expression(
+            &[Element::Add, Element::Long(1 << 40), Element::Integer(1)],
+            Ok(Value::Long((1 << 40) + 1)),
+        );
+        check_expression(
+            &[Element::Add, Element::Integer(i32::MAX), Element::Long(1)],
+            Ok(Value::Long(i64::from(i32::MAX) + 1)),
+        );
+        check_expression(
+            &[
+                Element::Multiply,
+                Element::Long(1 << 40),
+                Element::Integer(-2),
+            ],
+            Ok(Value::Long(-(1 << 41))),
+        );
+        check_expression(
+            &[Element::Subtract, Element::Integer(0), Element::Long(5)],
+            Ok(Value::Long(-5)),
+        );
+        check_expression(
+            &[Element::Divide, Element::Long(7), Element::Integer(0)],
+            Err(Error::DivideByZero),
+        );
+        check_expression(
+            &[Element::Modulo, Element::Long(7), Element::Integer(4)],
+            Ok(Value::Long(3)),
+        );
+        check_expression(
+            &[Element::Add, Element::Long(i64::MAX), Element::Integer(1)],
+            Err(Error::ArithmeticOverflow),
+        );
+        check_expression(
+            &[Element::Less, Element::Integer(5), Element::Long(1 << 40)],
+            Ok(Value::Boolean(true)),
+        );
+        check_expression(
+            &[Element::Negate, Element::Long(1 << 40)],
+            Ok(Value::Long(-(1 << 40))),
+        );
+        check_expression(
+            &[Element::Add, Element::Long(1), Element::Float(1.0)],
+            Err(Error::TypeMismatch(ValueKind::Long, ValueKind::Float)),
+        );
+    }
+
+    #[test]
+    fn program_stats() {
+        let source = "\
+fn foo
+    for i
+    1
+    10
+        if
+        true
+            print
+            i
+        end
+    end
+end
+fn bar
+    return
+    1
+end
+";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.byte_len(), used);
+        assert_eq!(
+            p.stats(),
+            ProgramStats {
+                functions: 2,
+                statements: 15,
+                max_depth: 3,
+            }
+        );
+        // Junk on the end is ignored
+        space[used] = 0xFF;
+        let p = Program::new(&space[0..used + 1]);
+        assert_eq!(p.byte_len(), used + 1);
+        assert_eq!(p.stats().statements, 15);
+    }
+
+    #[test]
+    fn checkpoint_restore() {
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert_all(&[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Return,
+            ])
+            .unwrap();
+        let checkpoint = builder.checkpoint();
+        builder
+            .insert_all(&[Element::StringLiteral("oops"), Element::End])
+            .unwrap();
+        assert_eq!(builder.used(), 7 + 6 + 1);
+        builder.restore(checkpoint).unwrap();
+        assert_eq!(builder.used(), 7);
+        builder
+            .insert_all(&[Element::Integer(1), Element::End])
+            .unwrap();
+        let used = builder.used();
+        assert_eq!(used, 7 + 2 + 1);
+        // Can't go forwards again
+        assert_eq!(
+            builder.restore(Checkpoint {
+                used: 20,
+                ..checkpoint
+            }),
+            Err(Error::InvalidHandle)
+        );
+        // Or use a checkpoint from somewhere else
+        let mut other_space = [0u8; 32];
+        let mut other = ProgramBuilder::new(&mut other_space);
+        assert_eq!(
+            builder.restore(other.checkpoint()),
+            Err(Error::InvalidHandle)
+        );
+        assert_eq!(builder.used(), used);
+        // The old bytes have been overwritten or zeroed
+        assert_eq!(space[used..14], [0, 0, 0, 0]);
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run("foo"), Ok(Value::Integer(1)));
+    }
+
+    #[test]
+    fn append_program() {
+        let library_source = "\
+fn double(x)
+    return
+    *
+    x
+    2
+end
+";
+        let mut library_space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut library_space);
+        builder.assemble(library_source).unwrap();
+        let used = builder.used();
+        let library = Program::new(&library_space[0..used]);
+
+        // Not enough room, so nothing changes
+        let mut space = [0u8; 8];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.insert(&Element::Nop).unwrap();
+        assert_eq!(
+            builder.append_program(&library),
+            Err(Error::InsufficientSpace)
+        );
+        assert_eq!(builder.used(), 1);
+        assert_eq!(space, [Program::NOP_ID, 0, 0, 0, 0, 0, 0, 0]);
+
+        let source = "\
+fn foo
+    return
+    +
+    call double 1
+    20
+    call twice 0
+end
+fn twice
+    return
+    call double 1
+    1
+end
+";
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.append_program(&library).unwrap();
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run("foo"), Ok(Value::Integer(42)));
+        assert_eq!(p.run("twice"), Ok(Value::Integer(2)));
+
+        // A library's checksum doesn't end up in the middle
+        let mut library_space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut library_space);
+        builder.assemble(library_source).unwrap();
+        let used = builder.finalize_with_crc().unwrap();
+        let library = Program::new(&library_space[0..used]);
+        assert_eq!(library.verify_crc(), Ok(()));
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.append_program(&library).unwrap();
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.validate(), Ok(()));
+        assert!(!p
+            .iter_statements(0)
+            .any(|(_, element)| matches!(element, Element::Checksum(_))));
+        assert_eq!(p.run("foo"), Ok(Value::Integer(42)));
+
+        // A broken library isn't copied
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut space);
+        let broken = Program::new(&library_space[0..10]);
+        assert!(builder.append_program(&broken).is_err());
+        assert_eq!(builder.used(), 0);
+
+        // Part way through swapping the bytes, the space runs out
+        let mut little_space = [0u8; 32];
+        let mut builder =
+            ProgramBuilder::new_with_options(&mut little_space, Endianness::Little).unwrap();
+        builder.assemble(library_source).unwrap();
+        let used = builder.used();
+        let little = Program::new(&little_space[0..used]);
+        let mut space = [0u8; 8];
+        let mut builder = ProgramBuilder::new(&mut space);
+        assert_eq!(
+            builder.append_program(&little),
+            Err(Error::InsufficientSpace)
+        );
+        assert_eq!(builder.used(), 0);
+        assert_eq!(space, [0; 8]);
+    }
+
+    #[test]
+    fn duplicate_functions() {
+        let foo = |value| {
+            [
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Return,
+                Element::Integer(value),
+                Element::End,
+            ]
+        };
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.insert_all(&foo(1)).unwrap();
+        let used = builder.used();
+        assert_eq!(builder.insert_all(&foo(2)), Err(Error::DuplicateFunction));
+        assert_eq!(builder.used(), used);
+        // The first one wins
+        builder.set_allow_duplicate_functions(true);
+        builder.insert_all(&foo(2)).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run("foo"), Ok(Value::Integer(1)));
+
+        let mut other_space = [0u8; 32];
+        let mut other = ProgramBuilder::new(&mut other_space);
+        other.insert_all(&foo(3)).unwrap();
+        assert_eq!(other.append_program(&p), Err(Error::DuplicateFunction));
+        other.set_allow_duplicate_functions(true);
+        other.append_program(&p).unwrap();
+    }
 
+    #[cfg(feature = "alloc")]
     #[test]
-    fn element_nop() {
-        assert_eq!(Ok(Element::Nop), "nop".try_into());
-        assert_eq!(Element::Nop.to_string(), "nop");
+    fn to_elements() {
+        let source =
+            "fn add(a, b)\nreturn\n+\na\nb\nend\nfn foo\nreturn\ncall add 2\n1\n2.5\nend\n";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let elements = p.to_elements();
+        assert_eq!(elements.len(), 12);
+        assert_eq!(elements[8], Element::Call("add", 2));
+        assert_eq!(Vec::from(&p), elements);
+        // Back to text, and assembled again, gives the same bytes
+        let text: Vec<_> = elements.iter().map(|e| e.to_string()).collect();
+        let mut space2 = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space2);
+        builder.assemble(&text.join("\n")).unwrap();
+        assert_eq!(builder.used(), used);
+        assert_eq!(space[0..used], space2[0..used]);
     }
 
     #[test]
-    fn element_end() {
-        assert_eq!(Ok(Element::End), "end".try_into());
-        assert_eq!(Element::End.to_string(), "end");
+    fn program_equality() {
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder
+            .insert_all(&[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::Return,
+                Element::Integer(300),
+                Element::End,
+            ])
+            .unwrap();
+        let used = builder.used();
+        // The same thing, but with the Integer stored another way
+        let mut other = [0u8; 32];
+        other[0..7].copy_from_slice(&space[0..7]);
+        let leb128 = ProgramBuilder::encode_integer_leb128(&mut [0u8; 6], 300).to_vec();
+        other[7..7 + leb128.len()].copy_from_slice(&leb128);
+        other[7 + leb128.len()] = Program::END_ID;
+        let other_used = 7 + leb128.len() + 1;
+
+        let a = Program::new(&space[0..used]);
+        let b = Program::new(&other[0..other_used]);
+        assert!(a == Program::new(&space[0..used]));
+        assert!(a != b);
+        assert!(a.eq_elements(&b));
+        assert_eq!(b.run("foo"), Ok(Value::Integer(300)));
+        // Zero padding is just a lot of Nops
+        let padded = Program::new(&other);
+        assert!(padded != b);
+        assert!(a.eq_elements(&padded));
+        // But different elements are different
+        let shorter = Program::new(&space[0..used - 1]);
+        assert!(!a.eq_elements(&shorter));
     }
 
     #[test]
-    fn element_function() {
-        assert_eq!(Ok(Element::Function("test123")), "fn test123".try_into());
+    fn recursion_limit() {
+        let source = "\
+fn loop
+    return
+    call loop 0
+end
+fn countdown(n)
+    if
+    ==
+    n
+    0
+        return
+        0
+    end
+    return
+    call countdown 1
+    -
+    n
+    1
+end
+fn three
+    return
+    call countdown 1
+    3
+end
+";
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run("loop"), Err(Error::RecursionLimitExceeded));
+        // Four calls deep
+        assert_eq!(p.run_with_depth_limit("three", 4), Ok(Value::Integer(0)));
         assert_eq!(
-            Err::<Element, Error>(Error::InvalidName),
-            "fn test123!".try_into()
+            p.run_with_depth_limit("three", 3),
+            Err(Error::RecursionLimitExceeded)
         );
         assert_eq!(
-            Err::<Element, Error>(Error::InvalidName),
-            "fn test 123".try_into()
+            p.run_with_depth_limit("loop", 0),
+            Err(Error::RecursionLimitExceeded)
         );
+    }
+
+    #[test]
+    fn constant_folding() {
+        let source = "\
+fn foo
+    let x
+    3
+    return
+    +
+    *
+    -
+    10
+    4
+    2
+    +
+    x
+    /
+    9
+    3
+end
+";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let mut folded_space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut folded_space).with_constant_folding();
+        builder.assemble(source).unwrap();
+        let folded_used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let folded = Program::new(&folded_space[0..folded_used]);
+        assert!(folded_used < used);
+        assert_eq!(p.run("foo"), Ok(Value::Integer(18)));
+        assert_eq!(folded.run("foo"), Ok(Value::Integer(18)));
+        // `(10 - 4) * 2` and `9 / 3` are worked out, but `x` isn't a constant
         assert_eq!(
-            Err::<Element, Error>(Error::InvalidName),
-            "fn test-123".try_into()
+            folded.to_string(),
+            "fn foo\n    let x\n    3\n    return\n    +\n    12\n    +\n    x\n    3\nend\n"
         );
+    }
+
+    #[test]
+    fn constant_folding_rollback() {
+        let long = [b'x'; 300];
+        let long = core::str::from_utf8(&long).unwrap();
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut space).with_constant_folding();
+        builder
+            .insert_all(&[Element::Add, Element::Integer(1)])
+            .unwrap();
+        let used = builder.used();
+        // Would fold `+ 1 2`, but then fails, so must leave `+ 1` alone
         assert_eq!(
-            Err::<Element, Error>(Error::InvalidName),
-            "fn 123test".try_into()
+            builder.insert_all(&[Element::Integer(2), Element::StringLiteral(long)]),
+            Err(Error::StringTooLong)
         );
-        assert_eq!(Element::Function("test123").to_string(), "fn test123");
+        assert_eq!(builder.used(), used);
+        // The same goes for a checkpoint
+        let checkpoint = builder.checkpoint();
+        builder.insert(&Element::Integer(2)).unwrap();
+        builder.restore(checkpoint).unwrap();
+        assert_eq!(builder.used(), used);
+        let expected = [Element::Add, Element::Integer(1)];
+        let p = Program::new(&space[0..used]);
+        assert!(p
+            .iter_statements(0)
+            .map(|(_, element)| element)
+            .eq(expected.iter().cloned()));
+        assert_eq!(p.iter_statements(0).count(), 2);
     }
 
     #[test]
-    fn element_return() {
-        assert_eq!(Ok(Element::Return), "return".try_into());
-        assert_eq!(Element::Return.to_string(), "return");
+    fn constant_folding_failures() {
+        for expression in &[
+            [
+                Element::Add,
+                Element::Integer(i32::MAX),
+                Element::Integer(1),
+            ],
+            [Element::Divide, Element::Integer(1), Element::Integer(0)],
+            [Element::Add, Element::Integer(1), Element::Float(1.0)],
+        ] {
+            let mut space = [0u8; 16];
+            let mut builder = ProgramBuilder::new(&mut space).with_constant_folding();
+            builder.insert_all(expression).unwrap();
+            let used = builder.used();
+            let p = Program::new(&space[0..used]);
+            assert_eq!(p.iter_statements(0).count(), 3, "{:?}", expression);
+        }
+        let mut space = [0u8; 16];
+        let mut builder = ProgramBuilder::new(&mut space).with_constant_folding();
+        builder
+            .insert_all(&[Element::Multiply, Element::Float(1.5), Element::Float(2.0)])
+            .unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.iter_statements(0).next(), Some((0, Element::Float(3.0))));
+        assert_eq!(p.iter_statements(0).count(), 1);
     }
 
     #[test]
-    fn element_integer() {
-        assert_eq!(Ok(Element::Integer(1234)), "1234".try_into());
-        assert_eq!(Element::Integer(1234).to_string(), "1234");
+    fn nil_literal() {
+        assert_eq!(Ok(Element::Nil), "nil".try_into());
+        assert_eq!(Element::Nil.to_string(), "nil");
+        check_expression(&[Element::Nil], Ok(Value::Nil));
+        check_expression(
+            &[Element::Equal, Element::Nil, Element::Nil],
+            Ok(Value::Boolean(true)),
+        );
+        check_expression(
+            &[Element::Equal, Element::Nil, Element::Integer(0)],
+            Ok(Value::Boolean(false)),
+        );
+        check_expression(
+            &[Element::Equal, Element::StringLiteral("nil"), Element::Nil],
+            Ok(Value::Boolean(false)),
+        );
+        check_expression(
+            &[Element::NotEqual, Element::Nil, Element::Boolean(false)],
+            Ok(Value::Boolean(true)),
+        );
+        check_expression(
+            &[Element::NotEqual, Element::Nil, Element::Nil],
+            Ok(Value::Boolean(false)),
+        );
+        // Nil still isn't ordered
+        check_expression(
+            &[Element::Less, Element::Nil, Element::Integer(0)],
+            Err(Error::TypeMismatch(ValueKind::Nil, ValueKind::Integer)),
+        );
     }
 
     #[test]
-    fn empty_program() {
+    fn select_expression() {
+        check_expression(
+            &[
+                Element::Select,
+                Element::Boolean(true),
+                Element::Integer(1),
+                Element::Integer(2),
+            ],
+            Ok(Value::Integer(1)),
+        );
+        check_expression(
+            &[
+                Element::Select,
+                Element::Integer(0),
+                Element::Integer(1),
+                Element::Add,
+                Element::Integer(2),
+                Element::Integer(3),
+            ],
+            Ok(Value::Integer(5)),
+        );
+        check_expression(
+            &[
+                Element::Select,
+                Element::StringLiteral("yes"),
+                Element::Integer(1),
+                Element::Integer(2),
+            ],
+            Err(Error::TypeMismatch(ValueKind::String, ValueKind::String)),
+        );
+    }
+
+    #[test]
+    fn select_skips_other_branch() {
+        let source = "\
+fn noisy(x)
+    print
+    x
+    return
+    x
+end
+fn foo
+    return
+    +
+    ?
+    true
+    call noisy 1
+    \"a\"
+    call noisy 1
+    \"b\"
+    ?
+    false
+    call noisy 1
+    \"c\"
+    call noisy 1
+    \"d\"
+end
+";
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut out = std::string::String::new();
+        let result = p.run_with_output("foo", &mut out);
+        assert_eq!(out, "ad");
+        #[cfg(feature = "alloc")]
+        assert_eq!(result, Ok(Value::String("ad".to_string())));
+        #[cfg(not(feature = "alloc"))]
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn run_with_options() {
+        struct Host;
+
+        impl HostFunctions for Host {
+            fn call<'a>(&mut self, name: &str, args: &[Value<'a>]) -> Result<Value<'a>, Error> {
+                match (name, args) {
+                    ("double", [Value::Integer(i)]) => Ok(Value::Integer(i * 2)),
+                    _ => Err(Error::function_not_found(name)),
+                }
+            }
+        }
+
+        let source = "\
+fn foo
+    let x
+    1
+    while
+    x < 8
+        print
+        x
+        let x
+        call double 1
+        x
+    end
+    return
+    x
+end
+";
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut out = std::string::String::new();
+        let mut host = Host;
+        let options = RunOptions::new()
+            .output(&mut out)
+            .host(&mut host)
+            .max_iterations(3);
+        assert_eq!(p.run_with_options("foo", options), Ok(Value::Integer(8)));
+        assert_eq!(out, "124");
+        let mut host = Host;
+        let options = RunOptions::new().host(&mut host).max_iterations(2);
+        assert_eq!(
+            p.run_with_options("foo", options),
+            Err(Error::IterationLimitExceeded)
+        );
+        // The default options are the same as `run`
+        assert_eq!(
+            p.run_with_options("foo", RunOptions::default()),
+            Err(Error::function_not_found("double"))
+        );
+    }
+
+    #[test]
+    fn run_with_hook() {
+        let source = "\
+fn foo
+    let x
+    0
+    while
+    false
+        nop
+    end
+    if
+    true
+        return
+        x
+    end
+end
+";
         let mut space = [0u8; 64];
-        let builder = ProgramBuilder::new(&mut space);
-        assert_eq!(builder.used(), 0);
-        assert_eq!(builder.free(), space.len());
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut visited = [(0usize, Program::NOP_ID); 8];
+        let mut count = 0;
+        let result = p.run_with_hook("foo", &mut |index, element| {
+            visited[count] = (index, space[index]);
+            count += 1;
+            assert_eq!(
+                p.iter_statements(index).next(),
+                Some((index, element.clone()))
+            );
+        });
+        assert_eq!(result, Ok(Value::Integer(0)));
+        // let, while, if, return - but not the nop, as the loop never runs
+        assert_eq!(
+            visited[0..count],
+            [
+                (6, Program::ASSIGN_ID),
+                (11, Program::WHILE_ID),
+                (15, Program::IF_ID),
+                (17, Program::RETURN_ID),
+            ]
+        );
     }
 
     #[test]
-    fn insert_function() {
+    fn interpreter_steps() {
+        let source = "\
+fn foo
+    let total
+    0
+    for i
+    1
+    3
+        if
+        ==
+        i
+        2
+            continue
+        end
+        let total
+        +
+        total
+        i
+    end
+    return
+    total
+end
+";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut interpreter = p.interpreter("foo").unwrap();
+        let mut steps = 0;
+        let result = loop {
+            steps += 1;
+            match interpreter.step().unwrap() {
+                StepResult::Continue => {}
+                other => break other,
+            }
+        };
+        assert_eq!(result, StepResult::Returned(Value::Integer(4)));
+        assert_eq!(p.run("foo"), Ok(Value::Integer(4)));
+        // let, for, then (if, let, end) for 1 and 3, (if, continue) for 2,
+        // and return
+        assert_eq!(steps, 1 + 1 + 3 + 3 + 2 + 1);
+        assert_eq!(interpreter.step(), Ok(StepResult::Finished));
+        assert!(p.interpreter("missing").is_err());
+    }
+
+    #[test]
+    fn interpreter_output_and_host() {
+        struct Host;
+
+        impl HostFunctions for Host {
+            fn call<'a>(&mut self, name: &str, args: &[Value<'a>]) -> Result<Value<'a>, Error> {
+                match (name, args) {
+                    ("double", [Value::Integer(i)]) => Ok(Value::Integer(i * 2)),
+                    _ => Err(Error::function_not_found(name)),
+                }
+            }
+        }
+
+        let source = "\
+fn foo
+    print
+    \"a\"
+    print
+    call double 1
+    21
+    return
+    true
+end
+";
         let mut space = [0u8; 64];
         let mut builder = ProgramBuilder::new(&mut space);
-        builder.insert(&Element::Function("foo")).unwrap();
-        assert_eq!(builder.used(), 5);
-        let p = Program::new(&space[0..5]);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut out = std::string::String::new();
+        let mut host = Host;
+        let mut interpreter = p
+            .interpreter("foo")
+            .unwrap()
+            .with_output(&mut out)
+            .with_host(&mut host);
+        assert_eq!(interpreter.step(), Ok(StepResult::Continue));
+        assert_eq!(interpreter.step(), Ok(StepResult::Continue));
         assert_eq!(
-            p.iter_statements(0).next(),
-            Some((0, Element::Function("foo")))
+            interpreter.step(),
+            Ok(StepResult::Returned(Value::Boolean(true)))
         );
+        assert_eq!(out, "a42");
+        // Without a host, the call fails just as it does with `run`
+        let mut interpreter = p.interpreter("foo").unwrap();
+        assert_eq!(interpreter.step(), Ok(StepResult::Continue));
+        assert_eq!(interpreter.step(), Err(Error::function_not_found("double")));
+        assert_eq!(p.run("foo"), Err(Error::function_not_found("double")));
     }
 
     #[test]
-    fn insert_two_functions() {
+    fn interpreter_open_blocks() {
+        let mut source = std::string::String::from("fn foo\n");
+        for _ in 0..=MAX_OPEN_BLOCKS {
+            source.push_str("if\ntrue\n");
+        }
+        source.push_str("return\n1\n");
+        for _ in 0..=MAX_OPEN_BLOCKS {
+            source.push_str("end\n");
+        }
+        source.push_str("end\n");
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(&source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.run("foo"), Ok(Value::Integer(1)));
+        let mut interpreter = p.interpreter("foo").unwrap();
+        let result = loop {
+            match interpreter.step() {
+                Ok(StepResult::Continue) => {}
+                other => break other,
+            }
+        };
+        assert_eq!(result, Err(Error::TooManyOpenBlocks));
+    }
+
+    #[test]
+    fn interpreter_finishes() {
+        let source = "fn foo\nwhile\ntrue\nbreak\nend\nend\n";
         let mut space = [0u8; 64];
         let mut builder = ProgramBuilder::new(&mut space);
-        builder.insert(&Element::Function("foo")).unwrap();
-        builder.insert(&Element::End).unwrap();
-        builder.insert(&Element::Function("test£")).unwrap();
-        builder.insert(&Element::End).unwrap();
-        let expected = 2 + 3 + 1 + 2 + 6 + 1;
-        assert_eq!(builder.used(), expected);
-        let p = Program::new(&space[0..expected]);
-        let mut iter = p.iter_statements(0);
-        assert_eq!(iter.next(), Some((0, Element::Function("foo"))));
-        assert_eq!(iter.next(), Some((5, Element::End)));
-        assert_eq!(iter.next(), Some((6, Element::Function("test£"))));
-        assert_eq!(iter.next(), Some((14, Element::End)));
-        assert_eq!(iter.next(), None);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut interpreter = p.interpreter("foo").unwrap();
+        assert_eq!(interpreter.step(), Ok(StepResult::Continue));
+        assert_eq!(interpreter.step(), Ok(StepResult::Continue));
+        assert_eq!(interpreter.step(), Ok(StepResult::Finished));
     }
 
     #[test]
-    fn create_program() {
-        let data = [
-            Program::FUNCTION_ID,
-            0x05, // 5 byte string
-            b'f', // string bytes
-            b'o',
-            b'o',
-            0xc2, // including a UTF-8 encoded £
-            0xa3,
-            Program::RETURN_ID,
-            Program::INTEGER1_ID,
-            0x01,
-            Program::END_ID,
-        ];
-        let p = Program::new(&data);
-        assert_eq!(p.run("bar"), Err(Error::FunctionNotFound));
-        assert_eq!(p.run("foo£"), Ok(Value::Integer(0x01)));
+    #[cfg(feature = "alloc")]
+    fn substring() {
+        let substring = |s, start, end| {
+            std::vec![
+                Element::Substring,
+                Element::StringLiteral(s),
+                Element::Integer(start),
+                Element::Integer(end),
+            ]
+        };
+        check_expression(
+            &substring("hello", 1, 3),
+            Ok(Value::String("el".to_string())),
+        );
+        let mut compare = std::vec![Element::Equal];
+        compare.extend(substring("hello", 1, 3));
+        compare.push(Element::StringLiteral("el"));
+        check_expression(&compare, Ok(Value::Boolean(true)));
+        check_expression(
+            &substring("hello", -4, -1),
+            Ok(Value::String("ell".to_string())),
+        );
+        check_expression(&substring("hello", 2, 2), Ok(Value::String("".to_string())));
+        check_expression(&substring("hello", 0, 6), Err(Error::IndexOutOfBounds));
+        check_expression(&substring("hello", -6, 2), Err(Error::IndexOutOfBounds));
+        check_expression(&substring("hello", 3, 2), Err(Error::IndexOutOfBounds));
+        // 'é' is two bytes long
+        check_expression(
+            &substring("héllo", 0, 3),
+            Ok(Value::String("hé".to_string())),
+        );
+        check_expression(&substring("héllo", 0, 2), Err(Error::NotCharBoundary));
+        check_expression(
+            &[
+                Element::Substring,
+                Element::Integer(1),
+                Element::Integer(0),
+                Element::Integer(0),
+            ],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Integer)),
+        );
     }
 
     #[test]
-    fn num_statements() {
-        let data = [
-            Program::FUNCTION_ID,
-            0x03,
-            b'f',
-            b'o',
-            b'o',
-            Program::NOP_ID,
-            Program::INTEGER2_ID,
-            0x01,
-            0x02,
-            Program::END_ID,
-        ];
-        let p = Program::new(&data);
-        assert_eq!(p.iter_statements(0).count(), 4);
+    #[cfg(feature = "alloc")]
+    fn to_str_expression() {
+        let check = |expression: &[Element], expected: &str| {
+            let mut elements = std::vec![Element::ToStr];
+            elements.extend_from_slice(expression);
+            check_expression(&elements, Ok(Value::String(expected.to_string())));
+        };
+        check(&[Element::Integer(-42)], "-42");
+        check(&[Element::Float(1.5)], "1.5");
+        check(&[Element::Boolean(true)], "true");
+        check(&[Element::Nil], "nil");
+        check(&[Element::StringLiteral("hi")], "hi");
+        check(
+            &[
+                Element::Vector(3),
+                Element::Integer(1),
+                Element::StringLiteral("a"),
+                Element::Nil,
+            ],
+            "[1, a, nil]",
+        );
     }
 
     #[test]
-    fn get_integer1() {
-        let data = [Program::INTEGER1_ID, 0x03];
-        let p = Program::new(&data);
-        assert_eq!(
-            p.iter_statements(0).next(),
-            Some((0, Element::Integer(0x03)))
+    fn equals_ignore_case() {
+        let check = |a, b, expected| {
+            check_expression(
+                &[
+                    Element::EqualsIgnoreCase,
+                    Element::StringLiteral(a),
+                    Element::StringLiteral(b),
+                ],
+                Ok(Value::Boolean(expected)),
+            );
+        };
+        check("FOO", "foo", true);
+        check("Hello", "hELLO", true);
+        check("foo", "food", false);
+        // Only ASCII letters are folded
+        check("\u{c9}T\u{c9}", "\u{e9}t\u{e9}", false);
+        check_expression(
+            &[
+                Element::EqualsIgnoreCase,
+                Element::StringLiteral("1"),
+                Element::Integer(1),
+            ],
+            Err(Error::TypeMismatch(ValueKind::String, ValueKind::Integer)),
         );
     }
 
     #[test]
-    fn get_integer2() {
-        let data = [Program::INTEGER2_ID, 0x03, 0x04];
-        let p = Program::new(&data);
-        assert_eq!(
-            p.iter_statements(0).next(),
-            Some((0, Element::Integer(0x0304)))
+    fn case_conversion() {
+        #[cfg(feature = "alloc")]
+        {
+            let check = |element, s, expected: &str| {
+                check_expression(
+                    &[element, Element::StringLiteral(s)],
+                    Ok(Value::String(expected.to_string())),
+                );
+            };
+            check(Element::ToUpper, "Hello, World!", "HELLO, WORLD!");
+            check(Element::ToLower, "Hello, World!", "hello, world!");
+            // Non-ASCII characters are left unchanged
+            check(Element::ToUpper, "caf\u{e9} \u{df}", "CAF\u{e9} \u{df}");
+            check(Element::ToLower, "CAF\u{c9} \u{3a3}", "caf\u{c9} \u{3a3}");
+        }
+        #[cfg(not(feature = "alloc"))]
+        check_expression(
+            &[Element::ToUpper, Element::StringLiteral("a")],
+            Err(Error::NoAllocator),
+        );
+        check_expression(
+            &[Element::ToLower, Element::Integer(1)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Integer)),
         );
     }
 
     #[test]
-    fn get_integer3() {
-        let data = [Program::INTEGER3_ID, 0x03, 0x04, 0x05];
-        let p = Program::new(&data);
+    fn parse_numbers() {
+        let parse_int = |s| [Element::ParseInt, Element::StringLiteral(s)];
+        let parse_float = |s| [Element::ParseFloat, Element::StringLiteral(s)];
+        check_expression(&parse_int("42"), Ok(Value::Integer(42)));
+        check_expression(&parse_int(" -7\n"), Ok(Value::Integer(-7)));
+        check_expression(&parse_int("0x10"), Ok(Value::Integer(16)));
+        check_expression(&parse_int("0b101"), Ok(Value::Integer(5)));
+        check_expression(&parse_int("5_000_000_000"), Ok(Value::Long(5_000_000_000)));
+        check_expression(&parse_int(""), Err(Error::ParseValueError));
+        check_expression(&parse_int("12abc"), Err(Error::ParseValueError));
+        check_expression(&parse_int("0xZZ"), Err(Error::ParseValueError));
+        check_expression(&parse_int("1.5"), Err(Error::ParseValueError));
+        check_expression(&parse_float("1.5"), Ok(Value::Float(1.5)));
+        check_expression(&parse_float("-2"), Ok(Value::Float(-2.0)));
+        check_expression(&parse_float("1e3"), Ok(Value::Float(1000.0)));
+        check_expression(&parse_float("one"), Err(Error::ParseValueError));
+        check_expression(&parse_float("1.5.2"), Err(Error::ParseValueError));
+        check_expression(
+            &[Element::ParseInt, Element::Integer(1)],
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Integer)),
+        );
+    }
+
+    #[test]
+    fn add_assign_statements() {
+        let source = "\
+fn foo
+    let count
+    0
+    let total
+    0
+    while
+    <
+    count
+    5
+        count +=
+        1
+        total +=
+        count
+    end
+    total -=
+    1
+    return
+    total
+end
+fn bar
+    x +=
+    1
+end
+";
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.validate(), Ok(()));
+        assert_eq!(p.run("foo"), Ok(Value::Integer(14)));
+        assert_eq!(p.run("bar"), Err(Error::UndefinedVariable));
+    }
+
+    #[test]
+    fn constants() {
+        let source = "\
+const LED
+    5
+const LEDS
+    *
+    LED
+    2
+const LOOP
+    LOOP
+fn foo
+    return
+    +
+    LED
+    LEDS
+end
+fn shadow(LED)
+    return
+    LED
+end
+fn assign
+    let LED
+    1
+end
+fn add_assign
+    LED +=
+    1
+end
+fn loop
+    return
+    LOOP
+end
+";
+        let mut space = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        assert_eq!(p.validate(), Ok(()));
         assert_eq!(
-            p.iter_statements(0).next(),
-            Some((0, Element::Integer(0x030405)))
+            p.functions().collect::<std::vec::Vec<_>>(),
+            ["foo", "shadow", "assign", "add_assign", "loop"]
+        );
+        assert_eq!(p.run("foo"), Ok(Value::Integer(15)));
+        // A parameter hides the constant
+        assert_eq!(
+            p.run_with_args("shadow", &[Value::Integer(7)]),
+            Ok(Value::Integer(7))
         );
+        assert_eq!(p.run("assign"), Err(Error::AssignToConstant));
+        assert_eq!(p.run("add_assign"), Err(Error::AssignToConstant));
+        assert_eq!(p.run("loop"), Err(Error::RecursionLimitExceeded));
     }
 
     #[test]
-    fn get_integer4() {
-        let data = [Program::INTEGER4_ID, 0x03, 0x04, 0x05, 0x06];
-        let p = Program::new(&data);
+    #[cfg(feature = "alloc")]
+    fn sort_and_reverse() {
+        let vector = |items: &[Element<'static>]| {
+            let mut elements = std::vec![Element::Vector(items.len() as u8)];
+            elements.extend_from_slice(items);
+            elements
+        };
+        let integers = |items: &[i32]| Value::vector(items.iter().map(|i| Value::Integer(*i)));
+        let unsorted = vector(&[
+            Element::Integer(3),
+            Element::Integer(1),
+            Element::Integer(2),
+        ]);
+        let mut sort = std::vec![Element::Sort];
+        sort.extend_from_slice(&unsorted);
+        check_expression(&sort, Ok(integers(&[1, 2, 3])));
+        let mut reverse = std::vec![Element::Reverse];
+        reverse.extend_from_slice(&sort);
+        check_expression(&reverse, Ok(integers(&[3, 2, 1])));
+        let mut reverse = std::vec![Element::Reverse];
+        reverse.extend_from_slice(&unsorted);
+        check_expression(&reverse, Ok(integers(&[2, 1, 3])));
+        // Numbers of different kinds can be sorted together
+        let mut sort = std::vec![Element::Sort];
+        sort.extend(vector(&[Element::Float(1.5), Element::Integer(1)]));
+        check_expression(
+            &sort,
+            Ok(Value::vector([Value::Integer(1), Value::Float(1.5)])),
+        );
+        check_expression(&[Element::Sort, Element::Vector(0)], Ok(Value::vector([])));
+        // But numbers and Strings can't
+        let mut sort = std::vec![Element::Sort];
+        sort.extend(vector(&[Element::Integer(1), Element::StringLiteral("a")]));
+        check_expression(
+            &sort,
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::String)),
+        );
+        check_expression(
+            &[Element::Reverse, Element::StringLiteral("abc")],
+            Err(Error::TypeMismatch(ValueKind::String, ValueKind::String)),
+        );
+    }
+
+    #[test]
+    fn map_and_filter() {
+        let source = "\
+fn double(x)
+    return
+    *
+    x
+    2
+end
+fn even(x)
+    return
+    ==
+    %
+    x
+    2
+    0
+end
+fn add(a, b)
+    return
+    +
+    a
+    b
+end
+fn doubled
+    return
+    map double
+    vec 3
+    1
+    2
+    3
+end
+fn evens
+    return
+    filter even
+    vec 4
+    1
+    2
+    3
+    4
+end
+fn wrong_arity
+    return
+    map add
+    vec 0
+end
+fn not_vector
+    return
+    map double
+    1
+end
+";
+        let mut space = [0u8; 256];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        #[cfg(feature = "alloc")]
+        {
+            let integers = |items: &[i32]| Value::vector(items.iter().map(|i| Value::Integer(*i)));
+            assert_eq!(p.run("doubled"), Ok(integers(&[2, 4, 6])));
+            assert_eq!(p.run("evens"), Ok(integers(&[2, 4])));
+        }
+        #[cfg(not(feature = "alloc"))]
+        assert_eq!(p.run("doubled"), Err(Error::NoAllocator));
+        assert_eq!(p.run("wrong_arity"), Err(Error::ArityMismatch));
         assert_eq!(
-            p.iter_statements(0).next(),
-            Some((0, Element::Integer(0x03040506)))
+            p.run("not_vector"),
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Integer))
         );
     }
 
     #[test]
-    fn get_negative_ntegeri8() {
-        let data = [Program::INTEGER1_ID, 0xFF];
-        let p = Program::new(&data);
-        assert_eq!(p.iter_statements(0).next(), Some((0, Element::Integer(-1))));
+    fn push_onto_vector() {
+        let source = "\
+fn foo
+    let list
+    vec 0
+    for i
+    1
+    3
+        let list
+        push
+        list
+        i
+    end
+    return
+    list
+end
+fn bar
+    return
+    push
+    1
+    2
+end
+";
+        let mut space = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut space);
+        builder.assemble(source).unwrap();
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        #[cfg(feature = "alloc")]
+        assert_eq!(
+            p.run("foo"),
+            Ok(Value::vector([
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Integer(3)
+            ]))
+        );
+        #[cfg(not(feature = "alloc"))]
+        assert_eq!(p.run("foo"), Err(Error::NoAllocator));
+        assert_eq!(
+            p.run("bar"),
+            Err(Error::TypeMismatch(ValueKind::Integer, ValueKind::Integer))
+        );
     }
 
     #[test]
-    fn get_negative_integer16() {
-        let data = [Program::INTEGER2_ID, 0xFF, 0xFE];
-        let p = Program::new(&data);
-        assert_eq!(p.iter_statements(0).next(), Some((0, Element::Integer(-2))));
+    fn undefined_variable() {
+        check_expression(&[Element::Variable("x")], Err(Error::UndefinedVariable));
     }
 
     #[test]
-    fn get_negative_integer24() {
-        let data = [Program::INTEGER3_ID, 0xFF, 0xFF, 0xFD];
-        let p = Program::new(&data);
-        assert_eq!(p.iter_statements(0).next(), Some((0, Element::Integer(-3))));
+    fn if_statement_bad_condition() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::If,
+                Element::StringLiteral("yes"),
+                Element::End,
+                Element::End,
+            ],
+            Err(Error::TypeMismatch(ValueKind::String, ValueKind::String)),
+        );
     }
 
     #[test]
-    fn get_negative_integer32() {
-        let data = [Program::INTEGER4_ID, 0xFF, 0xFF, 0xFF, 0xFC];
-        let p = Program::new(&data);
-        assert_eq!(p.iter_statements(0).next(), Some((0, Element::Integer(-4))));
+    fn if_statement_unclosed() {
+        check_program(
+            &[
+                Element::Function {
+                    name: "foo",
+                    params: Params::NONE,
+                },
+                Element::If,
+                Element::Integer(0),
+                Element::Return,
+                Element::Integer(10),
+            ],
+            Err(Error::SequenceError(12)),
+        );
     }
 
     #[test]
-    fn return_integer_literal() {
-        let data = [
-            Program::FUNCTION_ID,
-            0x03,
-            b'f',
-            b'o',
-            b'o',
-            Program::RETURN_ID,
-            Program::INTEGER1_ID,
-            0x0F,
-            Program::END_ID,
-        ];
-        let p = Program::new(&data);
-        assert_eq!(p.run("foo"), Ok(Value::Integer(15)));
+    fn builder_and_iterator_without_heap() {
+        // Everything here lives on the stack, so it works without `alloc`
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut space);
+        for element in &[
+            Element::Function {
+                name: "foo",
+                params: Params::NONE,
+            },
+            Element::Return,
+            Element::Multiply,
+            Element::Integer(-300),
+            Element::Integer(70000),
+            Element::End,
+        ] {
+            builder.insert(element).unwrap();
+        }
+        let used = builder.used();
+        let p = Program::new(&space[0..used]);
+        let mut offsets = [0usize; 6];
+        for (slot, (offset, _)) in offsets.iter_mut().zip(p.iter_statements(0)) {
+            *slot = offset;
+        }
+        assert_eq!(offsets, [0, 6, 7, 8, 11, 15]);
+        assert_eq!(p.run("foo"), Ok(Value::Integer(-21_000_000)));
+        let mut buffer = [0u8; 5];
+        assert_eq!(
+            ProgramBuilder::encode_integer(&mut buffer, 70000),
+            &[Program::INTEGER3_ID, 0x01, 0x11, 0x70]
+        );
     }
 
+    /// Check all the interesting boundary conditions. Note that 2's
+    /// complement integers are not symmetric - an i8 runs from -128 to +127
+    /// and so -128 fits in an INTEGER1 while +128 requires an INTEGER2.
+    static INTEGER_TEST_CASES: &[(usize, i32)] = &[
+        // INTEGER4
+        (5, i32::MIN),
+        (5, i32::MIN + 1),
+        (5, -(1 << 23) - 1),
+        // INTEGER3
+        (4, -(1 << 23)),
+        (4, -(1 << 23) + 1),
+        (4, -(1 << 15) - 1),
+        // INTEGER2
+        (3, -(1 << 15)),
+        (3, -(1 << 15) + 1),
+        (3, -(1 << 7) - 1),
+        // INTEGER1
+        (2, -(1 << 7)),
+        (2, -(1 << 7) + 1),
+        (2, -1),
+        (2, 0),
+        (2, 1),
+        (2, (1 << 7) - 1),
+        // INTEGER2
+        (3, (1 << 7)),
+        (3, (1 << 7) + 1),
+        (3, (1 << 15) - 1),
+        // INTEGER3
+        (4, (1 << 15)),
+        (4, (1 << 15) + 1),
+        (4, (1 << 23) - 1),
+        // INTEGER4
+        (5, (1 << 23)),
+        (5, (1 << 23) + 1),
+        (5, i32::MAX - 1),
+        (5, i32::MAX),
+    ];
+
     #[test]
     fn test_integer_encoding() {
-        // Check all the interesting boundary conditions. Note that 2's
-        // complement integers are not symmetric - an i8 runs from -128 to +127
-        // and so -128 fits in an INTEGER1 while +128 requires an INTEGER2.
-        static TEST_CASES: &[(usize, i32)] = &[
-            // INTEGER4
-            (5, i32::MIN),
-            (5, i32::MIN + 1),
-            (5, -(1 << 23) - 1),
-            // INTEGER3
-            (4, -(1 << 23)),
-            (4, -(1 << 23) + 1),
-            (4, -(1 << 15) - 1),
-            // INTEGER2
-            (3, -(1 << 15)),
-            (3, -(1 << 15) + 1),
-            (3, -(1 << 7) - 1),
-            // INTEGER1
-            (2, -(1 << 7)),
-            (2, -(1 << 7) + 1),
-            (2, -1),
-            (2, 0),
-            (2, 1),
-            (2, (1 << 7) - 1),
-            // INTEGER2
-            (3, (1 << 7)),
-            (3, (1 << 7) + 1),
-            (3, (1 << 15) - 1),
-            // INTEGER3
-            (4, (1 << 15)),
-            (4, (1 << 15) + 1),
-            (4, (1 << 23) - 1),
-            // INTEGER4
-            (5, (1 << 23)),
-            (5, (1 << 23) + 1),
-            (5, i32::MAX - 1),
-            (5, i32::MAX),
-        ];
-        for (len, integer) in TEST_CASES {
+        for (len, integer) in INTEGER_TEST_CASES {
             let mut buffer = [0u8; 5];
             let result = ProgramBuilder::encode_integer(&mut buffer, *integer);
             assert_eq!(
@@ -724,13 +12187,117 @@ mod tests {
                 *integer,
                 result
             );
-            let p = Program::new(&result);
+            let p = Program::new(result);
             assert_eq!(
                 p.iter_statements(0).next(),
                 Some((0, Element::Integer(*integer)))
             );
         }
     }
+
+    #[test]
+    fn integer_encoding_endianness() {
+        for endianness in [Endianness::Big, Endianness::Little] {
+            let mut space = [0u8; 256];
+            let mut builder = ProgramBuilder::new_with_options(&mut space, endianness).unwrap();
+            for (_, integer) in INTEGER_TEST_CASES {
+                builder.insert(&Element::Integer(*integer)).unwrap();
+            }
+            builder.insert(&Element::Long(i64::MIN)).unwrap();
+            builder.insert(&Element::Long(i64::MAX)).unwrap();
+            let used = builder.used();
+            let p = Program::new(&space[0..used]);
+            let mut iter = p.iter_statements(0);
+            for (_, integer) in INTEGER_TEST_CASES {
+                assert_eq!(
+                    iter.next().map(|(_, e)| e),
+                    Some(Element::Integer(*integer)),
+                    "{:?}",
+                    endianness
+                );
+            }
+            assert_eq!(iter.next().map(|(_, e)| e), Some(Element::Long(i64::MIN)));
+            assert_eq!(iter.next().map(|(_, e)| e), Some(Element::Long(i64::MAX)));
+            assert_eq!(iter.next(), None);
+            assert_eq!(iter.index, used - HEADER_LEN);
+        }
+        let mut buffer = [0u8; 5];
+        assert_eq!(
+            ProgramBuilder::encode_integer_as(&mut buffer, 70000, Endianness::Little),
+            &[Program::INTEGER3_ID, 0x70, 0x11, 0x01]
+        );
+
+        // A little endian program runs the same, and can be appended to a
+        // big endian one
+        let mut space = [0u8; 32];
+        let mut builder = ProgramBuilder::new_with_options(&mut space, Endianness::Little).unwrap();
+        builder
+            .assemble("fn foo\nreturn\n+\n70000\n1\nend\n")
+            .unwrap();
+        let used = builder.used();
+        assert_eq!(space[5], Program::LITTLE_ENDIAN_ID);
+        assert_eq!(&space[14..18], &[Program::INTEGER3_ID, 0x70, 0x11, 0x01]);
+        let little = Program::new(&space[0..used]);
+        assert_eq!(little.run("foo"), Ok(Value::Integer(70001)));
+        let mut big_space = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut big_space);
+        builder.append_program(&little).unwrap();
+        let used = builder.used();
+        let big = Program::new(&big_space[0..used]);
+        assert_eq!(&big_space[8..12], &[Program::INTEGER3_ID, 0x01, 0x11, 0x70]);
+        assert_eq!(big.run("foo"), Ok(Value::Integer(70001)));
+        assert!(ProgramBuilder::new_with_options(&mut [], Endianness::Big).is_err());
+    }
+
+    #[test]
+    fn test_integer_encoding_leb128() {
+        for (_, integer) in INTEGER_TEST_CASES {
+            let mut buffer = [0u8; 6];
+            let result = ProgramBuilder::encode_integer_leb128(&mut buffer, *integer);
+            let p = Program::new(result);
+            let mut iter = p.iter_statements(0);
+            assert_eq!(
+                iter.next(),
+                Some((0, Element::Integer(*integer))),
+                "{} ({:x?})",
+                *integer,
+                result
+            );
+            assert_eq!(iter.index, result.len());
+        }
+        for (len, integer) in &[
+            (2, -64),
+            (2, 63),
+            (3, -65),
+            (3, 64),
+            (3, -8192),
+            (3, 8191),
+            (4, 8192),
+            (6, i32::MIN),
+            (6, i32::MAX),
+        ] {
+            let mut buffer = [0u8; 6];
+            let result = ProgramBuilder::encode_integer_leb128(&mut buffer, *integer);
+            assert_eq!(result.len(), *len, "{}", integer);
+        }
+        assert_eq!(
+            ProgramBuilder::encode_integer_leb128(&mut [0u8; 6], -2),
+            &[Program::INTEGER_VARINT_ID, 0x7E]
+        );
+        // Truncated, and too long
+        let p = Program::new(&[Program::INTEGER_VARINT_ID, 0x80]);
+        assert_eq!(p.iter_statements(0).next(), None);
+        let p = Program::new(&[
+            Program::INTEGER_VARINT_ID,
+            0x80,
+            0x80,
+            0x80,
+            0x80,
+            0x80,
+            0x00,
+        ]);
+        assert_eq!(p.iter_statements(0).next(), None);
+    }
 }
 
 // -----------------------------------------------------------------------------